@@ -0,0 +1,167 @@
+// Time spent by the audio callback to render a batch: mix of several speakers, echo reference,
+// sidetone and taps. The speakers are fed by their own threads, while the control side adds and
+// removes speakers and changes their controls from another thread, like the receive loops and the
+// local API do.
+//
+// Run with `cargo bench -p vors_client_audio --bench mixer`
+
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::error::RecvError;
+use vors_client_audio::{
+    AudioTap, EchoReference, Mixer, PlaybackProcessingDesc, Sidetone, SpeakerControls,
+};
+use vors_share_session::{AudioBufferingConfig, SidetoneConfig, SidetoneSource};
+
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS_COUNT: usize = 2;
+const SPEAKERS_COUNT: u64 = 8;
+const PACKET_FRAMES_COUNT: usize = 480;
+const ITERATIONS: usize = 10_000;
+// Shorter than the real period, to keep the benchmark fast
+const CALLBACK_PERIOD: Duration = Duration::from_micros(200);
+
+fn print_stats(name: &str, (mut durations, checksum): (Vec<Duration>, f32)) {
+    durations.sort();
+    let percentile = |p: f64| durations[((durations.len() - 1) as f64 * p) as usize];
+
+    println!(
+        "{name}: median {:?}, p99 {:?}, p99.99 {:?}, max {:?} (checksum {checksum})",
+        percentile(0.5),
+        percentile(0.99),
+        percentile(0.9999),
+        durations.last().unwrap()
+    );
+}
+
+// The checksum keeps the compiler from optimizing the batches away
+fn bench_mixer(control_changes: bool) -> (Vec<Duration>, f32) {
+    let config = AudioBufferingConfig {
+        average_buffering_ms: 50,
+        batch_ms: 10,
+    };
+    let average_buffer_frames_count =
+        SAMPLE_RATE as usize * config.average_buffering_ms as usize / 1000;
+
+    let tap = AudioTap::new();
+    let sidetone = Sidetone::new(&SidetoneConfig {
+        source: SidetoneSource::ProcessedMicrophone,
+        level_db: -10.,
+    });
+    let echo_reference = EchoReference::new();
+    let mixer = Mixer::new(
+        CHANNELS_COUNT,
+        SAMPLE_RATE,
+        &config,
+        PlaybackProcessingDesc {
+            tap: tap.clone(),
+            sidetone: Some(sidetone.clone()),
+            ..Default::default()
+        },
+    );
+    let running = Arc::new(AtomicBool::new(true));
+    let mut threads = vec![];
+
+    for id in 0..SPEAKERS_COUNT {
+        let mut producer = mixer.add_speaker(id, None).unwrap();
+        let running = Arc::clone(&running);
+        threads.push(thread::spawn(move || {
+            let packet = vec![0.1; PACKET_FRAMES_COUNT * CHANNELS_COUNT];
+            while running.load(Ordering::Relaxed) {
+                // Keep the buffer around its average level
+                if producer.frames_count() < 2 * average_buffer_frames_count {
+                    producer.push(&packet);
+                }
+                thread::yield_now();
+            }
+        }));
+    }
+
+    // Capture path
+    threads.push(thread::spawn({
+        let running = Arc::clone(&running);
+        move || {
+            let frame = vec![0.2; PACKET_FRAMES_COUNT];
+            while running.load(Ordering::Relaxed) {
+                sidetone.push(&frame, 1, SAMPLE_RATE);
+                thread::sleep(CALLBACK_PERIOD);
+            }
+        }
+    }));
+
+    // Subscriber of the taps
+    let mut tap_receiver = tap.subscribe();
+    let tap_thread = thread::spawn(move || loop {
+        // Lagging is fine, only the cost of publishing matters
+        if let Err(RecvError::Closed) = tap_receiver.blocking_recv() {
+            break;
+        }
+    });
+
+    if control_changes {
+        let mixer = mixer.clone();
+        let running = Arc::clone(&running);
+        threads.push(thread::spawn(move || {
+            let mut volume = 0.;
+            while running.load(Ordering::Relaxed) {
+                volume = (volume + 0.01) % 1.;
+                for id in 0..SPEAKERS_COUNT {
+                    mixer.set_controls(
+                        id,
+                        SpeakerControls {
+                            volume,
+                            ..Default::default()
+                        },
+                    );
+                }
+
+                let producer = mixer.add_speaker(SPEAKERS_COUNT, None).unwrap();
+                thread::yield_now();
+                drop(producer);
+                mixer.remove_speaker(SPEAKERS_COUNT);
+                thread::yield_now();
+            }
+        }));
+    }
+
+    let mut renderer = mixer.take_renderer().unwrap();
+    let mut echo_reference_writer = echo_reference.writer().unwrap();
+    let mut batch = vec![0.; renderer.batch_samples_count()];
+    let mut checksum = 0.;
+    let durations = (0..ITERATIONS)
+        .map(|_| {
+            let start = Instant::now();
+            renderer.mix_batch(&mut batch);
+            echo_reference_writer.push(&batch, CHANNELS_COUNT, SAMPLE_RATE);
+            renderer.mix_sidetone(&mut batch);
+            let duration = start.elapsed();
+
+            checksum += batch[0];
+            thread::sleep(CALLBACK_PERIOD);
+
+            duration
+        })
+        .collect();
+
+    running.store(false, Ordering::Relaxed);
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    mixer.return_renderer(renderer);
+    // Stops the tap publisher, which closes the broadcast channel
+    drop((mixer, tap));
+    tap_thread.join().unwrap();
+
+    (durations, checksum)
+}
+
+fn main() {
+    print_stats("mixer", bench_mixer(false));
+    print_stats("mixer with control changes", bench_mixer(true));
+}
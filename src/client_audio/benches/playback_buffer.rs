@@ -0,0 +1,140 @@
+// Time spent by the audio callback to get a batch from the playback buffer, while a receive loop
+// pushes packets concurrently. The same hand-off based on a mutex is measured for comparison: its
+// worst case depends on how long the receive loop holds the lock.
+//
+// Run with `cargo bench -p vors_client_audio --bench playback_buffer`
+
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use vors_client_audio::playback_buffer;
+use vors_share_common::parking_lot::Mutex;
+
+const SAMPLE_RATE: u32 = 48000;
+const CHANNELS_COUNT: usize = 2;
+const BATCH_FRAMES_COUNT: usize = 480;
+const AVERAGE_BUFFER_FRAMES_COUNT: usize = 2400;
+const PACKET_FRAMES_COUNT: usize = 480;
+const ITERATIONS: usize = 10_000;
+// Shorter than the real period, to keep the benchmark fast
+const CALLBACK_PERIOD: Duration = Duration::from_micros(200);
+
+fn print_stats(name: &str, (mut durations, checksum): (Vec<Duration>, f32)) {
+    durations.sort();
+    let percentile = |p: f64| durations[((durations.len() - 1) as f64 * p) as usize];
+
+    println!(
+        "{name}: median {:?}, p99 {:?}, p99.99 {:?}, max {:?} (checksum {checksum})",
+        percentile(0.5),
+        percentile(0.99),
+        percentile(0.9999),
+        durations.last().unwrap()
+    );
+}
+
+// The checksums keep the compiler from optimizing the batches away
+fn bench_wait_free() -> (Vec<Duration>, f32) {
+    let (mut producer, mut consumer) = playback_buffer(
+        SAMPLE_RATE,
+        CHANNELS_COUNT,
+        BATCH_FRAMES_COUNT,
+        AVERAGE_BUFFER_FRAMES_COUNT,
+    );
+    let running = Arc::new(AtomicBool::new(true));
+
+    let producer_thread = thread::spawn({
+        let running = Arc::clone(&running);
+        move || {
+            let packet = vec![0.1; PACKET_FRAMES_COUNT * CHANNELS_COUNT];
+            while running.load(Ordering::Relaxed) {
+                // Keep the buffer around its average level
+                if producer.frames_count() < 2 * AVERAGE_BUFFER_FRAMES_COUNT {
+                    producer.push(&packet);
+                }
+                thread::yield_now();
+            }
+        }
+    });
+
+    let mut batch = vec![0.; BATCH_FRAMES_COUNT * CHANNELS_COUNT];
+    let mut checksum = 0.;
+    let durations = (0..ITERATIONS)
+        .map(|_| {
+            let start = Instant::now();
+            consumer.fill_batch(&mut batch);
+            let duration = start.elapsed();
+
+            checksum += batch[0];
+            thread::sleep(CALLBACK_PERIOD);
+
+            duration
+        })
+        .collect();
+
+    running.store(false, Ordering::Relaxed);
+    producer_thread.join().unwrap();
+
+    (durations, checksum)
+}
+
+fn bench_mutex() -> (Vec<Duration>, f32) {
+    let buffer = Arc::new(Mutex::new(VecDeque::<f32>::new()));
+    let running = Arc::new(AtomicBool::new(true));
+
+    let producer_thread = thread::spawn({
+        let buffer = Arc::clone(&buffer);
+        let running = Arc::clone(&running);
+        move || {
+            let packet = vec![0.1; PACKET_FRAMES_COUNT * CHANNELS_COUNT];
+            while running.load(Ordering::Relaxed) {
+                let mut buffer = buffer.lock();
+                if buffer.len() / CHANNELS_COUNT < 2 * AVERAGE_BUFFER_FRAMES_COUNT {
+                    buffer.extend(&packet);
+                }
+                // Recovery and cross-fades done while holding the lock
+                for sample in buffer.iter_mut().take(BATCH_FRAMES_COUNT * CHANNELS_COUNT) {
+                    *sample *= 0.999;
+                }
+                drop(buffer);
+                thread::yield_now();
+            }
+        }
+    });
+
+    let batch_samples_count = BATCH_FRAMES_COUNT * CHANNELS_COUNT;
+    let mut checksum = 0.;
+    let durations = (0..ITERATIONS)
+        .map(|_| {
+            let start = Instant::now();
+            let mut buffer = buffer.lock();
+            let batch = if buffer.len() >= batch_samples_count {
+                buffer.drain(..batch_samples_count).collect::<Vec<_>>()
+            } else {
+                vec![0.; batch_samples_count]
+            };
+            drop(buffer);
+            let duration = start.elapsed();
+
+            checksum += batch[0];
+            thread::sleep(CALLBACK_PERIOD);
+
+            duration
+        })
+        .collect();
+
+    running.store(false, Ordering::Relaxed);
+    producer_thread.join().unwrap();
+
+    (durations, checksum)
+}
+
+fn main() {
+    print_stats("wait-free playback buffer", bench_wait_free());
+    print_stats("mutex playback buffer", bench_mutex());
+}
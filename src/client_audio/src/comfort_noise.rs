@@ -0,0 +1,327 @@
+// Comfort noise for the discontinuous transmission. While the voice activity detection marks
+// silence, the sender stops sending audio frames and periodically describes its background noise
+// instead: the power in a few octave bands. The receiver shapes white noise with the same bands, so
+// that the silence between sentences sounds like the speaker's room instead of dead air, and the
+// jitter buffer plays it wherever it would otherwise fade to silence.
+
+use crate::dsp::{self, Biquad};
+use vors_share_packets::{
+    AudioPacketHeader, ComfortNoiseDescriptor, EchoTestPacketHeader, SystemAudioPacketHeader,
+    COMFORT_NOISE_BANDS_COUNT,
+};
+
+const BAND_CENTERS_HZ: [f32; COMFORT_NOISE_BANDS_COUNT] =
+    [125., 250., 500., 1000., 2000., 4000., 8000.];
+// One octave
+const BAND_Q: f32 = std::f32::consts::SQRT_2;
+// Bands too close to the Nyquist frequency are ignored
+const MAX_BAND_CENTER_RATIO: f32 = 0.4;
+
+const MIN_LEVEL_DB: f32 = -120.;
+// Applied once per analyzed frame
+const POWER_SMOOTHING: f32 = 0.9;
+const BAND_GAINS_ITERATIONS: usize = 20;
+
+// Implemented by the headers of the streams played by receive_samples_loop
+pub trait ComfortNoiseSource {
+    // Some if the packet is a comfort noise descriptor instead of audio
+    fn comfort_noise(&self) -> Option<&ComfortNoiseDescriptor> {
+        None
+    }
+}
+
+impl ComfortNoiseSource for AudioPacketHeader {
+    fn comfort_noise(&self) -> Option<&ComfortNoiseDescriptor> {
+        self.comfort_noise.as_ref()
+    }
+}
+
+impl ComfortNoiseSource for SystemAudioPacketHeader {}
+
+impl ComfortNoiseSource for EchoTestPacketHeader {}
+
+fn band_filters(sample_rate: u32) -> [Option<Biquad>; COMFORT_NOISE_BANDS_COUNT] {
+    BAND_CENTERS_HZ.map(|center_hz| {
+        (center_hz < sample_rate as f32 * MAX_BAND_CENTER_RATIO)
+            .then(|| Biquad::band_pass(sample_rate, center_hz, BAND_Q))
+    })
+}
+
+// Runs on the capture side, fed with the frames marked as silent
+pub struct ComfortNoiseEstimator {
+    bands: [Option<Biquad>; COMFORT_NOISE_BANDS_COUNT],
+    channels_count: usize,
+    frame_duration_ms: u64,
+    // None until the first silent frame
+    powers: Option<(f32, [f32; COMFORT_NOISE_BANDS_COUNT])>,
+    interval_frames_count: usize,
+    // None while not in discontinuous transmission
+    frames_until_descriptor: Option<usize>,
+    mono: Vec<f32>,
+}
+
+impl ComfortNoiseEstimator {
+    pub fn new(
+        sample_rate: u32,
+        channels_count: usize,
+        frame_duration_ms: u64,
+        descriptor_interval_ms: u64,
+    ) -> Self {
+        Self {
+            bands: band_filters(sample_rate),
+            channels_count,
+            frame_duration_ms,
+            powers: None,
+            interval_frames_count: (descriptor_interval_ms / frame_duration_ms).max(1) as usize,
+            frames_until_descriptor: None,
+            mono: vec![],
+        }
+    }
+
+    // Analyze a frame that contains only background noise
+    pub fn process(&mut self, frame: &[f32]) {
+        dsp::downmix_to_mono(frame, self.channels_count, &mut self.mono);
+
+        let power = dsp::mean_power(&self.mono);
+        let mut band_powers = [0.; COMFORT_NOISE_BANDS_COUNT];
+        for (band, band_power) in self.bands.iter_mut().zip(&mut band_powers) {
+            if let Some(band) = band {
+                *band_power = self
+                    .mono
+                    .iter()
+                    .map(|s| band.process(*s).powi(2))
+                    .sum::<f32>()
+                    / self.mono.len().max(1) as f32;
+            }
+        }
+
+        if let Some((smoothed_power, smoothed_band_powers)) = &mut self.powers {
+            *smoothed_power = *smoothed_power * POWER_SMOOTHING + power * (1. - POWER_SMOOTHING);
+            for (smoothed, band_power) in smoothed_band_powers.iter_mut().zip(band_powers) {
+                *smoothed = *smoothed * POWER_SMOOTHING + band_power * (1. - POWER_SMOOTHING);
+            }
+        } else {
+            self.powers = Some((power, band_powers));
+        }
+    }
+
+    // Called for each frame that is not sent. Returns a descriptor to send on the first one, then
+    // once per interval. Each descriptor stays valid for two intervals, so that a lost packet does
+    // not interrupt the comfort noise.
+    pub fn poll_descriptor(&mut self) -> Option<ComfortNoiseDescriptor> {
+        let (power, band_powers) = self.powers?;
+
+        let frames_until_descriptor = self.frames_until_descriptor.get_or_insert(0);
+        if *frames_until_descriptor > 0 {
+            *frames_until_descriptor -= 1;
+            return None;
+        }
+        *frames_until_descriptor = self.interval_frames_count - 1;
+
+        let to_db = |power: f32| (10. * power.max(1e-20).log10()).max(MIN_LEVEL_DB);
+        let level_db = to_db(power);
+
+        Some(ComfortNoiseDescriptor {
+            level_db,
+            spectrum_db: band_powers.map(|band_power| to_db(band_power) - level_db),
+            duration_ms: (2 * self.interval_frames_count as u64 * self.frame_duration_ms)
+                .min(u16::MAX as u64) as u16,
+        })
+    }
+
+    // Called when a frame is sent again. The next silence starts with a new descriptor.
+    pub fn end_silence(&mut self) {
+        self.frames_until_descriptor = None;
+    }
+}
+
+// Output power of a filter for unit variance white noise: the sum of its squared impulse response,
+// over a duration long enough for the lowest band
+fn impulse_power(sample_rate: u32, mut filter: impl FnMut(f32) -> f32) -> f32 {
+    (0..sample_rate / 10)
+        .map(|i| filter(if i == 0 { 1. } else { 0. }).powi(2))
+        .sum()
+}
+
+// Runs on the playback side, in the audio callback. It does not allocate.
+pub struct ComfortNoiseGenerator {
+    bands: [Option<Biquad>; COMFORT_NOISE_BANDS_COUNT],
+    // Power of the output of each band for unit variance white noise
+    band_noise_powers: [f32; COMFORT_NOISE_BANDS_COUNT],
+    // [i][j]: power measured by the band i of the estimator, for unit variance white noise shaped by
+    // the band j
+    band_leakages: [[f32; COMFORT_NOISE_BANDS_COUNT]; COMFORT_NOISE_BANDS_COUNT],
+    band_gains: [f32; COMFORT_NOISE_BANDS_COUNT],
+    channels_count: usize,
+    gain: f32,
+    random_state: u32,
+}
+
+impl ComfortNoiseGenerator {
+    pub fn new(sample_rate: u32, channels_count: usize) -> Self {
+        let bands = band_filters(sample_rate);
+
+        let band_noise_powers = bands.clone().map(|band| {
+            band.map(|mut band| impulse_power(sample_rate, |s| band.process(s)))
+                .unwrap_or(0.)
+        });
+        let band_leakages = bands.clone().map(|analysis_band| {
+            bands.clone().map(|synthesis_band| {
+                if let (Some(mut analysis_band), Some(mut synthesis_band)) =
+                    (analysis_band.clone(), synthesis_band)
+                {
+                    impulse_power(sample_rate, |s| {
+                        analysis_band.process(synthesis_band.process(s))
+                    })
+                } else {
+                    0.
+                }
+            })
+        });
+
+        Self {
+            bands,
+            band_noise_powers,
+            band_leakages,
+            band_gains: [0.; COMFORT_NOISE_BANDS_COUNT],
+            channels_count,
+            gain: 0.,
+            random_state: 0x9E37_79B9,
+        }
+    }
+
+    // The bands overlap: the squared gains are refined until the power measured in each band
+    // matches the descriptor, then scaled so that the total power matches the level
+    pub fn set_descriptor(&mut self, descriptor: &ComfortNoiseDescriptor) {
+        let mut target_powers = [0.; COMFORT_NOISE_BANDS_COUNT];
+        for (b, target_power) in target_powers.iter_mut().enumerate() {
+            if self.band_noise_powers[b] > 0. {
+                *target_power =
+                    10_f32.powf((descriptor.level_db + descriptor.spectrum_db[b]) / 10.);
+            }
+        }
+
+        let mut squared_gains = target_powers;
+        for _ in 0..BAND_GAINS_ITERATIONS {
+            for b in 0..COMFORT_NOISE_BANDS_COUNT {
+                let measured_power = self.band_leakages[b]
+                    .iter()
+                    .zip(squared_gains)
+                    .map(|(leakage, squared_gain)| leakage * squared_gain)
+                    .sum::<f32>();
+                if measured_power > 0. {
+                    squared_gains[b] *= target_powers[b] / measured_power;
+                }
+            }
+        }
+
+        // White noise shaped by each band is independent, the powers add up
+        let total_power = (0..COMFORT_NOISE_BANDS_COUNT)
+            .map(|b| self.band_noise_powers[b] * squared_gains[b])
+            .sum::<f32>();
+        let scale = if total_power > 0. {
+            10_f32.powf(descriptor.level_db / 10.) / total_power
+        } else {
+            0.
+        };
+
+        for (gain, squared_gain) in self.band_gains.iter_mut().zip(squared_gains) {
+            *gain = (squared_gain * scale).sqrt();
+        }
+    }
+
+    // Uniform, with unit variance
+    fn white_noise(&mut self) -> f32 {
+        // xorshift32
+        self.random_state ^= self.random_state << 13;
+        self.random_state ^= self.random_state >> 17;
+        self.random_state ^= self.random_state << 5;
+
+        (self.random_state as f32 / u32::MAX as f32 * 2. - 1.) * 3_f32.sqrt()
+    }
+
+    // Add the noise to an interleaved batch. The gain ramps from its previous value to the target
+    // over the batch.
+    pub fn add_to(&mut self, batch: &mut [f32], target_gain: f32) {
+        if self.gain == 0. && target_gain == 0. {
+            return;
+        }
+
+        let frames_count = batch.len() / self.channels_count;
+        for (f, frame) in batch.chunks_exact_mut(self.channels_count).enumerate() {
+            let gain = self.gain + (target_gain - self.gain) * (f + 1) as f32 / frames_count as f32;
+
+            let mut noise = 0.;
+            for b in 0..COMFORT_NOISE_BANDS_COUNT {
+                let input = self.white_noise();
+                if let Some(band) = &mut self.bands[b] {
+                    noise += band.process(input) * self.band_gains[b];
+                }
+            }
+
+            for sample in frame {
+                *sample += noise * gain;
+            }
+        }
+
+        self.gain = target_gain;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_comfort_noise_matches_background() {
+        const SAMPLE_RATE: u32 = 48000;
+        const FRAME_SIZE: usize = 480;
+
+        // Low-pass filtered noise, as background
+        let mut generator = ComfortNoiseGenerator::new(SAMPLE_RATE, 1);
+        let mut previous = 0.;
+        let background = (0..100 * FRAME_SIZE)
+            .map(|_| {
+                previous = previous * 0.9 + generator.white_noise() * 0.01;
+                previous
+            })
+            .collect::<Vec<_>>();
+
+        let mut estimator = ComfortNoiseEstimator::new(SAMPLE_RATE, 1, 10, 100);
+        assert!(estimator.poll_descriptor().is_none());
+        for frame in background.chunks_exact(FRAME_SIZE) {
+            estimator.process(frame);
+        }
+
+        let descriptor = estimator.poll_descriptor().unwrap();
+        assert_eq!(descriptor.duration_ms, 200);
+        for _ in 0..9 {
+            assert!(estimator.poll_descriptor().is_none());
+        }
+        assert!(estimator.poll_descriptor().is_some());
+
+        let background_db = dsp::power_db(&background);
+        assert!((descriptor.level_db - background_db).abs() < 1.);
+        // Less power at high frequencies
+        assert!(descriptor.spectrum_db[3] > descriptor.spectrum_db[6] + 5.);
+
+        generator.set_descriptor(&descriptor);
+        generator.add_to(&mut [0.; FRAME_SIZE], 1.);
+        let mut output = vec![0.; 100 * FRAME_SIZE];
+        generator.add_to(&mut output, 1.);
+        assert!((dsp::power_db(&output) - background_db).abs() < 1.);
+
+        let mut output_estimator = ComfortNoiseEstimator::new(SAMPLE_RATE, 1, 10, 100);
+        for frame in output.chunks_exact(FRAME_SIZE) {
+            output_estimator.process(frame);
+        }
+        let output_descriptor = output_estimator.poll_descriptor().unwrap();
+        for (output_db, background_db) in output_descriptor
+            .spectrum_db
+            .iter()
+            .zip(descriptor.spectrum_db)
+        {
+            assert!((output_db - background_db).abs() < 1.);
+        }
+    }
+}
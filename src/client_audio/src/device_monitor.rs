@@ -0,0 +1,207 @@
+// Audio device hot-plug. The monitor reports the devices that appear and disappear, while the
+// capture and playback threads use `DeviceSelector` to reopen their stream when their device is
+// unplugged: on the configured device if present, otherwise on the default one. They switch back
+// when the configured device is plugged in again.
+
+use crate::{device_from_custom_config, AudioEvent};
+use cpal::{
+    traits::{DeviceTrait, HostTrait},
+    Device, Host,
+};
+use serde::Serialize;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::mpsc as smpsc,
+    thread,
+    time::Duration,
+};
+use tokio::sync::mpsc as tmpsc;
+use vors_share_common::prelude::*;
+use vors_share_session::{CustomAudioDeviceConfig, LinuxAudioBackend};
+
+pub const DEVICE_POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum DeviceDirection {
+    Input,
+    Output,
+}
+
+#[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
+pub(crate) fn audio_host(linux_backend: Option<LinuxAudioBackend>) -> Host {
+    #[cfg(target_os = "linux")]
+    let host = match linux_backend {
+        Some(LinuxAudioBackend::Alsa) => cpal::host_from_id(cpal::HostId::Alsa).unwrap(),
+        Some(LinuxAudioBackend::Jack) => cpal::host_from_id(cpal::HostId::Jack).unwrap(),
+        None => cpal::default_host(),
+    };
+    #[cfg(not(target_os = "linux"))]
+    let host = cpal::default_host();
+
+    host
+}
+
+fn device_names(host: &Host, direction: DeviceDirection) -> HashSet<String> {
+    let devices = match direction {
+        DeviceDirection::Input => host.input_devices().map(|d| d.collect::<Vec<_>>()),
+        DeviceDirection::Output => host.output_devices().map(|d| d.collect::<Vec<_>>()),
+    };
+
+    devices
+        .unwrap_or_default()
+        .iter()
+        .filter_map(|d| d.name().ok())
+        .collect()
+}
+
+// How a device has been chosen, so that it can be chosen again after a hot-plug
+#[derive(Clone)]
+pub(crate) struct DeviceSelector {
+    pub linux_backend: Option<LinuxAudioBackend>,
+    pub config: Option<CustomAudioDeviceConfig>,
+    pub direction: DeviceDirection,
+}
+
+impl DeviceSelector {
+    // Returns the configured device if present, otherwise the default device. The flag is true for
+    // the default device used as fallback.
+    pub fn resolve(&self) -> StrResult<(Device, bool)> {
+        let host = audio_host(self.linux_backend);
+
+        if let Some(config) = &self.config {
+            if let Ok(device) = device_from_custom_config(&host, config) {
+                return Ok((device, false));
+            }
+        }
+
+        let device = match self.direction {
+            DeviceDirection::Input => host.default_input_device(),
+            DeviceDirection::Output => host.default_output_device(),
+        }
+        .ok_or_else(|| "No audio device found".to_owned())?;
+
+        Ok((device, self.config.is_some()))
+    }
+
+    // Returns the device the stream should be moved to, if any. A failed stream is always
+    // reopened. Otherwise the stream is moved if its device disappeared, or if a better device is
+    // available: the configured one, or the new default device when there is no configuration.
+    pub fn switch_target(&self, current: &Device, stream_failed: bool) -> Option<(Device, bool)> {
+        let (preferred, fallback) = self.resolve().ok()?;
+        if stream_failed {
+            return Some((preferred, fallback));
+        }
+
+        let current_name = current.name().ok()?;
+        if preferred.name().ok()? == current_name {
+            return None;
+        }
+
+        let current_present =
+            device_names(&audio_host(self.linux_backend), self.direction).contains(&current_name);
+
+        (!current_present || !fallback).then_some((preferred, fallback))
+    }
+}
+
+fn diff_devices(
+    previous: &HashSet<String>,
+    current: &HashSet<String>,
+    direction: DeviceDirection,
+) -> Vec<AudioEvent> {
+    let mut events = current
+        .difference(previous)
+        .map(|name| AudioEvent::DeviceAdded {
+            name: name.clone(),
+            direction,
+        })
+        .chain(
+            previous
+                .difference(current)
+                .map(|name| AudioEvent::DeviceRemoved {
+                    name: name.clone(),
+                    direction,
+                }),
+        )
+        .collect::<Vec<_>>();
+
+    // HashSet order is random
+    events.sort_by_key(|event| format!("{event:?}"));
+
+    events
+}
+
+// Keeps the device monitor running
+pub struct DeviceMonitorGuard {
+    _shutdown_notifier: smpsc::Sender<()>,
+}
+
+// Enumerate the devices periodically and report the changes until the returned guard is dropped
+pub fn start_device_monitor(
+    linux_backend: Option<LinuxAudioBackend>,
+    events_sender: tmpsc::UnboundedSender<AudioEvent>,
+) -> DeviceMonitorGuard {
+    let (shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
+
+    thread::spawn(move || {
+        let host = audio_host(linux_backend);
+
+        // The devices present at startup are not reported
+        let mut known_devices = HashMap::new();
+
+        loop {
+            for direction in [DeviceDirection::Input, DeviceDirection::Output] {
+                let names = device_names(&host, direction);
+                if let Some(previous) = known_devices.get(&direction) {
+                    for event in diff_devices(previous, &names, direction) {
+                        info!("{event:?}");
+                        events_sender.send(event).ok();
+                    }
+                }
+                known_devices.insert(direction, names);
+            }
+
+            if !matches!(
+                shutdown_receiver.recv_timeout(DEVICE_POLL_INTERVAL),
+                Err(smpsc::RecvTimeoutError::Timeout)
+            ) {
+                break;
+            }
+        }
+    });
+
+    DeviceMonitorGuard {
+        _shutdown_notifier: shutdown_notifier,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_diff() {
+        let previous = ["Speakers", "USB Headset"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+        let current = ["Speakers", "Bluetooth Headset"]
+            .into_iter()
+            .map(String::from)
+            .collect();
+
+        let events = diff_devices(&previous, &current, DeviceDirection::Output);
+        assert_eq!(events.len(), 2);
+        assert!(matches!(
+            &events[0],
+            AudioEvent::DeviceAdded { name, .. } if name == "Bluetooth Headset"
+        ));
+        assert!(matches!(
+            &events[1],
+            AudioEvent::DeviceRemoved { name, direction: DeviceDirection::Output }
+                if name == "USB Headset"
+        ));
+
+        assert!(diff_devices(&current, &current, DeviceDirection::Output).is_empty());
+    }
+}
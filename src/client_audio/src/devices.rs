@@ -0,0 +1,235 @@
+// Audio device enumeration. Devices are identified by a stable ID made of the host, the direction
+// and the name, with a suffix to tell apart devices with the same name. Unlike the enumeration
+// index, the ID does not change when other devices are plugged or unplugged.
+
+use crate::DeviceDirection;
+use cpal::{
+    traits::{DeviceTrait, HostTrait},
+    Device, Host, HostId,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+use vors_share_common::prelude::*;
+
+// Sample rates reported as supported when they fall in one of the ranges of the device
+const PROBED_SAMPLE_RATES: [u32; 11] = [
+    8000, 11025, 16000, 22050, 32000, 44100, 48000, 88200, 96000, 176400, 192000,
+];
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AudioDeviceInfo {
+    pub id: String,
+    pub name: String,
+    pub direction: DeviceDirection,
+    pub is_default: bool,
+    pub sample_rates: Vec<u32>,
+    pub channel_counts: Vec<u16>,
+    pub sample_formats: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct AudioHostInfo {
+    pub name: String,
+    pub is_default: bool,
+    pub devices: Vec<AudioDeviceInfo>,
+}
+
+fn device_ids(host_name: &str, direction: DeviceDirection, names: &[String]) -> Vec<String> {
+    let direction = match direction {
+        DeviceDirection::Input => "in",
+        DeviceDirection::Output => "out",
+    };
+
+    let mut occurrences = HashMap::<&str, usize>::new();
+    names
+        .iter()
+        .map(|name| {
+            let occurrence = occurrences.entry(name).or_default();
+            *occurrence += 1;
+
+            if *occurrence == 1 {
+                format!("{host_name}/{direction}/{name}")
+            } else {
+                format!("{host_name}/{direction}/{name}#{occurrence}")
+            }
+        })
+        .collect()
+}
+
+// Returns (id, name, device)
+fn host_devices(host: &Host, direction: DeviceDirection) -> Vec<(String, String, Device)> {
+    let devices = match direction {
+        DeviceDirection::Input => host.input_devices().map(|d| d.collect::<Vec<_>>()),
+        DeviceDirection::Output => host.output_devices().map(|d| d.collect::<Vec<_>>()),
+    }
+    .unwrap_or_default();
+    let names = devices
+        .iter()
+        .map(|d| d.name().unwrap_or_default())
+        .collect::<Vec<_>>();
+
+    device_ids(host.id().name(), direction, &names)
+        .into_iter()
+        .zip(names)
+        .zip(devices)
+        .map(|((id, name), device)| (id, name, device))
+        .collect()
+}
+
+// Returns (host name, direction)
+fn parse_device_id(id: &str) -> StrResult<(&str, DeviceDirection)> {
+    let mut parts = id.splitn(3, '/');
+    let host_name = parts.next().unwrap_or_default();
+    let direction = match parts.next() {
+        Some("in") => DeviceDirection::Input,
+        Some("out") => DeviceDirection::Output,
+        _ => return fmt_e!("Invalid audio device ID \"{id}\""),
+    };
+
+    Ok((host_name, direction))
+}
+
+fn host_id_from_name(host_name: &str) -> StrResult<HostId> {
+    cpal::available_hosts()
+        .into_iter()
+        .find(|host_id| host_id.name() == host_name)
+        .ok_or_else(|| format!("Audio host \"{host_name}\" is not available"))
+}
+
+// The ID selects the host, whatever host the device would be opened with otherwise
+pub(crate) fn device_from_id(id: &str) -> StrResult<Device> {
+    let (host_name, direction) = parse_device_id(id)?;
+    let host = cpal::host_from_id(host_id_from_name(host_name)?).map_err(err!())?;
+
+    host_devices(&host, direction)
+        .into_iter()
+        .find(|(device_id, ..)| device_id == id)
+        .map(|(.., device)| device)
+        .ok_or_else(|| format!("Cannot find audio device with ID \"{id}\""))
+}
+
+fn device_info(
+    id: String,
+    name: String,
+    device: &Device,
+    direction: DeviceDirection,
+    is_default: bool,
+) -> AudioDeviceInfo {
+    let configs = match direction {
+        DeviceDirection::Input => device.supported_input_configs().map(|c| c.collect()),
+        DeviceDirection::Output => device.supported_output_configs().map(|c| c.collect()),
+    }
+    .unwrap_or_else(|_| vec![]);
+
+    let sample_rates = PROBED_SAMPLE_RATES
+        .into_iter()
+        .filter(|rate| {
+            configs
+                .iter()
+                .any(|c| (c.min_sample_rate().0..=c.max_sample_rate().0).contains(rate))
+        })
+        .collect();
+
+    let mut channel_counts = configs.iter().map(|c| c.channels()).collect::<Vec<_>>();
+    channel_counts.sort_unstable();
+    channel_counts.dedup();
+
+    let mut sample_formats = configs
+        .iter()
+        .map(|c| c.sample_format().to_string())
+        .collect::<Vec<_>>();
+    sample_formats.sort();
+    sample_formats.dedup();
+
+    AudioDeviceInfo {
+        id,
+        name,
+        direction,
+        is_default,
+        sample_rates,
+        channel_counts,
+        sample_formats,
+    }
+}
+
+// List the devices of every available host. Devices that are both input and output are listed
+// once per direction.
+pub fn audio_devices_list() -> Vec<AudioHostInfo> {
+    let default_host_id = cpal::default_host().id();
+
+    cpal::available_hosts()
+        .into_iter()
+        .filter_map(|host_id| {
+            let host = cpal::host_from_id(host_id).ok()?;
+
+            let mut devices = vec![];
+            for direction in [DeviceDirection::Input, DeviceDirection::Output] {
+                let default_name = match direction {
+                    DeviceDirection::Input => host.default_input_device(),
+                    DeviceDirection::Output => host.default_output_device(),
+                }
+                .and_then(|d| d.name().ok());
+
+                for (id, name, device) in host_devices(&host, direction) {
+                    let is_default = default_name.as_ref() == Some(&name);
+                    devices.push(device_info(id, name, &device, direction, is_default));
+                }
+            }
+
+            Some(AudioHostInfo {
+                name: host_id.name().into(),
+                is_default: host_id == default_host_id,
+                devices,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_device_ids() {
+        let names = ["Speakers", "USB Audio", "USB Audio"].map(String::from);
+
+        let ids = device_ids("ALSA", DeviceDirection::Output, &names);
+        assert_eq!(
+            ids,
+            [
+                "ALSA/out/Speakers",
+                "ALSA/out/USB Audio",
+                "ALSA/out/USB Audio#2"
+            ]
+        );
+
+        // Independent of the other devices
+        let ids = device_ids("ALSA", DeviceDirection::Output, &names[1..]);
+        assert_eq!(ids[0], "ALSA/out/USB Audio");
+    }
+
+    #[test]
+    fn test_device_id_round_trip() {
+        // Not the default host, and not necessarily available on this machine
+        let names = ["system", "system"].map(String::from);
+
+        for id in device_ids("JACK", DeviceDirection::Input, &names) {
+            assert_eq!(
+                parse_device_id(&id).unwrap(),
+                ("JACK", DeviceDirection::Input)
+            );
+        }
+
+        assert!(parse_device_id("JACK/system").is_err());
+
+        let host_name = cpal::available_hosts()[0].name();
+        let id = &device_ids(host_name, DeviceDirection::Output, &names)[1];
+        let (parsed_host_name, _) = parse_device_id(id).unwrap();
+        assert_eq!(
+            host_id_from_name(parsed_host_name).unwrap().name(),
+            host_name
+        );
+
+        assert!(host_id_from_name("Unknown").is_err());
+    }
+}
@@ -0,0 +1,193 @@
+// Clock drift compensation. The remote capture clock and the local playback clock never run at
+// exactly the same rate, so the jitter buffer slowly fills up or drains. Instead of waiting for an
+// overflow or underflow, the buffer level is observed continuously and the incoming audio is
+// resampled by a tiny factor to keep the level around its target.
+
+// Maximum correction. Real world devices are usually within ±100 ppm, the extra margin is used to
+// converge faster after startup.
+const MAX_DRIFT_PPM: f64 = 2000.;
+
+// Buffer level smoothing factor, applied once per received packet. The buffer level is very noisy
+// because of network jitter, only the long term trend is interesting.
+const LEVEL_SMOOTHING: f64 = 0.995;
+
+const PROPORTIONAL_GAIN: f64 = 0.001;
+const INTEGRAL_GAIN: f64 = 0.000_002;
+
+pub struct DriftEstimator {
+    target_frames: f64,
+    smoothed_frames: Option<f64>,
+    integral: f64,
+    ratio: f64,
+}
+
+impl DriftEstimator {
+    pub fn new(target_frames: usize) -> Self {
+        Self {
+            target_frames: target_frames.max(1) as f64,
+            smoothed_frames: None,
+            integral: 0.,
+            ratio: 1.,
+        }
+    }
+
+    // Submit the current buffer level, in frames. Should be called once per received packet.
+    pub fn submit_buffer_level(&mut self, buffer_frames: usize) {
+        let level = buffer_frames as f64;
+        let smoothed = match self.smoothed_frames {
+            Some(smoothed) => smoothed * LEVEL_SMOOTHING + level * (1. - LEVEL_SMOOTHING),
+            None => level,
+        };
+        self.smoothed_frames = Some(smoothed);
+
+        let error = (smoothed - self.target_frames) / self.target_frames;
+
+        let max_ratio_offset = MAX_DRIFT_PPM / 1_000_000.;
+        self.integral =
+            (self.integral + error * INTEGRAL_GAIN).clamp(-max_ratio_offset, max_ratio_offset);
+
+        self.ratio = 1.
+            + (error * PROPORTIONAL_GAIN + self.integral)
+                .clamp(-max_ratio_offset, max_ratio_offset);
+    }
+
+    // Forget the buffer level history but keep the estimated drift, which depends only on the
+    // device clocks. Used after the buffer has been forcibly resized.
+    pub fn reset_level(&mut self) {
+        self.smoothed_frames = None;
+    }
+
+    // Number of input frames consumed for each output frame. A ratio greater than 1 means the
+    // remote clock is faster than the local one.
+    pub fn ratio(&self) -> f64 {
+        self.ratio
+    }
+
+    pub fn drift_ppm(&self) -> f64 {
+        self.integral * 1_000_000.
+    }
+}
+
+// Streaming fractional resampler based on cubic Hermite interpolation. The ratio can change
+// between calls without discontinuities in the output waveform.
+pub struct FractionalResampler {
+    channels_count: usize,
+    // Interleaved frames not consumed yet. The first frame is kept as history for interpolation
+    history: Vec<f32>,
+    // Read position in frames, relative to the start of `history`
+    position: f64,
+}
+
+impl FractionalResampler {
+    pub fn new(channels_count: usize) -> Self {
+        Self {
+            channels_count,
+            history: vec![],
+            position: 1.,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.position = 1.;
+    }
+
+    pub fn process(&mut self, input: &[f32], ratio: f64, output: &mut Vec<f32>) {
+        let channels_count = self.channels_count;
+
+        if self.history.is_empty() {
+            // Prime the history with the first frame to avoid a click from zero
+            self.history
+                .extend_from_slice(&input[0..channels_count.min(input.len())]);
+        }
+        self.history.extend_from_slice(input);
+
+        let frames_count = self.history.len() / channels_count;
+
+        // Interpolation needs one frame before and two frames after the read position
+        while (self.position as usize) + 2 < frames_count {
+            let index = self.position as usize;
+            let t = (self.position - index as f64) as f32;
+
+            for c in 0..channels_count {
+                let y0 = self.history[(index - 1) * channels_count + c];
+                let y1 = self.history[index * channels_count + c];
+                let y2 = self.history[(index + 1) * channels_count + c];
+                let y3 = self.history[(index + 2) * channels_count + c];
+
+                output.push(cubic_hermite(y0, y1, y2, y3, t));
+            }
+
+            self.position += ratio;
+        }
+
+        // Drop frames that will never be needed again
+        let consumed_frames = (self.position as usize).saturating_sub(1).min(frames_count);
+        self.history.drain(0..consumed_frames * channels_count);
+        self.position -= consumed_frames as f64;
+    }
+}
+
+#[inline]
+fn cubic_hermite(y0: f32, y1: f32, y2: f32, y3: f32, t: f32) -> f32 {
+    let c0 = y1;
+    let c1 = 0.5 * (y2 - y0);
+    let c2 = y0 - 2.5 * y1 + 2. * y2 - 0.5 * y3;
+    let c3 = 0.5 * (y3 - y0) + 1.5 * (y1 - y2);
+
+    ((c3 * t + c2) * t + c1) * t + c0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resampler_unity_ratio_is_transparent() {
+        let mut resampler = FractionalResampler::new(2);
+        let input = (0..200).map(|i| (i as f32 * 0.1).sin()).collect::<Vec<_>>();
+
+        let mut output = vec![];
+        for chunk in input.chunks(40) {
+            resampler.process(chunk, 1., &mut output);
+        }
+
+        // The last two frames are retained for interpolation, the rest must match exactly
+        assert_eq!(output.len(), input.len() - 2 * 2);
+        for (a, b) in input.iter().zip(output.iter()) {
+            assert!((a - b).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_resampler_output_length_follows_ratio() {
+        let mut resampler = FractionalResampler::new(1);
+        let input = vec![0.5; 48_000];
+
+        let mut output = vec![];
+        for chunk in input.chunks(480) {
+            resampler.process(chunk, 1.001, &mut output);
+        }
+
+        let expected = 48_000. / 1.001;
+        assert!((output.len() as f64 - expected).abs() < 4.);
+    }
+
+    #[test]
+    fn test_estimator_converges_on_constant_drift() {
+        let target_frames = 2400;
+        let mut estimator = DriftEstimator::new(target_frames);
+
+        // Simulate a remote clock 100 ppm faster than the local one
+        let remote_ratio = 1.0001;
+        let mut level = target_frames as f64;
+        for _ in 0..200_000 {
+            // 480 local frames are played for each packet
+            level += 480. * remote_ratio / estimator.ratio() - 480.;
+            estimator.submit_buffer_level(level as usize);
+        }
+
+        assert!((estimator.ratio() - remote_ratio).abs() < 20e-6);
+        assert!((level - target_frames as f64).abs() < 100.);
+    }
+}
@@ -0,0 +1,103 @@
+// Small DSP building blocks shared by the audio processors.
+
+use std::f32::consts::PI;
+
+#[inline]
+pub fn db_to_linear(db: f32) -> f32 {
+    10_f32.powf(db / 20.)
+}
+
+#[inline]
+pub fn mean_power(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.;
+    }
+
+    samples.iter().map(|s| s * s).sum::<f32>() / samples.len() as f32
+}
+
+// Mean power of a block of samples, in dBFS
+#[inline]
+pub fn power_db(samples: &[f32]) -> f32 {
+    10. * mean_power(samples).max(1e-20).log10()
+}
+
+// Average the channels of an interleaved buffer into a mono buffer
+pub fn downmix_to_mono(interleaved: &[f32], channels_count: usize, mono: &mut Vec<f32>) {
+    mono.clear();
+    mono.extend(
+        interleaved
+            .chunks_exact(channels_count)
+            .map(|frame| frame.iter().sum::<f32>() / channels_count as f32),
+    );
+}
+
+// Second order IIR filter, transposed direct form II. Coefficients from the Audio EQ Cookbook.
+#[derive(Clone)]
+pub struct Biquad {
+    b0: f32,
+    b1: f32,
+    b2: f32,
+    a1: f32,
+    a2: f32,
+    z1: f32,
+    z2: f32,
+}
+
+impl Biquad {
+    fn from_coefficients(b0: f32, b1: f32, b2: f32, a0: f32, a1: f32, a2: f32) -> Self {
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+            z1: 0.,
+            z2: 0.,
+        }
+    }
+
+    // Constant 0 dB peak gain band-pass
+    pub fn band_pass(sample_rate: u32, center_hz: f32, q: f32) -> Self {
+        let w0 = 2. * PI * center_hz / sample_rate as f32;
+        let alpha = w0.sin() / (2. * q);
+
+        Self::from_coefficients(alpha, 0., -alpha, 1. + alpha, -2. * w0.cos(), 1. - alpha)
+    }
+
+    #[inline]
+    pub fn process(&mut self, input: f32) -> f32 {
+        let output = self.b0 * input + self.z1;
+        self.z1 = self.b1 * input - self.a1 * output + self.z2;
+        self.z2 = self.b2 * input - self.a2 * output;
+
+        output
+    }
+}
+
+// Leaves the signal untouched below the knee and bends it smoothly towards full scale above it
+#[inline]
+pub fn soft_clip(sample: f32) -> f32 {
+    const KNEE: f32 = 0.8;
+
+    let magnitude = sample.abs();
+    if magnitude <= KNEE {
+        sample
+    } else {
+        let excess = (magnitude - KNEE) / (1. - KNEE);
+        (KNEE + (1. - KNEE) * excess.tanh()).copysign(sample)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_soft_clip() {
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert!(soft_clip(3.) <= 1.);
+        assert!(soft_clip(-3.) >= -1.);
+        assert!(soft_clip(0.9) > soft_clip(0.85));
+    }
+}
@@ -0,0 +1,112 @@
+// Ducking of the background streams (game audio, shared system audio) while someone talks, so that
+// the voices stay intelligible. The side-chain is the voice activity of the remote speakers, as
+// flagged in their packets, and optionally the voice activity of the local microphone.
+
+use crate::dsp;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use vors_share_session::DuckingConfig;
+
+// Voice activity shared between a capture or receive loop and the mixer
+#[derive(Clone, Default)]
+pub struct VoiceActivitySignal(Arc<AtomicBool>);
+
+impl VoiceActivitySignal {
+    pub fn set(&self, active: bool) {
+        self.0.store(active, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+// Clears the signal when dropped, so that a stopped loop does not keep the ducking active
+pub struct VoiceActivityGuard(pub VoiceActivitySignal);
+
+impl Drop for VoiceActivityGuard {
+    fn drop(&mut self) {
+        self.0.set(false);
+    }
+}
+
+// The attenuation ramps linearly in dB: the attack and release times are the durations of a full
+// transition between 0 dB and the depth.
+pub struct Ducker {
+    depth_db: f32,
+    attack_db_per_frame: f32,
+    release_db_per_frame: f32,
+    hold_frames: usize,
+    hold_remaining_frames: usize,
+    attenuation_db: f32,
+}
+
+impl Ducker {
+    pub fn new(config: &DuckingConfig, sample_rate: u32) -> Self {
+        let frames_count = |ms: u64| (sample_rate as u64 * ms / 1000).max(1) as f32;
+        let depth_db = config.depth_db.max(0.);
+
+        Self {
+            depth_db,
+            attack_db_per_frame: depth_db / frames_count(config.attack_ms),
+            release_db_per_frame: depth_db / frames_count(config.release_ms),
+            hold_frames: (sample_rate as u64 * config.hold_ms / 1000) as usize,
+            hold_remaining_frames: 0,
+            attenuation_db: 0.,
+        }
+    }
+
+    // Advance by a batch. Returns the gain of the background streams at the end of the batch.
+    pub fn process(&mut self, voice_active: bool, frames_count: usize) -> f32 {
+        if voice_active {
+            self.hold_remaining_frames = self.hold_frames;
+            self.attenuation_db = (self.attenuation_db
+                + self.attack_db_per_frame * frames_count as f32)
+                .min(self.depth_db);
+        } else if self.hold_remaining_frames > 0 {
+            self.hold_remaining_frames = self.hold_remaining_frames.saturating_sub(frames_count);
+        } else {
+            self.attenuation_db =
+                (self.attenuation_db - self.release_db_per_frame * frames_count as f32).max(0.);
+        }
+
+        dsp::db_to_linear(-self.attenuation_db)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_attack_hold_release() {
+        let mut ducker = Ducker::new(
+            &DuckingConfig {
+                depth_db: 20.,
+                attack_ms: 20,
+                hold_ms: 30,
+                release_ms: 40,
+                local_microphone: false,
+            },
+            1000,
+        );
+
+        assert_eq!(ducker.process(false, 10), 1.);
+
+        // Attack
+        assert!((ducker.process(true, 10) - dsp::db_to_linear(-10.)).abs() < 1e-6);
+        assert!((ducker.process(true, 10) - 0.1).abs() < 1e-6);
+        assert!((ducker.process(true, 10) - 0.1).abs() < 1e-6);
+
+        // Hold
+        for _ in 0..3 {
+            assert!((ducker.process(false, 10) - 0.1).abs() < 1e-6);
+        }
+
+        // Release
+        assert!((ducker.process(false, 20) - dsp::db_to_linear(-10.)).abs() < 1e-6);
+        assert_eq!(ducker.process(false, 20), 1.);
+    }
+}
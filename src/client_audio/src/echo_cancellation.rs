@@ -0,0 +1,696 @@
+// Acoustic echo cancellation. The playback path publishes what it renders into an
+// `EchoReference`, the capture path estimates how this far-end signal reaches the microphone and
+// subtracts it.
+//
+// * The bulk delay (output buffering, device latency, acoustic path) is estimated by correlating
+//   the energy envelopes of the far-end and microphone signals.
+// * The echo path is modeled with a partitioned block frequency domain adaptive filter (overlap
+//   save, normalized LMS), which covers the remaining delay and the room reverberation tail.
+// * Adaptation is frozen during double-talk, detected when the microphone is louder than the
+//   echo the far-end signal could produce.
+
+use crate::{
+    dsp, sample_ring, AudioProcessor, FractionalResampler, SampleConsumer, SampleProducer,
+};
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+use vors_share_common::parking_lot::Mutex;
+use vors_share_session::EchoCancellationConfig;
+
+// Room of the far-end ring: the longest maximum delay setting, plus a margin for the blocks. The
+// canceller discards the samples older than its own maximum delay.
+const MAX_REFERENCE_QUEUE_MS: usize = 1100;
+// The ring holds the maximum queue duration up to this sample rate
+const MAX_REFERENCE_SAMPLE_RATE: usize = 96_000;
+
+// Energy envelope history used for the delay estimation, in frames
+const DELAY_HISTORY_FRAMES: usize = 300;
+const DELAY_ESTIMATION_INTERVAL_FRAMES: usize = 50;
+const MIN_DELAY_CORRELATION: f32 = 0.6;
+
+const STEP_SIZE: f32 = 0.5;
+const POWER_SMOOTHING: f32 = 0.9;
+
+// Minimum echo path gain assumed by the double-talk detector, and margin over the expected echo
+// level. Near-end speech louder than that freezes the adaptation.
+const MIN_ECHO_PATH_GAIN: f32 = 0.5;
+const DOUBLE_TALK_MARGIN: f32 = 4.;
+const DOUBLE_TALK_HOLD_FRAMES: usize = 5;
+
+struct ReferenceQueue {
+    consumer: SampleConsumer,
+    sample_rate: u32,
+}
+
+struct ReferenceShared {
+    // Set by the playback path
+    sample_rate: AtomicU32,
+    // Taken by the playback path
+    producer: Mutex<Option<SampleProducer>>,
+    // Used only by the capture path
+    queue: Mutex<ReferenceQueue>,
+}
+
+// Far-end signal, as rendered by the playback path. Cheap to clone.
+#[derive(Clone)]
+pub struct EchoReference(Arc<ReferenceShared>);
+
+impl EchoReference {
+    pub fn new() -> Self {
+        let (producer, consumer) =
+            sample_ring(MAX_REFERENCE_SAMPLE_RATE * MAX_REFERENCE_QUEUE_MS / 1000);
+
+        Self(Arc::new(ReferenceShared {
+            sample_rate: AtomicU32::new(0),
+            producer: Mutex::new(Some(producer)),
+            queue: Mutex::new(ReferenceQueue {
+                consumer,
+                sample_rate: 0,
+            }),
+        }))
+    }
+
+    // Taken by the playback path, None if another playback already publishes the far-end signal
+    pub fn writer(&self) -> Option<EchoReferenceWriter> {
+        let producer = self.0.producer.lock().take()?;
+
+        Some(EchoReferenceWriter {
+            producer: Some(producer),
+            reference: self.clone(),
+        })
+    }
+
+    // Returns the sample rate of the far-end signal, 0 if nothing has been played yet. The samples
+    // older than max_delay_ms are discarded first.
+    fn pop(&self, max_count: usize, max_delay_ms: usize, output: &mut Vec<f32>) -> u32 {
+        let mut queue = self.0.queue.lock();
+        let ReferenceQueue {
+            consumer,
+            sample_rate,
+        } = &mut *queue;
+
+        let current_sample_rate = self.0.sample_rate.load(Ordering::Relaxed);
+        if *sample_rate != current_sample_rate {
+            consumer.skip(consumer.len());
+            *sample_rate = current_sample_rate;
+        }
+
+        let max_len = *sample_rate as usize * max_delay_ms / 1000;
+        consumer.skip(consumer.len().saturating_sub(max_len));
+
+        let start = output.len();
+        output.resize(start + max_count.min(consumer.len()), 0.);
+        consumer.pop(&mut output[start..]);
+
+        *sample_rate
+    }
+
+    // Discards the far-end samples played so far
+    fn clear(&self) {
+        let mut queue = self.0.queue.lock();
+        let len = queue.consumer.len();
+        queue.consumer.skip(len);
+    }
+}
+
+impl Default for EchoReference {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Owned by the playback callback. The far-end signal can be published again once it is dropped.
+pub struct EchoReferenceWriter {
+    producer: Option<SampleProducer>,
+    reference: EchoReference,
+}
+
+impl EchoReferenceWriter {
+    // Called with the interleaved samples just rendered. They are dropped if the capture path
+    // lags behind.
+    pub fn push(&mut self, samples: &[f32], channels_count: usize, sample_rate: u32) {
+        let Some(producer) = &mut self.producer else {
+            return;
+        };
+        self.reference
+            .0
+            .sample_rate
+            .store(sample_rate, Ordering::Relaxed);
+
+        let mut mono = [0.; 64];
+        for frames in samples.chunks(mono.len() * channels_count) {
+            let count = frames.len() / channels_count;
+            for (output, frame) in mono.iter_mut().zip(frames.chunks_exact(channels_count)) {
+                *output = frame.iter().sum::<f32>() / channels_count as f32;
+            }
+
+            producer.push(&mono[..count]);
+        }
+    }
+}
+
+impl Drop for EchoReferenceWriter {
+    fn drop(&mut self) {
+        *self.reference.0.producer.lock() = self.producer.take();
+    }
+}
+
+// Finds the lag between far-end and microphone energy envelopes
+struct DelayEstimator {
+    far_end_envelope: VecDeque<f32>,
+    near_end_envelope: VecDeque<f32>,
+    max_lag_frames: usize,
+    frames_count: usize,
+    candidate_lag: Option<usize>,
+}
+
+impl DelayEstimator {
+    fn new(max_lag_frames: usize) -> Self {
+        Self {
+            far_end_envelope: VecDeque::new(),
+            near_end_envelope: VecDeque::new(),
+            max_lag_frames,
+            frames_count: 0,
+            candidate_lag: None,
+        }
+    }
+
+    // Returns a new delay estimate, in frames, when a stable one is found
+    fn submit(&mut self, far_end_frame: &[f32], near_end_frame: &[f32]) -> Option<usize> {
+        let history_len = DELAY_HISTORY_FRAMES + self.max_lag_frames;
+        for (envelope, frame) in [
+            (&mut self.far_end_envelope, far_end_frame),
+            (&mut self.near_end_envelope, near_end_frame),
+        ] {
+            envelope.push_back(dsp::power_db(frame).max(-100.));
+            if envelope.len() > history_len {
+                envelope.pop_front();
+            }
+        }
+
+        self.frames_count += 1;
+        if self.frames_count % DELAY_ESTIMATION_INTERVAL_FRAMES != 0
+            || self.near_end_envelope.len() < history_len
+        {
+            return None;
+        }
+
+        let near_end = self
+            .near_end_envelope
+            .range(self.max_lag_frames..)
+            .copied()
+            .collect::<Vec<_>>();
+
+        let mut best = (0., 0);
+        for lag in 0..=self.max_lag_frames {
+            let start = self.max_lag_frames - lag;
+            let far_end = self
+                .far_end_envelope
+                .range(start..start + near_end.len())
+                .copied()
+                .collect::<Vec<_>>();
+
+            let correlation = normalized_correlation(&far_end, &near_end);
+            if correlation > best.0 {
+                best = (correlation, lag);
+            }
+        }
+
+        if best.0 < MIN_DELAY_CORRELATION {
+            return None;
+        }
+
+        // Require two consecutive agreeing estimates
+        let lag = best.1;
+        if self.candidate_lag == Some(lag) {
+            Some(lag)
+        } else {
+            self.candidate_lag = Some(lag);
+            None
+        }
+    }
+}
+
+fn normalized_correlation(a: &[f32], b: &[f32]) -> f32 {
+    let mean_a = a.iter().sum::<f32>() / a.len() as f32;
+    let mean_b = b.iter().sum::<f32>() / b.len() as f32;
+
+    let (mut ab, mut aa, mut bb) = (0., 0., 0.);
+    for (x, y) in a.iter().zip(b) {
+        let (x, y) = (x - mean_a, y - mean_b);
+        ab += x * y;
+        aa += x * x;
+        bb += y * y;
+    }
+
+    if aa <= f32::EPSILON || bb <= f32::EPSILON {
+        0.
+    } else {
+        ab / (aa * bb).sqrt()
+    }
+}
+
+pub struct EchoCanceller {
+    reference: EchoReference,
+    // Older far-end samples cannot be aligned with the microphone anymore
+    max_reference_delay_ms: usize,
+    sample_rate: u32,
+    channels_count: usize,
+    block_size: usize,
+    fft: Arc<dyn Fft<f32>>,
+    ifft: Arc<dyn Fft<f32>>,
+    reference_resampler: FractionalResampler,
+    reference_scratch: Vec<f32>,
+    resampled_scratch: Vec<f32>,
+    resampled_reference: VecDeque<f32>,
+    // Most recent far-end samples, used for the bulk delay alignment
+    reference_history: VecDeque<f32>,
+    delay_estimator: DelayEstimator,
+    delay_blocks: usize,
+    aligned_block: Vec<f32>,
+    previous_aligned_block: Vec<f32>,
+    // Partitions spectra, newest first
+    reference_spectra: VecDeque<Vec<Complex<f32>>>,
+    weights: Vec<Vec<Complex<f32>>>,
+    reference_power: Vec<f32>,
+    far_end_levels: VecDeque<f32>,
+    double_talk_hold: usize,
+    near_end: Vec<f32>,
+    error: Vec<f32>,
+    spectrum: Vec<Complex<f32>>,
+    gradient: Vec<Complex<f32>>,
+}
+
+impl EchoCanceller {
+    pub fn new(
+        config: &EchoCancellationConfig,
+        reference: EchoReference,
+        sample_rate: u32,
+        channels_count: usize,
+        frame_size: usize,
+    ) -> Self {
+        let fft_size = 2 * frame_size;
+        let partitions_count =
+            ((config.tail_length_ms as usize * sample_rate as usize / 1000) / frame_size).max(1);
+        let max_delay_blocks =
+            (config.max_delay_ms as usize * sample_rate as usize / 1000) / frame_size;
+
+        // Playback may have started long before the capture
+        reference.clear();
+
+        let mut planner = FftPlanner::new();
+
+        Self {
+            reference,
+            max_reference_delay_ms: config.max_delay_ms as usize
+                + (frame_size * 1000 + sample_rate as usize - 1) / sample_rate as usize,
+            sample_rate,
+            channels_count,
+            block_size: frame_size,
+            fft: planner.plan_fft_forward(fft_size),
+            ifft: planner.plan_fft_inverse(fft_size),
+            reference_resampler: FractionalResampler::new(1),
+            reference_scratch: vec![],
+            resampled_scratch: vec![],
+            resampled_reference: VecDeque::new(),
+            reference_history: VecDeque::from(vec![0.; (max_delay_blocks + 1) * frame_size]),
+            delay_estimator: DelayEstimator::new(max_delay_blocks),
+            delay_blocks: 0,
+            aligned_block: vec![0.; frame_size],
+            previous_aligned_block: vec![0.; frame_size],
+            reference_spectra: (0..partitions_count)
+                .map(|_| vec![Complex::default(); fft_size])
+                .collect(),
+            weights: (0..partitions_count)
+                .map(|_| vec![Complex::default(); fft_size])
+                .collect(),
+            reference_power: vec![0.; fft_size],
+            far_end_levels: VecDeque::new(),
+            double_talk_hold: 0,
+            near_end: vec![],
+            error: vec![0.; frame_size],
+            spectrum: vec![Complex::default(); fft_size],
+            gradient: vec![Complex::default(); fft_size],
+        }
+    }
+
+    fn reset_filter(&mut self) {
+        for partition in self.weights.iter_mut().chain(&mut self.reference_spectra) {
+            partition.fill(Complex::default());
+        }
+        self.reference_power.fill(0.);
+    }
+
+    // Fetch the next block of far-end samples, converted to the capture sample rate
+    fn fetch_reference_block(&mut self) {
+        let block_size = self.block_size;
+
+        while self.resampled_reference.len() < block_size {
+            self.reference_scratch.clear();
+            let reference_rate = self.reference.pop(
+                block_size,
+                self.max_reference_delay_ms,
+                &mut self.reference_scratch,
+            );
+            if self.reference_scratch.is_empty() {
+                // Nothing is playing
+                self.resampled_reference
+                    .extend((self.resampled_reference.len()..block_size).map(|_| 0.));
+                break;
+            }
+
+            if reference_rate == self.sample_rate {
+                self.resampled_reference.extend(&self.reference_scratch);
+            } else {
+                self.resampled_scratch.clear();
+                self.reference_resampler.process(
+                    &self.reference_scratch,
+                    reference_rate as f64 / self.sample_rate as f64,
+                    &mut self.resampled_scratch,
+                );
+                self.resampled_reference.extend(&self.resampled_scratch);
+            }
+        }
+
+        self.reference_history
+            .extend(self.resampled_reference.drain(0..block_size));
+        let excess =
+            self.reference_history.len() - (self.delay_estimator.max_lag_frames + 1) * block_size;
+        self.reference_history.drain(0..excess);
+    }
+
+    // Rough magnitude of the modeled echo path
+    fn echo_path_gain(&self) -> f32 {
+        let energy = self
+            .weights
+            .iter()
+            .flatten()
+            .map(|w| w.norm_sqr())
+            .sum::<f32>();
+
+        (energy / (2 * self.block_size) as f32).sqrt()
+    }
+}
+
+impl AudioProcessor for EchoCanceller {
+    fn name(&self) -> &'static str {
+        "echo_cancellation"
+    }
+
+    fn channels_count(&self) -> usize {
+        self.channels_count
+    }
+
+    fn reset(&mut self) {
+        self.reset_filter();
+        self.reference.clear();
+        self.reference_resampler.reset();
+        self.resampled_reference.clear();
+        self.reference_history.iter_mut().for_each(|s| *s = 0.);
+        self.delay_estimator = DelayEstimator::new(self.delay_estimator.max_lag_frames);
+        self.delay_blocks = 0;
+        self.aligned_block.fill(0.);
+        self.previous_aligned_block.fill(0.);
+        self.far_end_levels.clear();
+        self.double_talk_hold = 0;
+    }
+
+    // Process one frame of interleaved microphone samples in place
+    fn process(&mut self, frame: &mut [f32]) {
+        let block_size = self.block_size;
+        let fft_size = 2 * block_size;
+
+        self.fetch_reference_block();
+
+        dsp::downmix_to_mono(frame, self.channels_count, &mut self.near_end);
+
+        let newest_block_start = self.reference_history.len() - block_size;
+        let newest_block = self
+            .reference_history
+            .range(newest_block_start..)
+            .copied()
+            .collect::<Vec<_>>();
+        if let Some(delay_blocks) = self.delay_estimator.submit(&newest_block, &self.near_end) {
+            // Start one block early so that the filter can model some non causal jitter
+            let delay_blocks = delay_blocks.saturating_sub(1);
+            if delay_blocks != self.delay_blocks {
+                self.delay_blocks = delay_blocks;
+                self.reset_filter();
+            }
+        }
+
+        // Far-end block aligned with the microphone
+        std::mem::swap(&mut self.previous_aligned_block, &mut self.aligned_block);
+        let aligned_start = newest_block_start - self.delay_blocks * block_size;
+        for (dst, src) in self
+            .aligned_block
+            .iter_mut()
+            .zip(self.reference_history.range(aligned_start..))
+        {
+            *dst = *src;
+        }
+
+        // Newest reference spectrum, from the last two blocks (overlap save)
+        for n in 0..block_size {
+            self.spectrum[n] = Complex::new(self.previous_aligned_block[n], 0.);
+            self.spectrum[block_size + n] = Complex::new(self.aligned_block[n], 0.);
+        }
+        self.fft.process(&mut self.spectrum);
+        let mut newest_spectrum = self.reference_spectra.pop_back().unwrap();
+        newest_spectrum.copy_from_slice(&self.spectrum);
+        self.reference_spectra.push_front(newest_spectrum);
+
+        for (power, bin) in self
+            .reference_power
+            .iter_mut()
+            .zip(&self.reference_spectra[0])
+        {
+            *power = *power * POWER_SMOOTHING + bin.norm_sqr() * (1. - POWER_SMOOTHING);
+        }
+
+        // Echo estimate
+        self.spectrum.fill(Complex::default());
+        for (weights, reference) in self.weights.iter().zip(&self.reference_spectra) {
+            for ((bin, weight), reference) in self.spectrum.iter_mut().zip(weights).zip(reference) {
+                *bin += weight * reference;
+            }
+        }
+        self.ifft.process(&mut self.spectrum);
+        let normalization = 1. / fft_size as f32;
+
+        // Error signal, which is also the echo cancelled output
+        let channels_count = self.channels_count;
+        for (n, (error, near_end)) in self.error.iter_mut().zip(&self.near_end).enumerate() {
+            let echo = self.spectrum[block_size + n].re * normalization;
+            *error = near_end - echo;
+
+            for sample in &mut frame[n * channels_count..(n + 1) * channels_count] {
+                *sample -= echo;
+            }
+        }
+
+        // Double-talk detection
+        self.far_end_levels
+            .push_back(dsp::mean_power(&self.aligned_block));
+        if self.far_end_levels.len() > self.weights.len() + 1 {
+            self.far_end_levels.pop_front();
+        }
+        let far_end_power = self.far_end_levels.iter().copied().fold(0., f32::max);
+        let echo_path_gain = self.echo_path_gain().max(MIN_ECHO_PATH_GAIN);
+        let near_end_power = dsp::mean_power(&self.near_end);
+
+        if near_end_power > far_end_power * echo_path_gain * echo_path_gain * DOUBLE_TALK_MARGIN {
+            self.double_talk_hold = DOUBLE_TALK_HOLD_FRAMES;
+        } else if self.double_talk_hold > 0 {
+            self.double_talk_hold -= 1;
+        }
+
+        if self.double_talk_hold > 0 || far_end_power < 1e-8 {
+            return;
+        }
+
+        // Adaptation
+        let (zeros, error_half) = self.spectrum.split_at_mut(block_size);
+        zeros.fill(Complex::default());
+        for (bin, error) in error_half.iter_mut().zip(&self.error) {
+            *bin = Complex::new(*error, 0.);
+        }
+        self.fft.process(&mut self.spectrum);
+
+        let regularization = 1e-6 * fft_size as f32;
+        for (weights, reference) in self.weights.iter_mut().zip(&self.reference_spectra) {
+            for (((gradient, reference), error), power) in self
+                .gradient
+                .iter_mut()
+                .zip(reference)
+                .zip(&self.spectrum)
+                .zip(&self.reference_power)
+            {
+                *gradient = reference.conj() * error / (power + regularization);
+            }
+
+            // Gradient constraint: keep only the first half of the impulse response, otherwise
+            // the filter would model a circular convolution.
+            self.ifft.process(&mut self.gradient);
+            self.gradient[block_size..].fill(Complex::default());
+            self.fft.process(&mut self.gradient);
+
+            for (weight, gradient) in weights.iter_mut().zip(&self.gradient) {
+                *weight += gradient * (STEP_SIZE * normalization);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 16_000;
+    const FRAME_SIZE: usize = 160;
+
+    // Deterministic noise bursts, with a speech-like on/off envelope
+    fn far_end_signal(frames_count: usize) -> Vec<f32> {
+        let mut seed = 12345_u32;
+        (0..frames_count * FRAME_SIZE)
+            .map(|i| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let noise = (seed >> 16) as f32 / 32768. - 1.;
+                let envelope = if (i / (FRAME_SIZE * 23)) % 3 == 2 {
+                    0.05
+                } else {
+                    1.
+                };
+
+                0.3 * noise * envelope
+            })
+            .collect()
+    }
+
+    // Simulated room: 60ms of delay and a short decaying impulse response
+    fn echo_path(far_end: &[f32]) -> Vec<f32> {
+        let delay = SAMPLE_RATE as usize * 60 / 1000;
+        let taps = [0.4, 0.2, -0.1, 0.05, 0.02];
+
+        (0..far_end.len())
+            .map(|i| {
+                taps.iter()
+                    .enumerate()
+                    .filter(|(t, _)| i >= delay + t)
+                    .map(|(t, gain)| far_end[i - delay - t] * gain)
+                    .sum()
+            })
+            .collect()
+    }
+
+    fn config() -> EchoCancellationConfig {
+        EchoCancellationConfig {
+            max_delay_ms: 200,
+            tail_length_ms: 40,
+        }
+    }
+
+    #[test]
+    fn test_echo_is_cancelled() {
+        let frames_count = 1500;
+        let far_end = far_end_signal(frames_count);
+        let mut microphone = echo_path(&far_end);
+
+        let reference = EchoReference::new();
+        let mut canceller =
+            EchoCanceller::new(&config(), reference.clone(), SAMPLE_RATE, 1, FRAME_SIZE);
+        let mut writer = reference.writer().unwrap();
+
+        for (far_end_frame, microphone_frame) in far_end
+            .chunks_exact(FRAME_SIZE)
+            .zip(microphone.chunks_exact_mut(FRAME_SIZE))
+        {
+            writer.push(far_end_frame, 1, SAMPLE_RATE);
+            canceller.process(microphone_frame);
+        }
+
+        let echo = echo_path(&far_end);
+        let tail = (frames_count - 300) * FRAME_SIZE..;
+        let echo_return_loss_enhancement =
+            dsp::power_db(&echo[tail.clone()]) - dsp::power_db(&microphone[tail]);
+
+        assert!(
+            echo_return_loss_enhancement > 20.,
+            "ERLE: {echo_return_loss_enhancement} dB"
+        );
+    }
+
+    #[test]
+    fn test_near_end_speech_is_preserved_during_double_talk() {
+        let frames_count = 1500;
+        let far_end = far_end_signal(frames_count);
+        let echo = echo_path(&far_end);
+
+        // Near-end talker in the last 300 frames
+        let near_end = (0..frames_count * FRAME_SIZE)
+            .map(|i| {
+                if i >= (frames_count - 300) * FRAME_SIZE {
+                    0.5 * (2. * std::f32::consts::PI * 300. * i as f32 / SAMPLE_RATE as f32).sin()
+                } else {
+                    0.
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut microphone = echo
+            .iter()
+            .zip(&near_end)
+            .map(|(e, n)| e + n)
+            .collect::<Vec<_>>();
+
+        let reference = EchoReference::new();
+        let mut canceller =
+            EchoCanceller::new(&config(), reference.clone(), SAMPLE_RATE, 1, FRAME_SIZE);
+        let mut writer = reference.writer().unwrap();
+
+        for (far_end_frame, microphone_frame) in far_end
+            .chunks_exact(FRAME_SIZE)
+            .zip(microphone.chunks_exact_mut(FRAME_SIZE))
+        {
+            writer.push(far_end_frame, 1, SAMPLE_RATE);
+            canceller.process(microphone_frame);
+        }
+
+        let tail = (frames_count - 250) * FRAME_SIZE..;
+        let residual = microphone[tail.clone()]
+            .iter()
+            .zip(&near_end[tail.clone()])
+            .map(|(m, n)| m - n)
+            .collect::<Vec<_>>();
+
+        // The near-end signal must pass through, while the echo stays cancelled
+        let signal_to_residual = dsp::power_db(&near_end[tail]) - dsp::power_db(&residual);
+        assert!(signal_to_residual > 15., "{signal_to_residual} dB");
+    }
+
+    #[test]
+    fn test_reference_queue_is_bounded() {
+        let reference = EchoReference::new();
+        let mut writer = reference.writer().unwrap();
+
+        // Played before the capture starts
+        writer.push(&[0.5; SAMPLE_RATE as usize], 1, SAMPLE_RATE);
+        let mut canceller =
+            EchoCanceller::new(&config(), reference.clone(), SAMPLE_RATE, 1, FRAME_SIZE);
+        assert!(reference.0.queue.lock().consumer.is_empty());
+
+        // The capture lags behind: only the maximum delay plus one block is kept
+        writer.push(&[0.5; SAMPLE_RATE as usize], 1, SAMPLE_RATE);
+        canceller.process(&mut [0.; FRAME_SIZE]);
+        let max_len = SAMPLE_RATE as usize * config().max_delay_ms as usize / 1000 + FRAME_SIZE;
+        assert!(reference.0.queue.lock().consumer.len() <= max_len);
+
+        canceller.reset();
+        assert!(reference.0.queue.lock().consumer.is_empty());
+    }
+}
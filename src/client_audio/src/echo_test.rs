@@ -0,0 +1,233 @@
+// Client side of the echo test. The voice is captured and processed like during a call, without the
+// transmit gating, and sent to the echo test stream. The server sends it back after a delay and it
+// is played like a remote speaker. The latency is measured with the capture timestamps carried by
+// the packets, and the packet loss with their sequence numbers.
+
+use crate::{
+    start_capture, AudioDevice, AudioProcessor, CaptureProcessingDesc, FractionalResampler,
+    ProcessorChain, SpeakerId, CAPTURE_FRAME_MS,
+};
+use cpal::Sample;
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    sync::mpsc as smpsc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::mpsc as tmpsc;
+use vors_share_common::prelude::*;
+use vors_share_packets::EchoTestPacketHeader;
+use vors_share_sockets::StreamSender;
+
+// Mixer id of the echo test playback
+pub const ECHO_TEST_SPEAKER_ID: SpeakerId = SpeakerId::MAX;
+
+const ECHO_TEST_REPORT_INTERVAL: Duration = Duration::from_secs(1);
+// Packets later than this are not recognized anymore and stay counted as lost
+const MAX_MISSING_SEQUENCES: usize = 1000;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct EchoTestReport {
+    // Network and server, without the echo delay. Averaged over the report interval.
+    pub round_trip_ms: f32,
+    // From the capture of a frame to its playback, including the framing and the buffering on both
+    // sides. The latency of the audio devices themselves is not included.
+    pub end_to_end_latency_ms: f32,
+    // Since the start of the test
+    pub packet_loss_percent: f32,
+}
+
+pub(crate) fn timestamp_us() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_micros() as u64)
+        .unwrap_or(0)
+}
+
+pub struct EchoTestStats {
+    server_delay_us: u64,
+    // Framing and playback buffering, added to the round trip
+    local_latency_us: u64,
+    next_sequence: Option<u32>,
+    // Sequences skipped so far, in order. A late packet is removed from the lost ones.
+    missing_sequences: VecDeque<u32>,
+    received_count: u64,
+    lost_count: u64,
+    round_trip_sum_us: u64,
+    round_trip_count: u64,
+    window_start_us: Option<u64>,
+}
+
+impl EchoTestStats {
+    pub fn new(server_delay: Duration, playback_buffering: Duration) -> Self {
+        Self {
+            server_delay_us: server_delay.as_micros() as u64,
+            local_latency_us: (Duration::from_millis(CAPTURE_FRAME_MS) + playback_buffering)
+                .as_micros() as u64,
+            next_sequence: None,
+            missing_sequences: VecDeque::new(),
+            received_count: 0,
+            lost_count: 0,
+            round_trip_sum_us: 0,
+            round_trip_count: 0,
+            window_start_us: None,
+        }
+    }
+
+    // Returns a report at the end of each interval
+    pub fn submit(&mut self, header: &EchoTestPacketHeader, now_us: u64) -> Option<EchoTestReport> {
+        match self.next_sequence {
+            Some(next_sequence) if header.sequence < next_sequence => {
+                if let Ok(index) = self.missing_sequences.binary_search(&header.sequence) {
+                    self.missing_sequences.remove(index);
+                    self.lost_count -= 1;
+                } else {
+                    // Duplicate, or too late to be recognized
+                    return None;
+                }
+            }
+            Some(next_sequence) => {
+                self.lost_count += (header.sequence - next_sequence) as u64;
+                self.missing_sequences.extend(
+                    next_sequence.max(header.sequence.saturating_sub(MAX_MISSING_SEQUENCES as u32))
+                        ..header.sequence,
+                );
+                while self.missing_sequences.len() > MAX_MISSING_SEQUENCES {
+                    self.missing_sequences.pop_front();
+                }
+
+                self.next_sequence = Some(header.sequence + 1);
+            }
+            None => self.next_sequence = Some(header.sequence + 1),
+        }
+        self.received_count += 1;
+
+        self.round_trip_sum_us += now_us
+            .saturating_sub(header.capture_timestamp_us)
+            .saturating_sub(self.server_delay_us);
+        self.round_trip_count += 1;
+
+        let window_start_us = *self.window_start_us.get_or_insert(now_us);
+        if now_us - window_start_us < ECHO_TEST_REPORT_INTERVAL.as_micros() as u64 {
+            return None;
+        }
+
+        let round_trip_us = self.round_trip_sum_us / self.round_trip_count;
+        self.round_trip_sum_us = 0;
+        self.round_trip_count = 0;
+        self.window_start_us = Some(now_us);
+
+        Some(EchoTestReport {
+            round_trip_ms: round_trip_us as f32 / 1000.,
+            end_to_end_latency_ms: (round_trip_us + self.local_latency_us) as f32 / 1000.,
+            packet_loss_percent: self.lost_count as f32 * 100.
+                / (self.received_count + self.lost_count) as f32,
+        })
+    }
+}
+
+// Capture and send the voice to the echo test stream, until the stream is closed. The frames are
+// converted to the sample rate and channels count of the local playback, since they are played
+// back as they are.
+pub async fn echo_test_send_loop(
+    device: AudioDevice,
+    processing: CaptureProcessingDesc,
+    playback_sample_rate: u32,
+    playback_channels_count: usize,
+    mut sender: StreamSender<EchoTestPacketHeader>,
+) -> StrResult {
+    let (data_sender, mut data_receiver) = tmpsc::unbounded_channel::<StrResult<Vec<f32>>>();
+    let (_shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
+
+    let sample_rate = start_capture(
+        device,
+        playback_channels_count as _,
+        false,
+        data_sender,
+        None,
+        shutdown_receiver,
+    )?;
+
+    let frame_size = (sample_rate as u64 * CAPTURE_FRAME_MS / 1000) as usize;
+    let frame_samples_count = frame_size * playback_channels_count;
+
+    let mut processor_chain = ProcessorChain::capture(
+        &processing,
+        sample_rate,
+        playback_channels_count,
+        frame_size,
+    );
+    let mut resampler = (sample_rate != playback_sample_rate).then(|| {
+        (
+            FractionalResampler::new(playback_channels_count),
+            sample_rate as f64 / playback_sample_rate as f64,
+        )
+    });
+
+    let mut pending_samples = vec![];
+    let mut resampled = vec![];
+    let mut sequence = 0;
+
+    while let Some(maybe_data) = data_receiver.recv().await {
+        pending_samples.extend(maybe_data?);
+        // The frames completed by this data were captured now
+        let capture_timestamp_us = timestamp_us();
+
+        while pending_samples.len() >= frame_samples_count {
+            let mut frame = pending_samples
+                .drain(0..frame_samples_count)
+                .collect::<Vec<_>>();
+
+            processor_chain.process(&mut frame);
+
+            let frame = if let Some((resampler, ratio)) = &mut resampler {
+                resampled.clear();
+                resampler.process(&frame, *ratio, &mut resampled);
+                &resampled
+            } else {
+                &frame
+            };
+
+            let payload = frame
+                .iter()
+                .flat_map(|s| s.to_sample::<i16>().to_ne_bytes())
+                .collect();
+            let header = EchoTestPacketHeader {
+                sequence,
+                capture_timestamp_us,
+            };
+            sender.send(&header, payload).await.ok();
+
+            sequence += 1;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_echo_test_stats() {
+        let mut stats = EchoTestStats::new(Duration::from_millis(500), Duration::from_millis(40));
+        let packet = |sequence, capture_timestamp_us| EchoTestPacketHeader {
+            sequence,
+            capture_timestamp_us,
+        };
+
+        assert!(stats.submit(&packet(0, 0), 560_000).is_none());
+        // Packets 1 and 2 are missing, then 2 arrives late, twice
+        assert!(stats.submit(&packet(3, 30_000), 610_000).is_none());
+        assert!(stats.submit(&packet(2, 20_000), 620_000).is_none());
+        assert!(stats.submit(&packet(2, 20_000), 640_000).is_none());
+
+        let report = stats.submit(&packet(4, 1_000_000), 1_580_000).unwrap();
+        // (60 + 80 + 100 + 80) / 4
+        assert_eq!(report.round_trip_ms, 80.);
+        assert_eq!(report.end_to_end_latency_ms, 130.);
+        // Only packet 1 is lost
+        assert_eq!(report.packet_loss_percent, 100. * 1. / 5.);
+    }
+}
@@ -0,0 +1,287 @@
+// Level processing of the outgoing voice. The automatic gain control brings the voice to a target
+// loudness, the noise gate attenuates the microphone when it only picks up background noise (and
+// freezes the gain meanwhile, so that the gain control does not pump up the noise during pauses),
+// and the limiter makes sure that the signal never clips on the conversion to i16.
+
+use crate::{dsp, AudioProcessor};
+use vors_share_session::{AutomaticGainControlConfig, NoiseGateConfig};
+
+// The gain control can also reduce the level of hot microphones, down to this gain
+const MIN_GAIN_DB: f32 = -20.;
+
+// Attenuation applied while the noise gate is closed
+const GATE_ATTENUATION_DB: f32 = -40.;
+
+// Output peak level of the limiter. Leaves some margin for the rounding of the conversion to i16.
+const LIMITER_CEILING_DB: f32 = -1.;
+const LIMITER_RELEASE_MS: f32 = 50.;
+
+// Coefficient of a one pole smoother that reaches ~63% of a step in `time_ms`
+fn smoothing_coefficient(update_period_ms: f32, time_ms: f32) -> f32 {
+    1. - (-update_period_ms / time_ms.max(1e-3)).exp()
+}
+
+pub struct NoiseGate {
+    open_threshold_db: f32,
+    close_threshold_db: f32,
+    hold_frames: usize,
+    hold_count: usize,
+    open: bool,
+}
+
+impl NoiseGate {
+    pub fn new(config: &NoiseGateConfig, frame_ms: u64) -> Self {
+        Self {
+            open_threshold_db: config.open_threshold_db,
+            // A close threshold above the open threshold would make the gate flap
+            close_threshold_db: config.close_threshold_db.min(config.open_threshold_db),
+            hold_frames: (config.hold_ms / frame_ms.max(1)) as usize,
+            hold_count: 0,
+            open: false,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.hold_count = 0;
+        self.open = false;
+    }
+
+    // Update the gate with the level of a frame. Returns true if the gate is open.
+    pub fn process(&mut self, level_db: f32) -> bool {
+        if level_db > self.open_threshold_db || (self.open && level_db > self.close_threshold_db) {
+            self.open = true;
+            self.hold_count = self.hold_frames;
+        } else if self.hold_count > 0 {
+            self.hold_count -= 1;
+        } else {
+            self.open = false;
+        }
+
+        self.open
+    }
+}
+
+pub struct AutomaticGainControl {
+    target_level_db: f32,
+    max_gain_db: f32,
+    attack_coefficient: f32,
+    release_coefficient: f32,
+    noise_gate: Option<NoiseGate>,
+    gain_db: f32,
+    // Linear gain applied at the end of the previous frame, gate included
+    last_gain: f32,
+    channels_count: usize,
+}
+
+impl AutomaticGainControl {
+    pub fn new(config: &AutomaticGainControlConfig, channels_count: usize, frame_ms: u64) -> Self {
+        Self {
+            target_level_db: config.target_level_db,
+            max_gain_db: config.max_gain_db.max(0.),
+            attack_coefficient: smoothing_coefficient(frame_ms as f32, config.attack_ms as f32),
+            release_coefficient: smoothing_coefficient(frame_ms as f32, config.release_ms as f32),
+            noise_gate: config
+                .noise_gate
+                .as_option()
+                .map(|config| NoiseGate::new(config, frame_ms)),
+            gain_db: 0.,
+            last_gain: 1.,
+            channels_count,
+        }
+    }
+
+    pub fn gain_db(&self) -> f32 {
+        self.gain_db
+    }
+}
+
+impl AudioProcessor for AutomaticGainControl {
+    fn name(&self) -> &'static str {
+        "automatic_gain_control"
+    }
+
+    fn channels_count(&self) -> usize {
+        self.channels_count
+    }
+
+    fn reset(&mut self) {
+        if let Some(gate) = &mut self.noise_gate {
+            gate.reset();
+        }
+        self.gain_db = 0.;
+        self.last_gain = 1.;
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        let level_db = dsp::power_db(frame);
+
+        let gate_open = self
+            .noise_gate
+            .as_mut()
+            .map(|gate| gate.process(level_db))
+            .unwrap_or(true);
+
+        // While the gate is held open the level is already below the close threshold, adapting
+        // to it would raise the noise.
+        let adapting = gate_open
+            && self
+                .noise_gate
+                .as_ref()
+                .map(|gate| level_db > gate.close_threshold_db)
+                .unwrap_or(true);
+
+        if adapting {
+            let desired_gain_db =
+                (self.target_level_db - level_db).clamp(MIN_GAIN_DB, self.max_gain_db);
+            let coefficient = if desired_gain_db < self.gain_db {
+                self.attack_coefficient
+            } else {
+                self.release_coefficient
+            };
+
+            self.gain_db += (desired_gain_db - self.gain_db) * coefficient;
+        }
+
+        let gain = dsp::db_to_linear(if gate_open {
+            self.gain_db
+        } else {
+            self.gain_db + GATE_ATTENUATION_DB
+        });
+
+        // Interpolate the gain across the frame to avoid zipper noise
+        let frames_count = frame.len() / self.channels_count;
+        for (index, samples) in frame.chunks_exact_mut(self.channels_count).enumerate() {
+            let ratio = (index + 1) as f32 / frames_count as f32;
+            let sample_gain = self.last_gain + (gain - self.last_gain) * ratio;
+            for sample in samples {
+                *sample *= sample_gain;
+            }
+        }
+
+        self.last_gain = gain;
+    }
+}
+
+// Brickwall peak limiter without lookahead. The gain drops instantly to keep the peaks under the
+// ceiling and recovers smoothly. Channels are linked to preserve the stereo image.
+pub struct Limiter {
+    ceiling: f32,
+    release_coefficient: f32,
+    gain: f32,
+    channels_count: usize,
+}
+
+impl Limiter {
+    pub fn new(sample_rate: u32, channels_count: usize) -> Self {
+        Self {
+            ceiling: dsp::db_to_linear(LIMITER_CEILING_DB),
+            release_coefficient: smoothing_coefficient(
+                1000. / sample_rate as f32,
+                LIMITER_RELEASE_MS,
+            ),
+            gain: 1.,
+            channels_count,
+        }
+    }
+}
+
+impl AudioProcessor for Limiter {
+    fn name(&self) -> &'static str {
+        "limiter"
+    }
+
+    fn channels_count(&self) -> usize {
+        self.channels_count
+    }
+
+    fn reset(&mut self) {
+        self.gain = 1.;
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        for samples in frame.chunks_exact_mut(self.channels_count) {
+            let peak = samples.iter().fold(0_f32, |peak, s| peak.max(s.abs()));
+
+            self.gain += (1. - self.gain) * self.release_coefficient;
+            if peak * self.gain > self.ceiling {
+                self.gain = self.ceiling / peak;
+            }
+
+            for sample in samples {
+                *sample *= self.gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+    use vors_share_session::settings_schema::Switch;
+
+    const SAMPLE_RATE: u32 = 16000;
+    const FRAME_SIZE: usize = 160;
+
+    fn tone_frame(amplitude: f32, index: usize) -> Vec<f32> {
+        (0..FRAME_SIZE)
+            .map(|n| {
+                let t = (index * FRAME_SIZE + n) as f32 / SAMPLE_RATE as f32;
+                amplitude * (2. * PI * 440. * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_gain_control_reaches_target_and_freezes_on_noise() {
+        let mut agc = AutomaticGainControl::new(
+            &AutomaticGainControlConfig {
+                target_level_db: -18.,
+                max_gain_db: 30.,
+                attack_ms: 20,
+                release_ms: 200,
+                noise_gate: Switch::Enabled(NoiseGateConfig {
+                    open_threshold_db: -50.,
+                    close_threshold_db: -58.,
+                    hold_ms: 100,
+                }),
+            },
+            1,
+            10,
+        );
+
+        // Quiet voice, -38 dBFS
+        let mut frame = vec![];
+        for index in 0..300 {
+            frame = tone_frame(0.0178, index);
+            agc.process(&mut frame);
+        }
+        assert!((dsp::power_db(&frame) + 18.).abs() < 1.);
+        let speech_gain_db = agc.gain_db();
+
+        // Background noise, -70 dBFS. The gain must not rise and the output must be attenuated.
+        for index in 0..300 {
+            frame = tone_frame(0.0004, index);
+            agc.process(&mut frame);
+        }
+        assert!((agc.gain_db() - speech_gain_db).abs() < 0.5);
+        assert!(dsp::power_db(&frame) < -80.);
+    }
+
+    #[test]
+    fn test_limiter_prevents_clipping() {
+        let mut limiter = Limiter::new(SAMPLE_RATE, 1);
+        let ceiling = dsp::db_to_linear(LIMITER_CEILING_DB);
+
+        let mut frame = vec![];
+        for index in 0..100 {
+            frame = tone_frame(4., index);
+            limiter.process(&mut frame);
+            assert!(frame.iter().all(|s| s.abs() <= ceiling + 1e-6));
+        }
+
+        // Steady state peaks sit right under the ceiling
+        let peak = frame.iter().fold(0_f32, |peak, s| peak.max(s.abs()));
+        assert!(peak > ceiling * 0.9);
+    }
+}
@@ -0,0 +1,248 @@
+// Audio backends that do not need a sound card: a WAV file used as microphone, a WAV file that
+// receives the playback and a null device. They run on a real-time clock, so that the audio loops
+// behave as with a real device.
+
+use crate::{adapt_channels, CAPTURE_FRAME_MS};
+use hound::{SampleFormat, WavReader, WavSpec, WavWriter};
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc as smpsc,
+    thread,
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc as tmpsc;
+use vors_share_common::prelude::*;
+
+// Sleeps until the next period, without accumulating the delay of the work done between ticks
+struct RealTimeClock {
+    start: Instant,
+    period: Duration,
+    ticks: u32,
+}
+
+impl RealTimeClock {
+    fn new(period: Duration) -> Self {
+        Self {
+            start: Instant::now(),
+            period,
+            ticks: 0,
+        }
+    }
+
+    fn wait_next_tick(&mut self) {
+        self.ticks += 1;
+        let deadline = self.start + self.period * self.ticks;
+        thread::sleep(deadline.saturating_duration_since(Instant::now()));
+    }
+}
+
+fn is_shutdown(shutdown_receiver: &smpsc::Receiver<()>) -> bool {
+    !matches!(
+        shutdown_receiver.try_recv(),
+        Err(smpsc::TryRecvError::Empty)
+    )
+}
+
+pub(crate) fn wav_input_sample_rate(path: &Path) -> StrResult<u32> {
+    Ok(WavReader::open(path).map_err(err!())?.spec().sample_rate)
+}
+
+// Send the content of the file one capture frame at a time. If not looping, the sender is dropped
+// at the end of the file, which ends the capture.
+pub(crate) fn spawn_wav_capture(
+    path: PathBuf,
+    looping: bool,
+    channels_count: u16,
+    data_sender: tmpsc::UnboundedSender<StrResult<Vec<f32>>>,
+    shutdown_receiver: smpsc::Receiver<()>,
+) -> StrResult {
+    let mut reader = WavReader::open(&path).map_err(err!())?;
+    let WavSpec {
+        channels: file_channels_count,
+        sample_rate,
+        bits_per_sample,
+        sample_format,
+    } = reader.spec();
+
+    if file_channels_count > 2 {
+        return fmt_e!("WAV files with more than 2 channels are not supported");
+    }
+    if reader.len() == 0 {
+        return fmt_e!("The WAV file {} is empty", path.display());
+    }
+
+    let frame_samples_count =
+        (sample_rate as u64 * CAPTURE_FRAME_MS / 1000) as usize * file_channels_count as usize;
+    let int_scale = 1. / (1_u64 << (bits_per_sample - 1)) as f32;
+
+    thread::spawn(move || {
+        let mut clock = RealTimeClock::new(Duration::from_millis(CAPTURE_FRAME_MS));
+        let mut ended = false;
+
+        while !ended && !is_shutdown(&shutdown_receiver) {
+            let mut data = Vec::with_capacity(frame_samples_count);
+            while data.len() < frame_samples_count {
+                let remaining = frame_samples_count - data.len();
+                let res = if sample_format == SampleFormat::Float {
+                    reader
+                        .samples::<f32>()
+                        .take(remaining)
+                        .map(|s| s.map_err(err!()))
+                        .collect::<StrResult<Vec<_>>>()
+                } else {
+                    reader
+                        .samples::<i32>()
+                        .take(remaining)
+                        .map(|s| s.map(|s| s as f32 * int_scale).map_err(err!()))
+                        .collect::<StrResult<Vec<_>>>()
+                };
+
+                match res {
+                    Ok(samples) if samples.is_empty() => {
+                        if looping {
+                            if let Err(e) = reader.seek(0).map_err(err!()) {
+                                data_sender.send(Err(e)).ok();
+                                return;
+                            }
+                        } else {
+                            ended = true;
+                            break;
+                        }
+                    }
+                    Ok(samples) => data.extend(samples),
+                    Err(e) => {
+                        data_sender.send(Err(e)).ok();
+                        return;
+                    }
+                }
+            }
+
+            if !data.is_empty() {
+                let data = adapt_channels(data, file_channels_count, channels_count);
+                if data_sender.send(Ok(data)).is_err() {
+                    return;
+                }
+            }
+
+            clock.wait_next_tick();
+        }
+    });
+
+    Ok(())
+}
+
+// Send silence until shutdown
+pub(crate) fn spawn_null_capture(
+    sample_rate: u32,
+    channels_count: u16,
+    data_sender: tmpsc::UnboundedSender<StrResult<Vec<f32>>>,
+    shutdown_receiver: smpsc::Receiver<()>,
+) {
+    let frame_samples_count =
+        (sample_rate as u64 * CAPTURE_FRAME_MS / 1000) as usize * channels_count as usize;
+
+    thread::spawn(move || {
+        let mut clock = RealTimeClock::new(Duration::from_millis(CAPTURE_FRAME_MS));
+
+        while !is_shutdown(&shutdown_receiver)
+            && data_sender.send(Ok(vec![0.; frame_samples_count])).is_ok()
+        {
+            clock.wait_next_tick();
+        }
+    });
+}
+
+// Pull one batch at a time from the source, and write it to the file if any, until shutdown
+pub(crate) fn spawn_clocked_playback(
+    mut source: impl Iterator<Item = f32> + Send + 'static,
+    sample_rate: u32,
+    channels_count: u16,
+    batch_frames_count: usize,
+    wav_path: Option<PathBuf>,
+    shutdown_receiver: smpsc::Receiver<()>,
+) -> StrResult {
+    let mut writer = wav_path
+        .map(|path| {
+            WavWriter::create(
+                path,
+                WavSpec {
+                    channels: channels_count,
+                    sample_rate,
+                    bits_per_sample: 16,
+                    sample_format: SampleFormat::Int,
+                },
+            )
+            .map_err(err!())
+        })
+        .transpose()?;
+
+    let batch_samples_count = batch_frames_count * channels_count as usize;
+    let batch_duration = Duration::from_secs_f64(batch_frames_count as f64 / sample_rate as f64);
+
+    thread::spawn(move || {
+        let mut clock = RealTimeClock::new(batch_duration);
+
+        while !is_shutdown(&shutdown_receiver) {
+            for sample in source.by_ref().take(batch_samples_count) {
+                if let Some(writer) = &mut writer {
+                    let sample = (sample.clamp(-1., 1.) * i16::MAX as f32) as i16;
+                    if let Err(e) = writer.write_sample(sample) {
+                        error!("Cannot write the playback to file: {e}");
+                        return;
+                    }
+                }
+            }
+
+            clock.wait_next_tick();
+        }
+
+        if let Some(writer) = writer {
+            writer.finalize().map_err(err!()).ok();
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wav_capture_once() {
+        let path = std::env::temp_dir().join(format!(
+            "vors_headless_capture_test_{}.wav",
+            std::process::id()
+        ));
+        let mut writer = WavWriter::create(
+            &path,
+            WavSpec {
+                channels: 1,
+                sample_rate: 1000,
+                bits_per_sample: 16,
+                sample_format: SampleFormat::Int,
+            },
+        )
+        .unwrap();
+        for i in 0..45 {
+            writer.write_sample(i as i16 * 100).unwrap();
+        }
+        writer.finalize().unwrap();
+
+        let (data_sender, mut data_receiver) = tmpsc::unbounded_channel();
+        let (_shutdown_notifier, shutdown_receiver) = smpsc::channel();
+        spawn_wav_capture(path.clone(), false, 2, data_sender, shutdown_receiver).unwrap();
+
+        let mut samples = vec![];
+        while let Some(data) = data_receiver.blocking_recv() {
+            samples.extend(data.unwrap());
+        }
+
+        // Upmixed to stereo, and the capture ends with the file
+        assert_eq!(samples.len(), 90);
+        assert_eq!(samples[88], samples[89]);
+        assert!((samples[88] - 4400. / 32768.).abs() < 1e-6);
+
+        std::fs::remove_file(path).ok();
+    }
+}
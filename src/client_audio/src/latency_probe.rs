@@ -0,0 +1,491 @@
+// Mouth-to-ear latency measurement on a single machine. Markers (maximum length sequences) are
+// written into a WAV file used as microphone, the frames are sent through a stream socket connected
+// to itself, like a voice stream, and played by the mixer into a clocked null device. Each marker
+// is timestamped when its frame is sent and received, and detected by cross-correlation at the
+// playback sink, so that the latency is split into:
+// * capture buffering: from the nominal capture time of the marker to the sending of its frame
+// * network: through the loopback socket
+// * jitter buffer: from the reception to the playback batch that contains the marker
+// * playback buffering: from the start of that batch to the playback of the marker
+// The latency of real audio devices is not included. No voice processing is applied.
+
+use crate::{
+    decode_pcm_packet, headless, receive_samples_loop, start_capture, timestamp_us, AudioDevice,
+    Mixer, PlaybackProcessingDesc, ProcessorChain, StreamingSource, CAPTURE_FRAME_MS,
+};
+use cpal::Sample;
+use hound::{SampleFormat, WavSpec, WavWriter};
+use serde::Serialize;
+use std::{
+    collections::VecDeque,
+    env, fs,
+    net::{Ipv4Addr, UdpSocket},
+    path::Path,
+    process,
+    sync::{mpsc as smpsc, Arc},
+    time::{Duration, Instant},
+};
+use tokio::sync::mpsc as tmpsc;
+use vors_share_common::{parking_lot::Mutex, prelude::*};
+use vors_share_packets::EchoTestPacketHeader;
+use vors_share_session::{AudioBufferingConfig, SocketBufferSize, SocketProtocol};
+use vors_share_sockets::{StreamSender, StreamSocketBuilder};
+
+const SAMPLE_RATE: u32 = 48000;
+const MAX_PACKET_SIZE: usize = 1400;
+// The socket carries only the probe stream
+const PROBE_STREAM_ID: u16 = 0;
+
+// Must be longer than the measured latency, so that each marker is played before the next one is
+// captured, and a multiple of the capture frame duration
+const MARKER_INTERVAL_MS: u64 = 500;
+const MARKER_AMPLITUDE: f32 = 0.5;
+// Length 2^9 - 1, about 10 ms
+const MLS_ORDER: u32 = 9;
+// Normalized cross-correlation above which a marker is detected. The correlation of a maximum
+// length sequence with a shifted copy of itself is -1/length.
+const DETECTION_THRESHOLD: f32 = 0.5;
+
+#[derive(Serialize, Clone, Copy, Default, PartialEq, Debug)]
+pub struct LatencyComponents {
+    pub capture_buffering_ms: f32,
+    pub network_ms: f32,
+    pub jitter_buffer_ms: f32,
+    pub playback_buffering_ms: f32,
+    pub total_ms: f32,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct LatencyMeasurementReport {
+    // One entry per detected marker
+    pub markers: Vec<LatencyComponents>,
+    pub average: LatencyComponents,
+    pub missed_markers_count: usize,
+}
+
+// Fibonacci LFSR with the primitive polynomial x^9 + x^5 + 1
+fn maximum_length_sequence() -> Vec<f32> {
+    let mut state = 1_u32;
+
+    (0..(1 << MLS_ORDER) - 1)
+        .map(|_| {
+            let bit = state & 1;
+            let feedback = (state ^ (state >> 4)) & 1;
+            state = (state >> 1) | (feedback << (MLS_ORDER - 1));
+
+            if bit == 1 {
+                MARKER_AMPLITUDE
+            } else {
+                -MARKER_AMPLITUDE
+            }
+        })
+        .collect()
+}
+
+fn marker_interval_frames() -> usize {
+    (SAMPLE_RATE as u64 * MARKER_INTERVAL_MS / 1000) as usize
+}
+
+// Marker i starts at the beginning of a capture frame, after i + 1 intervals of silence
+fn marker_index(capture_frame_index: u32) -> Option<usize> {
+    let frames_per_marker = (MARKER_INTERVAL_MS / CAPTURE_FRAME_MS) as u32;
+
+    (capture_frame_index > 0 && capture_frame_index % frames_per_marker == 0)
+        .then(|| (capture_frame_index / frames_per_marker - 1) as usize)
+}
+
+// Mono. The last marker is followed by more than an interval of silence, so that it is played
+// before the capture ends.
+fn write_marker_file(path: &Path, markers_count: usize) -> StrResult {
+    let mut writer = WavWriter::create(
+        path,
+        WavSpec {
+            channels: 1,
+            sample_rate: SAMPLE_RATE,
+            bits_per_sample: 16,
+            sample_format: SampleFormat::Int,
+        },
+    )
+    .map_err(err!())?;
+
+    let marker = maximum_length_sequence();
+    let interval_frames = marker_interval_frames();
+    for _ in 0..interval_frames {
+        writer.write_sample(0_i16).map_err(err!())?;
+    }
+    for _ in 0..markers_count {
+        for sample in &marker {
+            writer
+                .write_sample(sample.to_sample::<i16>())
+                .map_err(err!())?;
+        }
+        for _ in marker.len()..interval_frames {
+            writer.write_sample(0_i16).map_err(err!())?;
+        }
+    }
+    for _ in 0..interval_frames {
+        writer.write_sample(0_i16).map_err(err!())?;
+    }
+
+    writer.finalize().map_err(err!())
+}
+
+struct MarkerDetector {
+    marker: Vec<f32>,
+    marker_energy: f64,
+    history: VecDeque<f32>,
+    history_energy: f64,
+    // Count of pushed samples
+    position: u64,
+    // Best correlation above the threshold, with the position of the start of the marker
+    candidate: Option<(f32, u64)>,
+    holdoff_end: u64,
+}
+
+impl MarkerDetector {
+    fn new(marker: Vec<f32>) -> Self {
+        Self {
+            marker_energy: marker.iter().map(|s| (s * s) as f64).sum(),
+            history: VecDeque::with_capacity(marker.len()),
+            history_energy: 0.,
+            position: 0,
+            candidate: None,
+            holdoff_end: 0,
+            marker,
+        }
+    }
+
+    // Returns the position of the first sample of a marker. The detection is reported on the sample
+    // that follows the correlation peak.
+    fn push(&mut self, sample: f32) -> Option<u64> {
+        if self.history.len() == self.marker.len() {
+            let old_sample = self.history.pop_front().unwrap_or(0.);
+            self.history_energy -= (old_sample * old_sample) as f64;
+        }
+        self.history.push_back(sample);
+        self.history_energy += (sample * sample) as f64;
+        self.position += 1;
+
+        // Most of the signal is silence, the correlation is computed only when the window contains
+        // enough energy
+        let correlation = if self.history.len() == self.marker.len()
+            && self.position >= self.holdoff_end
+            && self.history_energy > self.marker_energy / 16.
+        {
+            let product = self
+                .history
+                .iter()
+                .zip(&self.marker)
+                .map(|(h, m)| h * m)
+                .sum::<f32>();
+
+            product / (self.history_energy * self.marker_energy).sqrt() as f32
+        } else {
+            0.
+        };
+
+        match self.candidate {
+            Some((best, _)) if correlation > best => (),
+            Some((_, start)) => {
+                self.candidate = None;
+                self.holdoff_end = start + 2 * self.marker.len() as u64;
+
+                return Some(start);
+            }
+            None if correlation <= DETECTION_THRESHOLD => return None,
+            None => (),
+        }
+        self.candidate = Some((correlation, self.position - self.marker.len() as u64));
+
+        None
+    }
+}
+
+#[derive(Default, Clone, Copy)]
+struct MarkerTimestamps {
+    sent: Option<Instant>,
+    received: Option<Instant>,
+    batch_start: Option<Instant>,
+    played: Option<Instant>,
+}
+
+struct ProbeState {
+    capture_start: Option<Instant>,
+    markers: Vec<MarkerTimestamps>,
+    // Detections at the sink are attributed to this marker
+    last_received_marker: Option<usize>,
+}
+
+// Wraps the mixer output played by the clocked device, whose batches are pulled at the pace of the
+// playback
+struct ProbeSink {
+    source: StreamingSource,
+    state: Arc<Mutex<ProbeState>>,
+    detector: MarkerDetector,
+    batch_frames_count: usize,
+    position: u64,
+    // Position and time of the recent batches
+    batches: VecDeque<(u64, Instant)>,
+}
+
+impl Iterator for ProbeSink {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        if self.position % self.batch_frames_count as u64 == 0 {
+            if self.batches.len() == 4 {
+                self.batches.pop_front();
+            }
+            self.batches.push_back((self.position, Instant::now()));
+        }
+        self.position += 1;
+
+        let sample = self.source.next()?;
+
+        if let Some(start) = self.detector.push(sample) {
+            let batch = self
+                .batches
+                .iter()
+                .rev()
+                .find(|(batch_position, _)| *batch_position <= start);
+            let mut state = self.state.lock();
+            if let (Some(&(batch_position, batch_start)), Some(index)) =
+                (batch, state.last_received_marker)
+            {
+                let marker = &mut state.markers[index];
+                if marker.played.is_none() {
+                    marker.batch_start = Some(batch_start);
+                    marker.played = Some(
+                        batch_start
+                            + Duration::from_secs_f64(
+                                (start - batch_position) as f64 / SAMPLE_RATE as f64,
+                            ),
+                    );
+                }
+            }
+        }
+
+        Some(sample)
+    }
+}
+
+async fn probe_send_loop(
+    device: AudioDevice,
+    state: Arc<Mutex<ProbeState>>,
+    mut sender: StreamSender<EchoTestPacketHeader>,
+) -> StrResult {
+    let (data_sender, mut data_receiver) = tmpsc::unbounded_channel::<StrResult<Vec<f32>>>();
+    let (_shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
+
+    state.lock().capture_start = Some(Instant::now());
+    start_capture(device, 1, false, data_sender, None, shutdown_receiver)?;
+
+    let frame_size = (SAMPLE_RATE as u64 * CAPTURE_FRAME_MS / 1000) as usize;
+    let mut pending_samples = vec![];
+    let mut sequence = 0;
+
+    while let Some(maybe_data) = data_receiver.recv().await {
+        pending_samples.extend(maybe_data?);
+
+        while pending_samples.len() >= frame_size {
+            let payload = pending_samples
+                .drain(0..frame_size)
+                .flat_map(|s| s.to_sample::<i16>().to_ne_bytes())
+                .collect();
+            let header = EchoTestPacketHeader {
+                sequence,
+                capture_timestamp_us: timestamp_us(),
+            };
+            sender.send(&header, payload).await?;
+
+            if let Some(index) = marker_index(sequence) {
+                if let Some(marker) = state.lock().markers.get_mut(index) {
+                    marker.sent = Some(Instant::now());
+                }
+            }
+
+            sequence += 1;
+        }
+    }
+
+    Ok(())
+}
+
+fn build_report(state: &ProbeState) -> StrResult<LatencyMeasurementReport> {
+    let Some(capture_start) = state.capture_start else {
+        return fmt_e!("The capture did not start");
+    };
+    let ms = |from: Instant, to: Instant| to.saturating_duration_since(from).as_secs_f32() * 1000.;
+
+    let markers = state
+        .markers
+        .iter()
+        .enumerate()
+        .filter_map(|(index, marker)| {
+            let nominal =
+                capture_start + Duration::from_millis(MARKER_INTERVAL_MS * (index as u64 + 1));
+            let sent = marker.sent?;
+            let received = marker.received?;
+            let batch_start = marker.batch_start?;
+            let played = marker.played?;
+
+            Some(LatencyComponents {
+                capture_buffering_ms: ms(nominal, sent),
+                network_ms: ms(sent, received),
+                jitter_buffer_ms: ms(received, batch_start),
+                playback_buffering_ms: ms(batch_start, played),
+                total_ms: ms(nominal, played),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    if markers.is_empty() {
+        return fmt_e!("No marker has been detected");
+    }
+
+    let count = markers.len() as f32;
+    let average = LatencyComponents {
+        capture_buffering_ms: markers.iter().map(|m| m.capture_buffering_ms).sum::<f32>() / count,
+        network_ms: markers.iter().map(|m| m.network_ms).sum::<f32>() / count,
+        jitter_buffer_ms: markers.iter().map(|m| m.jitter_buffer_ms).sum::<f32>() / count,
+        playback_buffering_ms: markers.iter().map(|m| m.playback_buffering_ms).sum::<f32>() / count,
+        total_ms: markers.iter().map(|m| m.total_ms).sum::<f32>() / count,
+    };
+
+    Ok(LatencyMeasurementReport {
+        missed_markers_count: state.markers.len() - markers.len(),
+        markers,
+        average,
+    })
+}
+
+// Run the measurement with the given playback buffering. Takes about half a second per marker.
+pub async fn measure_latency(
+    buffering: AudioBufferingConfig,
+    markers_count: usize,
+) -> StrResult<LatencyMeasurementReport> {
+    let path = env::temp_dir().join(format!("vors_latency_markers_{}.wav", process::id()));
+    write_marker_file(&path, markers_count)?;
+    let device = AudioDevice::new_wav_input(&path, false);
+
+    let res = async {
+        let device = device?;
+
+        // Pick a free port
+        let port = UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|socket| socket.local_addr())
+            .map_err(err!())?
+            .port();
+        let socket = StreamSocketBuilder::connect_to_client(
+            Ipv4Addr::LOCALHOST.into(),
+            port,
+            SocketProtocol::Udp,
+            SocketBufferSize::Default,
+            SocketBufferSize::Default,
+            MAX_PACKET_SIZE,
+        )
+        .await?;
+        let sender = socket.request_stream(PROBE_STREAM_ID).await?;
+        let receiver = socket.subscribe_to_stream(PROBE_STREAM_ID).await?;
+
+        let state = Arc::new(Mutex::new(ProbeState {
+            capture_start: None,
+            markers: vec![MarkerTimestamps::default(); markers_count],
+            last_received_marker: None,
+        }));
+
+        let mixer = Mixer::new(
+            1,
+            SAMPLE_RATE,
+            &buffering,
+            PlaybackProcessingDesc::default(),
+        );
+        let playback_buffer = mixer.add_speaker(0, None)?;
+        let batch_frames_count = mixer.batch_frames_count();
+        let average_buffer_frames_count =
+            (SAMPLE_RATE as u64 * buffering.average_buffering_ms / 1000) as usize;
+
+        let (_shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
+        headless::spawn_clocked_playback(
+            ProbeSink {
+                source: StreamingSource::new(mixer, None)?,
+                state: Arc::clone(&state),
+                detector: MarkerDetector::new(maximum_length_sequence()),
+                batch_frames_count,
+                position: 0,
+                batches: VecDeque::new(),
+            },
+            SAMPLE_RATE,
+            1,
+            batch_frames_count,
+            None,
+            shutdown_receiver,
+        )?;
+
+        let receive_loop = receive_samples_loop(
+            receiver,
+            |header: &EchoTestPacketHeader, packet, samples| {
+                if let Some(index) = marker_index(header.sequence) {
+                    let mut state = state.lock();
+                    if let Some(marker) = state.markers.get_mut(index) {
+                        marker.received = Some(Instant::now());
+                        state.last_received_marker = Some(index);
+                    }
+                }
+
+                decode_pcm_packet(packet, samples)
+            },
+            ProcessorChain::new(1),
+            playback_buffer,
+            1,
+            average_buffer_frames_count,
+        );
+
+        // The capture ends with the file
+        tokio::select! {
+            res = probe_send_loop(device, Arc::clone(&state), sender) => res?,
+            res = receive_loop => res?,
+            res = socket.receive_loop() => res?,
+        }
+
+        let state = state.lock();
+        build_report(&state)
+    }
+    .await;
+
+    fs::remove_file(&path).ok();
+
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marker_detection() {
+        let marker = maximum_length_sequence();
+        assert_eq!(marker.len(), 511);
+        assert_eq!(marker.iter().filter(|s| **s > 0.).count(), 256);
+
+        let mut detector = MarkerDetector::new(marker.clone());
+        let mut detections = vec![];
+        let signal = (0..1000)
+            .map(|_| 0.)
+            .chain(marker.iter().map(|s| s * 0.3 + 0.01))
+            .chain((0..2000).map(|i| (i as f32 * 0.05).sin() * 0.1))
+            .chain(marker.iter().copied())
+            .chain((0..1000).map(|_| 0.));
+        for (position, sample) in signal.enumerate() {
+            if let Some(start) = detector.push(sample) {
+                detections.push((start, position as u64));
+            }
+        }
+
+        assert_eq!(detections.len(), 2);
+        assert_eq!(detections[0].0, 1000);
+        assert_eq!(detections[1].0, 1000 + 511 + 2000);
+        // Detected once the whole marker has been received
+        assert_eq!(detections[0].1, 1000 + 511);
+    }
+}
@@ -1,6 +1,60 @@
-use vors_share_common::{once_cell::sync::Lazy, parking_lot::Mutex, prelude::*};
+mod comfort_noise;
+mod device_monitor;
+mod devices;
+mod drift;
+mod dsp;
+mod ducking;
+mod echo_cancellation;
+mod echo_test;
+mod gain;
+mod headless;
+mod latency_probe;
+mod meter;
+mod mixer;
+mod noise_suppression;
+mod playback_buffer;
+mod processor;
+mod recorder;
+mod ring_buffer;
+mod sidetone;
+mod soundboard;
+mod spatial;
+mod system_audio;
+mod tap;
+mod transmit;
+mod vad;
+mod voice_effects;
+
+pub use comfort_noise::*;
+pub use device_monitor::*;
+pub use devices::*;
+pub use drift::*;
+pub use ducking::*;
+pub use echo_cancellation::*;
+pub use echo_test::*;
+pub use gain::*;
+pub use latency_probe::*;
+pub use meter::*;
+pub use mixer::*;
+pub use noise_suppression::*;
+pub use playback_buffer::*;
+pub use processor::*;
+pub use recorder::*;
+pub use ring_buffer::*;
+pub use sidetone::*;
+pub use soundboard::*;
+pub use spatial::*;
+pub use system_audio::*;
+pub use tap::*;
+pub use transmit::*;
+pub use vad::*;
+pub use voice_effects::*;
+
+use vors_share_common::{once_cell::sync::Lazy, prelude::*};
+use vors_share_packets::AudioPacketHeader;
 use vors_share_session::{
     AudioBufferingConfig, CustomAudioDeviceConfig, LinuxAudioBackend, MicrophoneDevicesConfig,
+    SidetoneSource,
 };
 use vors_share_sockets::{ReceiverBuffer, StreamReceiver, StreamSender};
 use cpal::{
@@ -8,13 +62,60 @@ use cpal::{
     BufferSize, Device, Host, Sample, SampleFormat, StreamConfig,
 };
 use rodio::{OutputStream, Source};
+use serde::{de::DeserializeOwned, Serialize};
 use std::{
-    collections::{HashMap, VecDeque},
-    sync::{mpsc as smpsc, Arc},
+    collections::HashMap,
+    path::PathBuf,
+    sync::mpsc as smpsc,
     thread,
+    time::{Duration, Instant},
 };
 use tokio::sync::mpsc as tmpsc;
 
+// Duration of the frames processed by the capture pipeline. Each frame is sent in its own packet.
+pub const CAPTURE_FRAME_MS: u64 = 10;
+
+// How often the capture thread checks for stream errors and shutdown requests
+const STREAM_SUPERVISION_INTERVAL: Duration = Duration::from_millis(100);
+
+// Notifications from the audio loops. They are forwarded to the dashboard by the caller.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum AudioEvent {
+    VoiceActivity {
+        speaking: bool,
+    },
+    Transmitting {
+        active: bool,
+    },
+    DeviceAdded {
+        name: String,
+        direction: DeviceDirection,
+    },
+    DeviceRemoved {
+        name: String,
+        direction: DeviceDirection,
+    },
+    // A stream has been moved to another device after a hot-plug
+    DeviceSwitched {
+        name: String,
+        direction: DeviceDirection,
+        fallback: bool,
+    },
+    // Level of the outgoing voice, after processing. Also reported by the microphone test.
+    InputLevel {
+        level: AudioLevel,
+    },
+    SpeakerLevel {
+        speaker: SpeakerId,
+        level: AudioLevel,
+    },
+    // Sent every second while the echo test is running
+    EchoTest {
+        report: EchoTestReport,
+    },
+}
+
 static VIRTUAL_MICROPHONE_PAIRS: Lazy<HashMap<&str, &str>> = Lazy::new(|| {
     [
         ("CABLE Input", "CABLE Output"),
@@ -44,6 +145,7 @@ fn device_from_custom_config(host: &Host, config: &CustomAudioDeviceConfig) -> S
             .map_err(err!())?
             .nth(*index)
             .ok_or_else(|| format!("Cannot find audio device at index {index}"))?,
+        CustomAudioDeviceConfig::Id(id) => device_from_id(id)?,
     })
 }
 
@@ -75,10 +177,25 @@ fn microphone_pair_from_sink_name(host: &Host, sink_name: &str) -> StrResult<(De
     }
 }
 
+enum DeviceBackend {
+    Cpal(Device),
+    WavInput {
+        path: PathBuf,
+        looping: bool,
+        sample_rate: u32,
+    },
+    WavOutput(PathBuf),
+    Null {
+        sample_rate: u32,
+    },
+}
+
 #[allow(dead_code)]
 pub struct AudioDevice {
-    inner: Device,
+    inner: DeviceBackend,
     is_output: bool,
+    // Used to reopen the stream after a hot-plug. None if the device must not be replaced.
+    failover: Option<DeviceSelector>,
 }
 
 #[cfg_attr(not(target_os = "linux"), allow(unused_variables))]
@@ -87,14 +204,7 @@ impl AudioDevice {
         linux_backend: Option<LinuxAudioBackend>,
         config: Option<&CustomAudioDeviceConfig>,
     ) -> StrResult<Self> {
-        #[cfg(target_os = "linux")]
-        let host = match linux_backend {
-            Some(LinuxAudioBackend::Alsa) => cpal::host_from_id(cpal::HostId::Alsa).unwrap(),
-            Some(LinuxAudioBackend::Jack) => cpal::host_from_id(cpal::HostId::Jack).unwrap(),
-            None => cpal::default_host(),
-        };
-        #[cfg(not(target_os = "linux"))]
-        let host = cpal::default_host();
+        let host = audio_host(linux_backend);
 
         let device = match config {
             None => host
@@ -104,24 +214,65 @@ impl AudioDevice {
         };
 
         Ok(Self {
-            inner: device,
+            inner: DeviceBackend::Cpal(device),
             is_output: true,
+            failover: Some(DeviceSelector {
+                linux_backend,
+                config: config.cloned(),
+                direction: DeviceDirection::Output,
+            }),
         })
     }
 
     pub fn new_input(config: Option<CustomAudioDeviceConfig>) -> StrResult<Self> {
         let host = cpal::default_host();
 
-        let device = match config {
+        let device = match &config {
             None => host
                 .default_input_device()
                 .ok_or_else(|| "No input audio device found".to_owned())?,
-            Some(config) => device_from_custom_config(&host, &config)?,
+            Some(config) => device_from_custom_config(&host, config)?,
+        };
+
+        Ok(Self {
+            inner: DeviceBackend::Cpal(device),
+            is_output: false,
+            failover: Some(DeviceSelector {
+                linux_backend: None,
+                config,
+                direction: DeviceDirection::Input,
+            }),
+        })
+    }
+
+    // Capture the audio played by this computer. On Windows the output device is captured in
+    // loopback mode, elsewhere the monitor of the output is exposed as an input device.
+    pub fn new_loopback(
+        linux_backend: Option<LinuxAudioBackend>,
+        config: Option<&CustomAudioDeviceConfig>,
+    ) -> StrResult<Self> {
+        if cfg!(windows) {
+            return Self::new_output(linux_backend, config);
+        }
+
+        let host = audio_host(linux_backend);
+
+        let device = match config {
+            None => device_from_custom_config(
+                &host,
+                &CustomAudioDeviceConfig::NameSubstring("monitor".into()),
+            )
+            .map_err(|_| {
+                "No loopback audio device found, select the monitor of the output device".to_owned()
+            })?,
+            Some(config) => device_from_custom_config(&host, config)?,
         };
 
         Ok(Self {
-            inner: device,
+            inner: DeviceBackend::Cpal(device),
             is_output: false,
+            // The failover would fall back to the default input device, which is the microphone
+            failover: None,
         })
     }
 
@@ -130,14 +281,7 @@ impl AudioDevice {
         linux_backend: Option<LinuxAudioBackend>,
         config: MicrophoneDevicesConfig,
     ) -> StrResult<(Self, Self)> {
-        #[cfg(target_os = "linux")]
-        let host = match linux_backend {
-            Some(LinuxAudioBackend::Alsa) => cpal::host_from_id(cpal::HostId::Alsa).unwrap(),
-            Some(LinuxAudioBackend::Jack) => cpal::host_from_id(cpal::HostId::Jack).unwrap(),
-            None => cpal::default_host(),
-        };
-        #[cfg(not(target_os = "linux"))]
-        let host = cpal::default_host();
+        let host = audio_host(linux_backend);
 
         let (sink, source) = match config {
             MicrophoneDevicesConfig::Automatic => {
@@ -171,33 +315,94 @@ impl AudioDevice {
 
         Ok((
             Self {
-                inner: sink,
+                inner: DeviceBackend::Cpal(sink),
                 is_output: true,
+                failover: None,
             },
             Self {
-                inner: source,
+                inner: DeviceBackend::Cpal(source),
                 is_output: false,
+                failover: None,
             },
         ))
     }
 
-    pub fn input_sample_rate(&self) -> StrResult<u32> {
-        let config = self
-            .inner
-            .default_input_config()
-            // On Windows, loopback devices are not recognized as input devices. Use output config.
-            .or_else(|_| self.inner.default_output_config())
-            .map_err(err!())?;
+    // Use a WAV file as microphone. If not looping, the capture ends with the file.
+    pub fn new_wav_input(path: impl Into<PathBuf>, looping: bool) -> StrResult<Self> {
+        let path = path.into();
+        let sample_rate = headless::wav_input_sample_rate(&path)?;
+
+        Ok(Self {
+            inner: DeviceBackend::WavInput {
+                path,
+                looping,
+                sample_rate,
+            },
+            is_output: false,
+            failover: None,
+        })
+    }
+
+    // Write the playback to a WAV file, with the sample rate and channels count of the playback
+    pub fn new_wav_output(path: impl Into<PathBuf>) -> Self {
+        Self {
+            inner: DeviceBackend::WavOutput(path.into()),
+            is_output: true,
+            failover: None,
+        }
+    }
+
+    // Captures silence and discards the playback. The sample rate is used only for the capture.
+    pub fn new_null(sample_rate: u32) -> Self {
+        Self {
+            inner: DeviceBackend::Null { sample_rate },
+            is_output: false,
+            failover: None,
+        }
+    }
+
+    fn cpal_device(&self) -> StrResult<&Device> {
+        match &self.inner {
+            DeviceBackend::Cpal(device) => Ok(device),
+            _ => fmt_e!("Not a sound card device"),
+        }
+    }
 
-        Ok(config.sample_rate().0)
+    pub fn input_sample_rate(&self) -> StrResult<u32> {
+        match &self.inner {
+            DeviceBackend::Cpal(device) => {
+                let config = device
+                    .default_input_config()
+                    // On Windows, loopback devices are not recognized as input devices. Use output
+                    // config.
+                    .or_else(|_| device.default_output_config())
+                    .map_err(err!())?;
+
+                Ok(config.sample_rate().0)
+            }
+            DeviceBackend::WavInput { sample_rate, .. } | DeviceBackend::Null { sample_rate } => {
+                Ok(*sample_rate)
+            }
+            DeviceBackend::WavOutput(_) => fmt_e!("A WAV output cannot be used for capture"),
+        }
     }
 }
 
 pub fn is_same_device(device1: &AudioDevice, device2: &AudioDevice) -> bool {
-    if let (Ok(name1), Ok(name2)) = (device1.inner.name(), device2.inner.name()) {
-        name1 == name2
-    } else {
-        false
+    match (&device1.inner, &device2.inner) {
+        (DeviceBackend::Cpal(device1), DeviceBackend::Cpal(device2)) => {
+            if let (Ok(name1), Ok(name2)) = (device1.name(), device2.name()) {
+                name1 == name2
+            } else {
+                false
+            }
+        }
+        (
+            DeviceBackend::WavInput { path: path1, .. },
+            DeviceBackend::WavInput { path: path2, .. },
+        )
+        | (DeviceBackend::WavOutput(path1), DeviceBackend::WavOutput(path2)) => path1 == path2,
+        _ => false,
     }
 }
 
@@ -210,7 +415,7 @@ fn get_windows_device(device: &AudioDevice) -> StrResult<windows::Win32::Media::
         System::Com::{self, CLSCTX_ALL, COINIT_MULTITHREADED, STGM_READ},
     };
 
-    let device_name = device.inner.name().map_err(err!())?;
+    let device_name = device.cpal_device()?.name().map_err(err!())?;
 
     unsafe {
         // This will fail the second time is called, ignore the error
@@ -288,18 +493,35 @@ fn set_mute_windows_device(device: &AudioDevice, mute: bool) -> StrResult {
     Ok(())
 }
 
-#[cfg_attr(not(windows), allow(unused_variables))]
-pub async fn record_audio_loop(
-    device: AudioDevice,
+// Convert between mono and stereo
+pub(crate) fn adapt_channels(
+    data: Vec<f32>,
+    device_channels_count: u16,
     channels_count: u16,
-    mute: bool,
-    mut sender: StreamSender<()>,
-) -> StrResult {
+) -> Vec<f32> {
+    if device_channels_count == 1 && channels_count == 2 {
+        data.iter().flat_map(|&s| [s, s]).collect()
+    } else if device_channels_count == 2 && channels_count == 1 {
+        data.chunks_exact(2).map(|c| c[0]).collect()
+    } else {
+        data
+    }
+}
+
+// Opens a capture stream on the device. Returns the stream and the sample rate of the device. If a
+// target sample rate is provided, the samples are resampled to it, so that the processing is not
+// affected when the stream is moved to another device.
+fn open_input_stream(
+    device: &Device,
+    channels_count: u16,
+    target_sample_rate: Option<u32>,
+    data_sender: tmpsc::UnboundedSender<StrResult<Vec<f32>>>,
+    error_sender: smpsc::Sender<String>,
+) -> StrResult<(cpal::Stream, u32)> {
     let config = device
-        .inner
         .default_input_config()
         // On Windows, loopback devices are not recognized as input devices. Use output config.
-        .or_else(|_| device.inner.default_output_config())
+        .or_else(|_| device.default_output_config())
         .map_err(err!())?;
 
     if config.channels() > 2 {
@@ -310,239 +532,541 @@ pub async fn record_audio_loop(
         );
     }
 
+    let sample_rate = config.sample_rate().0;
+    let mut resampler = target_sample_rate
+        .filter(|&target| target != sample_rate)
+        .map(|target| {
+            (
+                FractionalResampler::new(channels_count as _),
+                sample_rate as f64 / target as f64,
+            )
+        });
+
     let stream_config = StreamConfig {
         channels: config.channels(),
         sample_rate: config.sample_rate(),
         buffer_size: BufferSize::Default,
     };
 
-    // data_sender/receiver is the bridge between tokio and std thread
-    let (data_sender, mut data_receiver) = tmpsc::unbounded_channel::<StrResult<Vec<_>>>();
-    let (_shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
+    let stream = device
+        .build_input_stream_raw(
+            &stream_config,
+            config.sample_format(),
+            move |data, _| {
+                let data = if config.sample_format() == SampleFormat::F32 {
+                    data.bytes()
+                        .chunks_exact(4)
+                        .map(|b| f32::from_ne_bytes([b[0], b[1], b[2], b[3]]))
+                        .collect::<Vec<_>>()
+                } else {
+                    data.bytes()
+                        .chunks_exact(2)
+                        .map(|b| i16::from_ne_bytes([b[0], b[1]]).to_sample::<f32>())
+                        .collect()
+                };
+
+                let mut data = adapt_channels(data, config.channels(), channels_count);
+
+                if let Some((resampler, ratio)) = &mut resampler {
+                    let mut resampled = Vec::with_capacity(data.len());
+                    resampler.process(&data, *ratio, &mut resampled);
+                    data = resampled;
+                }
+
+                data_sender.send(Ok(data)).ok();
+            },
+            move |e| {
+                error_sender.send(e.to_string()).ok();
+            },
+            None,
+        )
+        .map_err(err!())?;
+
+    stream.play().map_err(err!())?;
+
+    Ok((stream, sample_rate))
+}
+
+// Returns the sample rate. The captured samples are sent through data_sender until the shutdown
+// notifier is dropped. If the device supports failover, the stream is moved to another device when
+// it fails or when its device is unplugged, otherwise a stream error ends the capture.
+#[cfg_attr(not(windows), allow(unused_variables))]
+fn start_cpal_capture(
+    device: AudioDevice,
+    channels_count: u16,
+    mute: bool,
+    data_sender: tmpsc::UnboundedSender<StrResult<Vec<f32>>>,
+    events_sender: Option<tmpsc::UnboundedSender<AudioEvent>>,
+    shutdown_receiver: smpsc::Receiver<()>,
+) -> StrResult<u32> {
+    let mut current_device = device.cpal_device()?.clone();
+    let (ready_sender, ready_receiver) = smpsc::channel();
 
-    let thread_callback = {
-        let data_sender = data_sender.clone();
-        move || {
-            #[cfg(windows)]
-            if mute && device.is_output {
-                set_mute_windows_device(&device, true).ok();
+    // use a std thread to store the stream object. The stream object must be destroyed on the same
+    // thread of creation.
+    thread::spawn(move || {
+        let (error_sender, error_receiver) = smpsc::channel();
+
+        let res = open_input_stream(
+            &current_device,
+            channels_count,
+            None,
+            data_sender.clone(),
+            error_sender.clone(),
+        );
+        let (mut _stream, sample_rate) = match res {
+            Ok(res) => res,
+            Err(e) => {
+                ready_sender.send(Err(e)).ok();
+                return;
             }
+        };
+        ready_sender.send(Ok(sample_rate)).ok();
 
-            let stream = device
-                .inner
-                .build_input_stream_raw(
-                    &stream_config,
-                    config.sample_format(),
-                    {
-                        let data_sender = data_sender.clone();
-                        move |data, _| {
-                            let data = if config.sample_format() == SampleFormat::F32 {
-                                data.bytes()
-                                    .chunks_exact(4)
-                                    .flat_map(|b| {
-                                        f32::from_ne_bytes([b[0], b[1], b[2], b[3]])
-                                            .to_sample::<i16>()
-                                            .to_ne_bytes()
-                                            .to_vec()
-                                    })
-                                    .collect()
-                            } else {
-                                data.bytes().to_vec()
-                            };
-
-                            let data = if config.channels() == 1 && channels_count == 2 {
-                                data.chunks_exact(2)
-                                    .flat_map(|c| vec![c[0], c[1], c[0], c[1]])
-                                    .collect()
-                            } else if config.channels() == 2 && channels_count == 1 {
-                                data.chunks_exact(4)
-                                    .flat_map(|c| vec![c[0], c[1]])
-                                    .collect()
-                            } else {
-                                data
-                            };
-
-                            data_sender.send(Ok(data)).ok();
-                        }
-                    },
-                    {
-                        let data_sender = data_sender.clone();
-                        move |e| {
-                            data_sender
-                                .send(fmt_e!("Error while recording audio: {e}"))
-                                .ok();
-                        }
-                    },
-                    None,
-                )
-                .map_err(err!())?;
+        #[cfg(windows)]
+        if mute && device.is_output {
+            set_mute_windows_device(&device, true).ok();
+        }
 
-            stream.play().map_err(err!())?;
+        let mut stream_failed = false;
+        let mut last_check_instant = Instant::now();
+        loop {
+            if let Ok(e) = error_receiver.recv_timeout(STREAM_SUPERVISION_INTERVAL) {
+                if device.failover.is_none() {
+                    data_sender
+                        .send(fmt_e!("Error while recording audio: {e}"))
+                        .ok();
+                    break;
+                }
 
-            shutdown_receiver.recv().ok();
+                warn!("Error while recording audio: {e}");
+                stream_failed = true;
+            }
 
-            #[cfg(windows)]
-            if mute && device.is_output {
-                set_mute_windows_device(&device, false).ok();
+            if !matches!(
+                shutdown_receiver.try_recv(),
+                Err(smpsc::TryRecvError::Empty)
+            ) {
+                break;
             }
 
-            Ok(vec![])
+            let Some(selector) = &device.failover else {
+                continue;
+            };
+            if last_check_instant.elapsed() < DEVICE_POLL_INTERVAL {
+                continue;
+            }
+            last_check_instant = Instant::now();
+
+            let Some((new_device, fallback)) =
+                selector.switch_target(&current_device, stream_failed)
+            else {
+                continue;
+            };
+
+            match open_input_stream(
+                &new_device,
+                channels_count,
+                Some(sample_rate),
+                data_sender.clone(),
+                error_sender.clone(),
+            ) {
+                Ok((stream, _)) => {
+                    _stream = stream;
+
+                    let name = new_device.name().unwrap_or_default();
+                    info!("Capture moved to the audio device \"{name}\"");
+                    if let Some(sender) = &events_sender {
+                        sender
+                            .send(AudioEvent::DeviceSwitched {
+                                name,
+                                direction: DeviceDirection::Input,
+                                fallback,
+                            })
+                            .ok();
+                    }
+
+                    current_device = new_device;
+                    stream_failed = false;
+                }
+                // Retried on the next check
+                Err(e) => warn!("Cannot open the audio device: {e}"),
+            }
         }
-    };
 
-    // use a std thread to store the stream object. The stream object must be destroyed on the same
-    // thread of creation.
-    thread::spawn(move || {
-        let res = thread_callback();
-        if res.is_err() {
-            data_sender.send(res).ok();
+        #[cfg(windows)]
+        if mute && device.is_output {
+            set_mute_windows_device(&device, false).ok();
         }
     });
 
+    ready_receiver.recv().map_err(err!())?
+}
+
+// Start the capture on any backend. Returns the sample rate. The captured samples are sent through
+// data_sender until the shutdown notifier is dropped.
+fn start_capture(
+    device: AudioDevice,
+    channels_count: u16,
+    mute: bool,
+    data_sender: tmpsc::UnboundedSender<StrResult<Vec<f32>>>,
+    events_sender: Option<tmpsc::UnboundedSender<AudioEvent>>,
+    shutdown_receiver: smpsc::Receiver<()>,
+) -> StrResult<u32> {
+    let sample_rate = match &device.inner {
+        DeviceBackend::Cpal(_) => start_cpal_capture(
+            device,
+            channels_count,
+            mute,
+            data_sender,
+            events_sender,
+            shutdown_receiver,
+        )?,
+        DeviceBackend::WavInput {
+            path,
+            looping,
+            sample_rate,
+        } => {
+            headless::spawn_wav_capture(
+                path.clone(),
+                *looping,
+                channels_count,
+                data_sender,
+                shutdown_receiver,
+            )?;
+
+            *sample_rate
+        }
+        DeviceBackend::Null { sample_rate } => {
+            headless::spawn_null_capture(
+                *sample_rate,
+                channels_count,
+                data_sender,
+                shutdown_receiver,
+            );
+
+            *sample_rate
+        }
+        DeviceBackend::WavOutput(_) => return fmt_e!("A WAV output cannot be used for capture"),
+    };
+
+    Ok(sample_rate)
+}
+
+pub async fn record_audio_loop(
+    device: AudioDevice,
+    channels_count: u16,
+    mute: bool,
+    processing: CaptureProcessingDesc,
+    mut transmit_controller: TransmitController,
+    events_sender: Option<tmpsc::UnboundedSender<AudioEvent>>,
+    mut sender: StreamSender<AudioPacketHeader>,
+) -> StrResult {
+    // data_sender/receiver is the bridge between tokio and std thread
+    let (data_sender, mut data_receiver) = tmpsc::unbounded_channel::<StrResult<Vec<f32>>>();
+    let (_shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
+
+    let sample_rate = start_capture(
+        device,
+        channels_count,
+        mute,
+        data_sender,
+        events_sender.clone(),
+        shutdown_receiver,
+    )?;
+
+    let frame_size = (sample_rate as u64 * CAPTURE_FRAME_MS / 1000) as usize;
+    let frame_samples_count = frame_size * channels_count as usize;
+
+    let mut processor_chain =
+        ProcessorChain::capture(&processing, sample_rate, channels_count as _, frame_size);
+    let mut voice_activity_detector = processing.voice_activity_detection.as_ref().map(|config| {
+        VoiceActivityDetector::new(config, sample_rate, channels_count as _, CAPTURE_FRAME_MS)
+    });
+    let (suppress_silent_frames, comfort_noise_config) = processing
+        .voice_activity_detection
+        .as_ref()
+        .map(|config| {
+            (
+                config.suppress_silent_frames,
+                config.comfort_noise.as_option().cloned(),
+            )
+        })
+        .unwrap_or((false, None));
+    let mut comfort_noise_estimator =
+        comfort_noise_config
+            .filter(|_| suppress_silent_frames)
+            .map(|config| {
+                ComfortNoiseEstimator::new(
+                    sample_rate,
+                    channels_count as _,
+                    CAPTURE_FRAME_MS,
+                    config.descriptor_interval_ms,
+                )
+            });
+    let mut was_speaking = false;
+    let mut was_transmitting = false;
+    let mut level_meter = LevelMeter::new(sample_rate, channels_count as _);
+    let mut soundboard = SoundboardPlayer::new(
+        processing.soundboard.clone(),
+        sample_rate,
+        channels_count as _,
+    );
+    let _voice_activity_guard = processing
+        .local_voice_activity
+        .clone()
+        .map(VoiceActivityGuard);
+
+    // The audio callback delivers buffers of arbitrary size, the processing is done on fixed size
+    // frames instead.
+    let mut pending_samples = vec![];
+    let mut sidetone_samples = vec![];
+
     // todo: reuse buffers also in the audio callback
     while let Some(maybe_data) = data_receiver.recv().await {
-        sender.send(&(), maybe_data?).await.ok();
+        pending_samples.extend(maybe_data?);
+
+        while pending_samples.len() >= frame_samples_count {
+            let mut frame = pending_samples
+                .drain(0..frame_samples_count)
+                .collect::<Vec<_>>();
+
+            processor_chain.process(&mut frame);
+
+            if let Some(sidetone) = &processing.sidetone {
+                if matches!(sidetone.source(), SidetoneSource::ProcessedMicrophone) {
+                    sidetone.push(&frame, channels_count as _, sample_rate);
+                }
+            }
+
+            if let (Some(sender), Some(level)) = (&events_sender, level_meter.process(&frame)) {
+                sender.send(AudioEvent::InputLevel { level }).ok();
+            }
+
+            let maybe_voice_activity = voice_activity_detector.as_mut().map(|detector| {
+                let speaking = detector.process(&frame);
+                if speaking != was_speaking {
+                    if let Some(sender) = &events_sender {
+                        sender.send(AudioEvent::VoiceActivity { speaking }).ok();
+                    }
+                    was_speaking = speaking;
+                }
+
+                speaking
+            });
+            let voice_activity = maybe_voice_activity.unwrap_or(true);
+
+            if let (Some(estimator), false) = (&mut comfort_noise_estimator, voice_activity) {
+                estimator.process(&frame);
+            }
+
+            let transmitting = transmit_controller.process(maybe_voice_activity);
+            if let Some(signal) = &processing.local_voice_activity {
+                signal.set(transmitting && maybe_voice_activity.unwrap_or(false));
+            }
+            if transmitting != was_transmitting {
+                if let Some(sender) = &events_sender {
+                    sender
+                        .send(AudioEvent::Transmitting {
+                            active: transmitting,
+                        })
+                        .ok();
+                }
+                was_transmitting = transmitting;
+            }
+
+            let playing_clips = soundboard
+                .as_mut()
+                .map(|soundboard| soundboard.process(&mut frame, transmitting))
+                .unwrap_or(false);
+
+            if !(transmitting && (voice_activity || !suppress_silent_frames)) && !playing_clips {
+                // Discontinuous transmission: describe the background noise from time to time
+                if let Some(estimator) = &mut comfort_noise_estimator {
+                    if !transmitting {
+                        estimator.end_silence();
+                    } else if let Some(descriptor) = estimator.poll_descriptor() {
+                        let header = AudioPacketHeader {
+                            voice_activity: false,
+                            comfort_noise: Some(descriptor),
+                        };
+                        sender.send(&header, vec![]).await.ok();
+                    }
+                }
+
+                continue;
+            }
+            if let Some(estimator) = &mut comfort_noise_estimator {
+                estimator.end_silence();
+            }
+
+            let payload = frame
+                .iter()
+                .flat_map(|s| s.to_sample::<i16>().to_ne_bytes())
+                .collect::<Vec<_>>();
+
+            if let Some(sidetone) = &processing.sidetone {
+                if matches!(sidetone.source(), SidetoneSource::OutgoingStream) {
+                    sidetone_samples.clear();
+                    decode_pcm_packet(&payload, &mut sidetone_samples)?;
+                    sidetone.push(&sidetone_samples, channels_count as _, sample_rate);
+                }
+            }
+            let header = AudioPacketHeader {
+                voice_activity,
+                comfort_noise: None,
+            };
+            sender.send(&header, payload).await.ok();
+        }
     }
 
     Ok(())
 }
 
-// Audio callback. This is designed to be as less complex as possible. Still, when needed, this
-// callback can render a fade-out autonomously.
-#[inline]
-pub fn get_next_frame_batch(
-    sample_buffer: &mut VecDeque<f32>,
-    channels_count: usize,
-    batch_frames_count: usize,
-) -> Vec<f32> {
-    if sample_buffer.len() / channels_count >= batch_frames_count {
-        let mut batch = sample_buffer
-            .drain(0..batch_frames_count * channels_count)
-            .collect::<Vec<_>>();
-
-        if sample_buffer.len() / channels_count < batch_frames_count {
-            // Render fade-out. It is completely contained in the current batch
-            for f in 0..batch_frames_count {
-                let volume = 1. - f as f32 / batch_frames_count as f32;
-                for c in 0..channels_count {
-                    batch[f * channels_count + c] *= volume;
-                }
+// Report the level of the microphone without processing nor transmitting, to test it while not
+// connected. Runs until the future is dropped.
+pub async fn mic_test_loop(
+    device: AudioDevice,
+    channels_count: u16,
+    events_sender: tmpsc::UnboundedSender<AudioEvent>,
+) -> StrResult {
+    let (data_sender, mut data_receiver) = tmpsc::unbounded_channel::<StrResult<Vec<f32>>>();
+    let (_shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
+
+    let sample_rate = start_capture(
+        device,
+        channels_count,
+        false,
+        data_sender,
+        Some(events_sender.clone()),
+        shutdown_receiver,
+    )?;
+
+    let mut level_meter = LevelMeter::new(sample_rate, channels_count as _);
+    while let Some(maybe_data) = data_receiver.recv().await {
+        if let Some(level) = level_meter.process(&maybe_data?) {
+            if events_sender.send(AudioEvent::InputLevel { level }).is_err() {
+                break;
             }
         }
-        // fade-ins and cross-fades are rendered in the receive loop directly inside sample_buffer.
-
-        batch
-    } else {
-        vec![0.; batch_frames_count * channels_count]
     }
+
+    Ok(())
+}
+
+pub(crate) fn decode_pcm_packet(packet: &[u8], samples: &mut Vec<f32>) -> StrResult {
+    samples.extend(
+        packet
+            .chunks_exact(2)
+            .map(|c| i16::from_ne_bytes([c[0], c[1]]).to_sample::<f32>()),
+    );
+
+    Ok(())
 }
 
-// The receive loop is resposible for ensuring smooth transitions in case of disruptions (buffer
-// underflow, overflow, packet loss). In case the computation takes too much time, the audio
-// callback will gracefully handle an interruption, and the callback timing and sound wave
-// continuity will not be affected.
-pub async fn receive_samples_loop(
-    mut receiver: StreamReceiver<()>,
-    sample_buffer: Arc<Mutex<VecDeque<f32>>>,
+// The receive loop decodes the packets and compensates the clock drift. The buffering policy, which
+// ensures smooth transitions in case of disruptions (buffer underflow, overflow, packet loss), is
+// applied by the audio callback on its side of the playback buffer, so the two never wait for each
+// other.
+// The payload is decoded by decode_packet, which appends interleaved samples at the playback
+// sample rate and channels count. Comfort noise descriptors are passed to the playback buffer, which
+// plays the noise instead of silence.
+pub async fn receive_samples_loop<H: DeserializeOwned + ComfortNoiseSource>(
+    mut receiver: StreamReceiver<H>,
+    mut decode_packet: impl FnMut(&H, &[u8], &mut Vec<f32>) -> StrResult,
+    mut processor_chain: ProcessorChain,
+    mut playback_buffer: PlaybackBufferProducer,
     channels_count: usize,
-    batch_frames_count: usize,
     average_buffer_frames_count: usize,
 ) -> StrResult {
     let mut receiver_buffer = ReceiverBuffer::new();
-    let mut recovery_sample_buffer = vec![];
+    let mut drift_estimator = DriftEstimator::new(average_buffer_frames_count);
+    let mut resampler = FractionalResampler::new(channels_count);
+    let mut packet_samples = vec![];
+    let mut new_samples = vec![];
     loop {
         receiver.recv_buffer(&mut receiver_buffer).await?;
-        let (_, packet) = receiver_buffer.get()?;
+        let (header, packet) = receiver_buffer.get()?;
 
-        let new_samples = packet
-            .chunks_exact(2)
-            .map(|c| i16::from_ne_bytes([c[0], c[1]]).to_sample::<f32>())
-            .collect::<Vec<_>>();
+        if let Some(descriptor) = header.comfort_noise() {
+            playback_buffer.set_comfort_noise(*descriptor);
+            continue;
+        }
+
+        packet_samples.clear();
+        decode_packet(&header, packet, &mut packet_samples)?;
 
-        let mut sample_buffer_ref = sample_buffer.lock();
+        processor_chain.process(&mut packet_samples);
 
         if receiver_buffer.had_packet_loss() {
             info!("Audio packet loss!");
 
-            if sample_buffer_ref.len() / channels_count < batch_frames_count {
-                sample_buffer_ref.clear();
-            } else {
-                // clear remaining samples
-                sample_buffer_ref.drain(batch_frames_count * channels_count..);
-            }
-
-            recovery_sample_buffer.clear();
+            playback_buffer.flush();
+            resampler.reset();
         }
 
-        if sample_buffer_ref.len() / channels_count < batch_frames_count {
-            recovery_sample_buffer.extend(sample_buffer_ref.drain(..));
-        }
+        // Compensate the clock drift between the remote capture device and the local playback
+        // device. Without this, the buffer would slowly overflow or underflow on long sessions.
+        new_samples.clear();
+        resampler.process(&packet_samples, drift_estimator.ratio(), &mut new_samples);
 
-        if sample_buffer_ref.len() == 0 || receiver_buffer.had_packet_loss() {
-            recovery_sample_buffer.extend(&new_samples);
-
-            if recovery_sample_buffer.len() / channels_count
-                > average_buffer_frames_count + batch_frames_count
-            {
-                // Fade-in
-                for f in 0..batch_frames_count {
-                    let volume = f as f32 / batch_frames_count as f32;
-                    for c in 0..channels_count {
-                        recovery_sample_buffer[f * channels_count + c] *= volume;
-                    }
-                }
-
-                if receiver_buffer.had_packet_loss()
-                    && sample_buffer_ref.len() / channels_count == batch_frames_count
-                {
-                    // Add a fade-out to make a cross-fade.
-                    for f in 0..batch_frames_count {
-                        let volume = 1. - f as f32 / batch_frames_count as f32;
-                        for c in 0..channels_count {
-                            recovery_sample_buffer[f * channels_count + c] +=
-                                sample_buffer_ref[f * channels_count + c] * volume;
-                        }
-                    }
-
-                    sample_buffer_ref.clear();
-                }
-
-                sample_buffer_ref.extend(recovery_sample_buffer.drain(..));
-                info!("Audio recovered");
-            }
-        } else {
-            sample_buffer_ref.extend(&new_samples);
+        let dropped_count = playback_buffer.push(&new_samples);
+        if dropped_count > 0 {
+            info!("Audio buffer full! {dropped_count} samples dropped");
         }
 
-        // todo: use smarter policy with EventTiming
-        let buffer_frames_size = sample_buffer_ref.len() / channels_count;
-        if buffer_frames_size > 2 * average_buffer_frames_count + batch_frames_count {
-            info!("Audio buffer overflow! size: {buffer_frames_size}");
-
-            let drained_samples = sample_buffer_ref
-                .drain(0..(buffer_frames_size - average_buffer_frames_count) * channels_count)
-                .collect::<Vec<_>>();
-
-            // Render a cross-fade.
-            for f in 0..batch_frames_count {
-                let volume = f as f32 / batch_frames_count as f32;
-                for c in 0..channels_count {
-                    let index = f * channels_count + c;
-                    sample_buffer_ref[index] =
-                        sample_buffer_ref[index] * volume + drained_samples[index] * (1. - volume);
-                }
-            }
+        if playback_buffer.take_recovered() {
+            drift_estimator.reset_level();
+            info!("Audio recovered");
+        }
+        if playback_buffer.take_overflowed() {
+            info!(
+                "Audio buffer overflow! estimated drift: {:.1} ppm",
+                drift_estimator.drift_ppm()
+            );
+            drift_estimator.reset_level();
         }
+
+        drift_estimator.submit_buffer_level(playback_buffer.frames_count());
     }
 }
 
 struct StreamingSource {
-    sample_buffer: Arc<Mutex<VecDeque<f32>>>,
+    mixer: Mixer,
+    // Always set, given back to the mixer when dropped
+    renderer: Option<MixerRenderer>,
+    echo_reference: Option<EchoReferenceWriter>,
     current_batch: Vec<f32>,
     current_batch_cursor: usize,
-    channels_count: usize,
-    sample_rate: u32,
-    batch_frames_count: usize,
+}
+
+impl StreamingSource {
+    fn new(mixer: Mixer, echo_reference: Option<&EchoReference>) -> StrResult<Self> {
+        let renderer = mixer.take_renderer()?;
+        let echo_reference = echo_reference.and_then(|reference| {
+            let writer = reference.writer();
+            if writer.is_none() {
+                warn!("The echo reference is already published by another playback");
+            }
+            writer
+        });
+
+        Ok(Self {
+            current_batch: vec![0.; renderer.batch_samples_count()],
+            mixer,
+            renderer: Some(renderer),
+            echo_reference,
+            current_batch_cursor: 0,
+        })
+    }
+}
+
+impl Drop for StreamingSource {
+    fn drop(&mut self) {
+        if let Some(renderer) = self.renderer.take() {
+            self.mixer.return_renderer(renderer);
+        }
+    }
 }
 
 impl Source for StreamingSource {
@@ -551,11 +1075,11 @@ impl Source for StreamingSource {
     }
 
     fn channels(&self) -> u16 {
-        self.channels_count as _
+        self.mixer.channels_count() as _
     }
 
     fn sample_rate(&self) -> u32 {
-        self.sample_rate
+        self.mixer.sample_rate()
     }
 
     fn total_duration(&self) -> Option<std::time::Duration> {
@@ -568,67 +1092,154 @@ impl Iterator for StreamingSource {
 
     #[inline]
     fn next(&mut self) -> Option<f32> {
+        let renderer = self.renderer.as_mut()?;
+
         if self.current_batch_cursor == 0 {
-            self.current_batch = get_next_frame_batch(
-                &mut self.sample_buffer.lock(),
-                self.channels_count,
-                self.batch_frames_count,
-            );
+            renderer.mix_batch(&mut self.current_batch);
+
+            if let Some(reference) = &mut self.echo_reference {
+                reference.push(
+                    &self.current_batch,
+                    self.mixer.channels_count(),
+                    self.mixer.sample_rate(),
+                );
+            }
+
+            renderer.mix_sidetone(&mut self.current_batch);
         }
 
         let sample = self.current_batch[self.current_batch_cursor];
 
-        self.current_batch_cursor =
-            (self.current_batch_cursor + 1) % (self.batch_frames_count * self.channels_count);
+        self.current_batch_cursor = (self.current_batch_cursor + 1) % self.current_batch.len();
 
         Some(sample)
     }
 }
 
+// Keeps the output stream alive
+pub struct PlaybackGuard {
+    _shutdown_notifier: smpsc::Sender<()>,
+}
+
+// Play the output of the mixer until the returned guard is dropped. If the device supports
+// failover, the output is moved to another device when its device is unplugged.
+pub fn start_playback(
+    device: AudioDevice,
+    mixer: Mixer,
+    echo_reference: Option<EchoReference>,
+    events_sender: Option<tmpsc::UnboundedSender<AudioEvent>>,
+) -> StrResult<PlaybackGuard> {
+    let (shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
+
+    let sample_rate = mixer.sample_rate();
+    let channels_count = mixer.channels_count() as u16;
+    let batch_frames_count = mixer.batch_frames_count();
+    let make_source = move || StreamingSource::new(mixer.clone(), echo_reference.as_ref());
+    let source = make_source()?;
+
+    match device.inner {
+        DeviceBackend::Cpal(mut current_device) => {
+            let (ready_sender, ready_receiver) = smpsc::channel();
+
+            // Store the stream in a thread (because !Send)
+            thread::spawn(move || {
+                let open_stream = |device: &Device, source| {
+                    let (stream, handle) = OutputStream::try_from_device(device).map_err(err!())?;
+                    handle.play_raw(source).map_err(err!())?;
+
+                    StrResult::Ok(stream)
+                };
+
+                let mut _stream = match open_stream(&current_device, source) {
+                    Ok(stream) => {
+                        ready_sender.send(Ok(())).ok();
+                        Some(stream)
+                    }
+                    Err(e) => {
+                        ready_sender.send(Err(e)).ok();
+                        return;
+                    }
+                };
+
+                while let Err(smpsc::RecvTimeoutError::Timeout) =
+                    shutdown_receiver.recv_timeout(DEVICE_POLL_INTERVAL)
+                {
+                    let Some((new_device, fallback)) = device
+                        .failover
+                        .as_ref()
+                        .and_then(|selector| selector.switch_target(&current_device, false))
+                    else {
+                        continue;
+                    };
+
+                    // The mixer is played by one stream at a time. If the new device cannot be
+                    // opened, nothing plays until the next attempt.
+                    _stream = None;
+                    match make_source().and_then(|source| open_stream(&new_device, source)) {
+                        Ok(new_stream) => {
+                            _stream = Some(new_stream);
+
+                            let name = new_device.name().unwrap_or_default();
+                            info!("Playback moved to the audio device \"{name}\"");
+                            if let Some(sender) = &events_sender {
+                                sender
+                                    .send(AudioEvent::DeviceSwitched {
+                                        name,
+                                        direction: DeviceDirection::Output,
+                                        fallback,
+                                    })
+                                    .ok();
+                            }
+
+                            current_device = new_device;
+                        }
+                        // Retried on the next check
+                        Err(e) => warn!("Cannot open the audio device: {e}"),
+                    }
+                }
+            });
+
+            ready_receiver.recv().map_err(err!())??;
+        }
+        DeviceBackend::WavOutput(path) => headless::spawn_clocked_playback(
+            source,
+            sample_rate,
+            channels_count,
+            batch_frames_count,
+            Some(path),
+            shutdown_receiver,
+        )?,
+        DeviceBackend::Null { .. } => headless::spawn_clocked_playback(
+            source,
+            sample_rate,
+            channels_count,
+            batch_frames_count,
+            None,
+            shutdown_receiver,
+        )?,
+        DeviceBackend::WavInput { .. } => return fmt_e!("A WAV input cannot be used for playback"),
+    }
+
+    Ok(PlaybackGuard {
+        _shutdown_notifier: shutdown_notifier,
+    })
+}
+
+// Play a single remote stream
+#[allow(clippy::too_many_arguments)]
 pub async fn play_audio_loop(
     device: AudioDevice,
     channels_count: u16,
     sample_rate: u32,
     config: AudioBufferingConfig,
-    receiver: StreamReceiver<()>,
+    echo_reference: Option<EchoReference>,
+    processing: PlaybackProcessingDesc,
+    events_sender: Option<tmpsc::UnboundedSender<AudioEvent>>,
+    receiver: StreamReceiver<AudioPacketHeader>,
 ) -> StrResult {
-    // Size of a chunk of frames. It corresponds to the duration if a fade-in/out in frames.
-    let batch_frames_count = sample_rate as usize * config.batch_ms as usize / 1000;
+    let mixer = Mixer::new(channels_count as _, sample_rate, &config, processing);
 
-    // Average buffer size in frames
-    let average_buffer_frames_count =
-        sample_rate as usize * config.average_buffering_ms as usize / 1000;
+    let _playback_guard = start_playback(device, mixer.clone(), echo_reference, events_sender)?;
 
-    let sample_buffer = Arc::new(Mutex::new(VecDeque::new()));
-
-    // Store the stream in a thread (because !Send)
-    let (_shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
-    thread::spawn({
-        let sample_buffer = Arc::clone(&sample_buffer);
-        move || -> StrResult {
-            let (_stream, handle) = OutputStream::try_from_device(&device.inner).map_err(err!())?;
-
-            let source = StreamingSource {
-                sample_buffer,
-                current_batch: vec![],
-                current_batch_cursor: 0,
-                channels_count: channels_count as _,
-                sample_rate,
-                batch_frames_count,
-            };
-            handle.play_raw(source).map_err(err!())?;
-
-            shutdown_receiver.recv().ok();
-            Ok(())
-        }
-    });
-
-    receive_samples_loop(
-        receiver,
-        sample_buffer,
-        channels_count as _,
-        batch_frames_count,
-        average_buffer_frames_count,
-    )
-    .await
+    mixer.speaker_loop(0, receiver).await
 }
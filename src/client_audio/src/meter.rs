@@ -0,0 +1,115 @@
+// Level metering for VU meters and "who's talking" indicators. Levels are accumulated over a
+// reporting period, so that the events are throttled independently of the frame size.
+
+use crate::{AudioEvent, AudioTap, TapTrack};
+use serde::Serialize;
+use std::collections::HashMap;
+use tokio::sync::{broadcast, mpsc as tmpsc};
+
+pub const LEVEL_REPORT_INTERVAL_MS: u64 = 100;
+
+// Floor used for digital silence
+const MIN_LEVEL_DB: f32 = -100.;
+
+#[derive(Serialize, Clone, Copy, PartialEq, Debug)]
+pub struct AudioLevel {
+    pub peak_db: f32,
+    pub rms_db: f32,
+}
+
+pub struct LevelMeter {
+    period_samples_count: usize,
+    peak: f32,
+    sum_squares: f64,
+    samples_count: usize,
+}
+
+impl LevelMeter {
+    pub fn new(sample_rate: u32, channels_count: usize) -> Self {
+        Self {
+            period_samples_count: (sample_rate as u64 * LEVEL_REPORT_INTERVAL_MS / 1000) as usize
+                * channels_count,
+            peak: 0.,
+            sum_squares: 0.,
+            samples_count: 0,
+        }
+    }
+
+    // Returns the levels of the period when it is complete
+    pub fn process(&mut self, samples: &[f32]) -> Option<AudioLevel> {
+        for &sample in samples {
+            self.peak = self.peak.max(sample.abs());
+            self.sum_squares += (sample * sample) as f64;
+        }
+        self.samples_count += samples.len();
+
+        if self.samples_count < self.period_samples_count {
+            return None;
+        }
+
+        let rms = (self.sum_squares / self.samples_count as f64).sqrt() as f32;
+        let level = AudioLevel {
+            peak_db: (20. * self.peak.log10()).max(MIN_LEVEL_DB),
+            rms_db: (20. * rms.log10()).max(MIN_LEVEL_DB),
+        };
+
+        self.peak = 0.;
+        self.sum_squares = 0.;
+        self.samples_count = 0;
+
+        Some(level)
+    }
+}
+
+// Report the level of each remote speaker, as published by the mixer, until the tap is closed
+pub async fn speaker_levels_loop(tap: AudioTap, events_sender: tmpsc::UnboundedSender<AudioEvent>) {
+    let mut receiver = tap.subscribe();
+    let mut meters = HashMap::new();
+
+    loop {
+        let frame = match receiver.recv().await {
+            Ok(frame) => frame,
+            Err(broadcast::error::RecvError::Lagged(_)) => continue,
+            Err(broadcast::error::RecvError::Closed) => return,
+        };
+        let TapTrack::Speaker(speaker) = frame.track else {
+            continue;
+        };
+
+        let meter = meters
+            .entry(speaker)
+            .or_insert_with(|| LevelMeter::new(frame.sample_rate, frame.channels_count as _));
+        if let Some(level) = meter.process(&frame.samples) {
+            if events_sender
+                .send(AudioEvent::SpeakerLevel { speaker, level })
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_level_meter() {
+        let mut meter = LevelMeter::new(1000, 1);
+
+        // Full scale square wave
+        let frame = [1., -1., 1., -1.];
+        for _ in 0..24 {
+            assert!(meter.process(&frame).is_none());
+        }
+        let level = meter.process(&frame).unwrap();
+        assert!(level.peak_db.abs() < 1e-6);
+        assert!(level.rms_db.abs() < 1e-6);
+
+        // Silence
+        let level = meter.process(&[0.; 100]).unwrap();
+        assert_eq!(level.peak_db, MIN_LEVEL_DB);
+        assert_eq!(level.rms_db, MIN_LEVEL_DB);
+    }
+}
@@ -0,0 +1,673 @@
+// Playback mixer. Each remote speaker has its own jitter buffer, filled by its own receive loop,
+// and the audio callback pulls one batch from every buffer, applies the per-speaker controls and
+// sums everything into a single output stream. The state used by the callback is owned by the
+// `MixerRenderer`: the speakers are handed over through a command queue and the controls are read
+// from atomics, so that the callback never waits for the receive loops or the local API.
+
+use crate::{
+    decode_pcm_packet, dsp, item_ring, playback_buffer, receive_samples_loop,
+    system_audio_speaker_id, timestamp_us, AudioEvent, AudioTap, Ducker, EchoTestStats,
+    ItemConsumer, ItemProducer, PlaybackBufferConsumer, PlaybackBufferProducer,
+    PlaybackProcessingDesc, ProcessorChain, SidetoneReader, SpatialScene, SystemAudioDecoder,
+    TapPublisherGuard, TapTrack, TapWriter, VoiceActivitySignal, ECHO_TEST_SPEAKER_ID,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    f32::consts::{FRAC_PI_4, SQRT_2},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc as tmpsc;
+use vors_share_common::{parking_lot::Mutex, prelude::*};
+use vors_share_packets::{AudioPacketHeader, EchoTestPacketHeader, SystemAudioPacketHeader};
+use vors_share_session::AudioBufferingConfig;
+use vors_share_sockets::StreamReceiver;
+
+// The audio callback cannot allocate, the room for the speakers is reserved upfront
+const MAX_SPEAKERS_COUNT: usize = 64;
+const MIXER_COMMANDS_CAPACITY: usize = 4 * MAX_SPEAKERS_COUNT;
+
+pub type SpeakerId = u64;
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct SpeakerControls {
+    // Linear gain
+    pub volume: f32,
+    pub muted: bool,
+    // -1 is full left, 1 is full right. Ignored for mono outputs.
+    pub pan: f32,
+}
+
+impl Default for SpeakerControls {
+    fn default() -> Self {
+        Self {
+            volume: 1.,
+            muted: false,
+            pan: 0.,
+        }
+    }
+}
+
+impl SpeakerControls {
+    fn channel_gains(&self, channels_count: usize, gains: &mut Vec<f32>) {
+        gains.clear();
+
+        let volume = if self.muted { 0. } else { self.volume.max(0.) };
+        if channels_count == 2 {
+            // Constant power pan law, normalized to unity gain at the center
+            let angle = (self.pan.clamp(-1., 1.) + 1.) * FRAC_PI_4;
+            gains.push(volume * angle.cos() * SQRT_2);
+            gains.push(volume * angle.sin() * SQRT_2);
+        } else {
+            gains.resize(channels_count, volume);
+        }
+    }
+}
+
+// Written by the local API, read by the audio callback
+struct SharedControls {
+    // Bits of the f32 values
+    volume: AtomicU32,
+    muted: AtomicBool,
+    pan: AtomicU32,
+}
+
+impl SharedControls {
+    fn new(controls: SpeakerControls) -> Self {
+        Self {
+            volume: AtomicU32::new(controls.volume.to_bits()),
+            muted: AtomicBool::new(controls.muted),
+            pan: AtomicU32::new(controls.pan.to_bits()),
+        }
+    }
+
+    fn load(&self) -> SpeakerControls {
+        SpeakerControls {
+            volume: f32::from_bits(self.volume.load(Ordering::Relaxed)),
+            muted: self.muted.load(Ordering::Relaxed),
+            pan: f32::from_bits(self.pan.load(Ordering::Relaxed)),
+        }
+    }
+
+    fn store(&self, controls: SpeakerControls) {
+        self.volume
+            .store(controls.volume.to_bits(), Ordering::Relaxed);
+        self.muted.store(controls.muted, Ordering::Relaxed);
+        self.pan.store(controls.pan.to_bits(), Ordering::Relaxed);
+    }
+}
+
+struct Speaker {
+    id: SpeakerId,
+    playback_buffer: PlaybackBufferConsumer,
+    controls: Arc<SharedControls>,
+    // Gains applied at the end of the last batch, used to ramp the control changes
+    last_gains: Vec<f32>,
+    // Set from the packets of voice streams. Background streams have none and are ducked.
+    voice_activity: Option<VoiceActivitySignal>,
+}
+
+// Not boxed, the callback would free the box
+#[allow(clippy::large_enum_variant)]
+enum MixerCommand {
+    // Replaces the speaker with the same id, if any
+    AddSpeaker(Speaker),
+    RemoveSpeaker(SpeakerId),
+}
+
+struct MixerControl {
+    speakers: HashSet<SpeakerId>,
+    // Kept also for disconnected speakers, so that the controls can be set in advance and survive
+    // reconnections
+    controls: HashMap<SpeakerId, Arc<SharedControls>>,
+    commands: ItemProducer<MixerCommand>,
+    // The speakers removed by the callback are dropped here, since dropping frees memory
+    removed_speakers: ItemConsumer<Speaker>,
+}
+
+impl MixerControl {
+    fn send(&mut self, command: MixerCommand) -> StrResult {
+        while self.removed_speakers.pop().is_some() {}
+
+        self.commands
+            .push(command)
+            .map_err(|_| "The mixer command queue is full".into())
+    }
+}
+
+// State of the audio callback, taken by the playback with Mixer::take_renderer()
+pub struct MixerRenderer {
+    commands: ItemConsumer<MixerCommand>,
+    // Sized like the command queue, and emptied before each command is sent
+    removed_speakers: ItemProducer<Speaker>,
+    speakers: Vec<Speaker>,
+    channels_count: usize,
+    sample_rate: u32,
+    batch_frames_count: usize,
+    // Frames mixed since the creation
+    position_frames: u64,
+    // Reused by every batch, the audio callback must not allocate
+    speaker_batch: Vec<f32>,
+    gains: Vec<f32>,
+    ducker: Option<Ducker>,
+    local_voice_activity: Option<VoiceActivitySignal>,
+    tap: TapWriter,
+    sidetone: Option<SidetoneReader>,
+}
+
+impl MixerRenderer {
+    // Size of the batches, interleaved
+    pub fn batch_samples_count(&self) -> usize {
+        self.batch_frames_count * self.channels_count
+    }
+
+    fn apply_commands(&mut self) {
+        while let Some(command) = self.commands.pop() {
+            let id = match &command {
+                MixerCommand::AddSpeaker(speaker) => speaker.id,
+                MixerCommand::RemoveSpeaker(id) => *id,
+            };
+
+            if let Some(index) = self.speakers.iter().position(|speaker| speaker.id == id) {
+                let speaker = self.speakers.swap_remove(index);
+                self.removed_speakers.push(speaker).ok();
+            }
+
+            if let MixerCommand::AddSpeaker(speaker) = command {
+                self.speakers.push(speaker);
+            }
+        }
+    }
+
+    // Called by the audio callback after the mixed batch is published as echo reference
+    pub fn mix_sidetone(&mut self, batch: &mut [f32]) {
+        if let Some(sidetone) = &mut self.sidetone {
+            sidetone.mix_into(batch, self.channels_count);
+        }
+    }
+
+    // Called by the audio callback, with a batch of batch_samples_count() samples
+    pub fn mix_batch(&mut self, batch: &mut [f32]) {
+        self.apply_commands();
+
+        let channels_count = self.channels_count;
+        let Self {
+            speakers,
+            position_frames,
+            speaker_batch,
+            gains,
+            ducker,
+            tap,
+            ..
+        } = self;
+
+        batch.fill(0.);
+
+        // Voices count only while they are actually played
+        let voice_active = self
+            .local_voice_activity
+            .as_ref()
+            .map(|signal| signal.get())
+            .unwrap_or(false)
+            || speakers.iter().any(|speaker| {
+                speaker.playback_buffer.is_playing()
+                    && speaker
+                        .voice_activity
+                        .as_ref()
+                        .map(|signal| signal.get())
+                        .unwrap_or(false)
+            });
+        let ducking_gain = ducker
+            .as_mut()
+            .map(|ducker| ducker.process(voice_active, self.batch_frames_count))
+            .unwrap_or(1.);
+
+        for speaker in speakers {
+            speaker.playback_buffer.fill_batch(speaker_batch);
+            tap.write(
+                TapTrack::Speaker(speaker.id),
+                *position_frames,
+                self.sample_rate,
+                channels_count as _,
+                speaker_batch,
+            );
+
+            speaker.controls.load().channel_gains(channels_count, gains);
+            if speaker.voice_activity.is_none() {
+                for gain in gains.iter_mut() {
+                    *gain *= ducking_gain;
+                }
+            }
+            if speaker.last_gains.is_empty() {
+                speaker.last_gains.clone_from(gains);
+            }
+
+            for (frame_index, (output, input)) in batch
+                .chunks_exact_mut(channels_count)
+                .zip(speaker_batch.chunks_exact(channels_count))
+                .enumerate()
+            {
+                let ratio = (frame_index + 1) as f32 / self.batch_frames_count as f32;
+                for (((output, input), gain), last_gain) in output
+                    .iter_mut()
+                    .zip(input)
+                    .zip(gains.iter())
+                    .zip(&speaker.last_gains)
+                {
+                    *output += input * (last_gain + (gain - last_gain) * ratio);
+                }
+            }
+
+            speaker.last_gains.clone_from(gains);
+        }
+
+        for sample in batch.iter_mut() {
+            *sample = dsp::soft_clip(*sample);
+        }
+
+        tap.write(
+            TapTrack::Mix,
+            *position_frames,
+            self.sample_rate,
+            channels_count as _,
+            batch,
+        );
+
+        *position_frames += self.batch_frames_count as u64;
+    }
+}
+
+// Cheap to clone
+#[derive(Clone)]
+pub struct Mixer {
+    control: Arc<Mutex<MixerControl>>,
+    // None while playing
+    renderer: Arc<Mutex<Option<MixerRenderer>>>,
+    _tap_publisher: Arc<TapPublisherGuard>,
+    channels_count: usize,
+    sample_rate: u32,
+    batch_frames_count: usize,
+    average_buffer_frames_count: usize,
+    processing: PlaybackProcessingDesc,
+    scene: SpatialScene,
+}
+
+impl Mixer {
+    pub fn new(
+        channels_count: usize,
+        sample_rate: u32,
+        config: &AudioBufferingConfig,
+        processing: PlaybackProcessingDesc,
+    ) -> Self {
+        // Size of a chunk of frames. It corresponds to the duration if a fade-in/out in frames.
+        let batch_frames_count = sample_rate as usize * config.batch_ms as usize / 1000;
+        let batch_samples_count = batch_frames_count * channels_count;
+
+        let (commands_producer, commands_consumer) = item_ring(MIXER_COMMANDS_CAPACITY);
+        let (removed_speakers_producer, removed_speakers_consumer) =
+            item_ring(MIXER_COMMANDS_CAPACITY);
+        let (tap_writer, tap_publisher) = processing
+            .tap
+            .spawn_writer(batch_samples_count, Duration::from_millis(config.batch_ms));
+
+        let renderer = MixerRenderer {
+            commands: commands_consumer,
+            removed_speakers: removed_speakers_producer,
+            speakers: Vec::with_capacity(MAX_SPEAKERS_COUNT),
+            channels_count,
+            sample_rate,
+            batch_frames_count,
+            position_frames: 0,
+            speaker_batch: vec![0.; batch_samples_count],
+            gains: Vec::with_capacity(channels_count),
+            ducker: processing
+                .ducking
+                .as_ref()
+                .map(|config| Ducker::new(config, sample_rate)),
+            local_voice_activity: processing.local_voice_activity.clone(),
+            tap: tap_writer,
+            sidetone: processing
+                .sidetone
+                .as_ref()
+                .and_then(|sidetone| sidetone.reader(sample_rate)),
+        };
+
+        Self {
+            control: Arc::new(Mutex::new(MixerControl {
+                speakers: HashSet::new(),
+                controls: HashMap::new(),
+                commands: commands_producer,
+                removed_speakers: removed_speakers_consumer,
+            })),
+            renderer: Arc::new(Mutex::new(Some(renderer))),
+            _tap_publisher: Arc::new(tap_publisher),
+            channels_count,
+            sample_rate,
+            batch_frames_count,
+            average_buffer_frames_count: sample_rate as usize
+                * config.average_buffering_ms as usize
+                / 1000,
+            processing,
+            scene: SpatialScene::new(),
+        }
+    }
+
+    pub fn channels_count(&self) -> usize {
+        self.channels_count
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn batch_frames_count(&self) -> usize {
+        self.batch_frames_count
+    }
+
+    // Poses used by the spatial audio
+    pub fn scene(&self) -> &SpatialScene {
+        &self.scene
+    }
+
+    // Receives the voice of each speaker, before the controls are applied, and the final mix
+    pub fn tap(&self) -> &AudioTap {
+        &self.processing.tap
+    }
+
+    // The renderer must be given back when the playback stops, to play again on another device
+    pub fn take_renderer(&self) -> StrResult<MixerRenderer> {
+        self.renderer
+            .lock()
+            .take()
+            .ok_or_else(|| "The mixer is already playing".into())
+    }
+
+    pub fn return_renderer(&self, renderer: MixerRenderer) {
+        *self.renderer.lock() = Some(renderer);
+    }
+
+    pub fn speakers(&self) -> Vec<SpeakerId> {
+        self.control.lock().speakers.iter().copied().collect()
+    }
+
+    pub fn controls(&self, id: SpeakerId) -> SpeakerControls {
+        self.control
+            .lock()
+            .controls
+            .get(&id)
+            .map(|controls| controls.load())
+            .unwrap_or_default()
+    }
+
+    pub fn set_controls(&self, id: SpeakerId, controls: SpeakerControls) {
+        let mut control = self.control.lock();
+        if let Some(shared) = control.controls.get(&id) {
+            shared.store(controls);
+        } else {
+            control
+                .controls
+                .insert(id, Arc::new(SharedControls::new(controls)));
+        }
+    }
+
+    // Used by the receive loops, which remove the speaker when the stream is closed
+    pub fn add_speaker(
+        &self,
+        id: SpeakerId,
+        voice_activity: Option<VoiceActivitySignal>,
+    ) -> StrResult<PlaybackBufferProducer> {
+        let mut control = self.control.lock();
+        if control.speakers.contains(&id) {
+            return fmt_e!("Speaker {id} is already playing");
+        }
+        if control.speakers.len() >= MAX_SPEAKERS_COUNT {
+            return fmt_e!("Cannot play more than {MAX_SPEAKERS_COUNT} speakers");
+        }
+
+        let (producer, consumer) = playback_buffer(
+            self.sample_rate,
+            self.channels_count,
+            self.batch_frames_count,
+            self.average_buffer_frames_count,
+        );
+        let controls = Arc::clone(
+            control
+                .controls
+                .entry(id)
+                .or_insert_with(|| Arc::new(SharedControls::new(SpeakerControls::default()))),
+        );
+        control.send(MixerCommand::AddSpeaker(Speaker {
+            id,
+            playback_buffer: consumer,
+            controls,
+            last_gains: Vec::with_capacity(self.channels_count),
+            voice_activity,
+        }))?;
+        control.speakers.insert(id);
+
+        Ok(producer)
+    }
+
+    pub fn remove_speaker(&self, id: SpeakerId) {
+        let mut control = self.control.lock();
+        if control.speakers.remove(&id) {
+            if let Err(e) = control.send(MixerCommand::RemoveSpeaker(id)) {
+                // The buffer of the speaker stays empty, it is replaced if the speaker comes back
+                warn!("Cannot remove the speaker {id}: {e}");
+            }
+        }
+    }
+
+    // Receive the voice of a remote speaker and play it until the stream is closed
+    pub async fn speaker_loop(
+        &self,
+        id: SpeakerId,
+        receiver: StreamReceiver<AudioPacketHeader>,
+    ) -> StrResult {
+        let voice_activity = VoiceActivitySignal::default();
+        let playback_buffer = self.add_speaker(id, Some(voice_activity.clone()))?;
+
+        let res = receive_samples_loop(
+            receiver,
+            |header: &AudioPacketHeader, packet, samples| {
+                voice_activity.set(header.voice_activity);
+                decode_pcm_packet(packet, samples)
+            },
+            ProcessorChain::playback(
+                &self.processing,
+                id,
+                &self.scene,
+                self.sample_rate,
+                self.channels_count,
+            ),
+            playback_buffer,
+            self.channels_count,
+            self.average_buffer_frames_count,
+        )
+        .await;
+
+        self.remove_speaker(id);
+
+        res
+    }
+
+    // Receive the system audio shared by a remote speaker and play it until the stream is closed.
+    // It is mixed as its own speaker, so its controls are set separately from the voice, with the
+    // id returned by system_audio_speaker_id(). No voice processing is applied.
+    pub async fn system_audio_loop(
+        &self,
+        id: SpeakerId,
+        receiver: StreamReceiver<SystemAudioPacketHeader>,
+    ) -> StrResult {
+        let id = system_audio_speaker_id(id);
+        let mut decoder = SystemAudioDecoder::new(self.sample_rate, self.channels_count)?;
+        let playback_buffer = self.add_speaker(id, None)?;
+
+        let res = receive_samples_loop(
+            receiver,
+            |_, packet, samples| decoder.decode(packet, samples),
+            ProcessorChain::new(self.channels_count),
+            playback_buffer,
+            self.channels_count,
+            self.average_buffer_frames_count,
+        )
+        .await;
+
+        self.remove_speaker(id);
+
+        res
+    }
+
+    // Play the game audio streamed by the server until the stream is closed. Like the system audio,
+    // it is ducked while someone talks and no voice processing is applied.
+    pub async fn game_audio_loop(
+        &self,
+        id: SpeakerId,
+        receiver: StreamReceiver<AudioPacketHeader>,
+    ) -> StrResult {
+        let playback_buffer = self.add_speaker(id, None)?;
+
+        let res = receive_samples_loop(
+            receiver,
+            |_, packet, samples| decode_pcm_packet(packet, samples),
+            ProcessorChain::new(self.channels_count),
+            playback_buffer,
+            self.channels_count,
+            self.average_buffer_frames_count,
+        )
+        .await;
+
+        self.remove_speaker(id);
+
+        res
+    }
+
+    // Play the voice returned by the server echo test until the stream is closed, and report the
+    // latency and packet loss with AudioEvent::EchoTest. It is mixed like a talking speaker, so
+    // that the background streams are ducked as during a call. The voice is already processed.
+    pub async fn echo_test_loop(
+        &self,
+        receiver: StreamReceiver<EchoTestPacketHeader>,
+        server_delay: Duration,
+        events_sender: tmpsc::UnboundedSender<AudioEvent>,
+    ) -> StrResult {
+        let voice_activity = VoiceActivitySignal::default();
+        let playback_buffer =
+            self.add_speaker(ECHO_TEST_SPEAKER_ID, Some(voice_activity.clone()))?;
+
+        let playback_buffering = Duration::from_secs_f64(
+            (self.average_buffer_frames_count + self.batch_frames_count) as f64
+                / self.sample_rate as f64,
+        );
+        let mut stats = EchoTestStats::new(server_delay, playback_buffering);
+
+        let res = receive_samples_loop(
+            receiver,
+            |header: &EchoTestPacketHeader, packet, samples| {
+                voice_activity.set(true);
+                if let Some(report) = stats.submit(header, timestamp_us()) {
+                    events_sender.send(AudioEvent::EchoTest { report }).ok();
+                }
+                decode_pcm_packet(packet, samples)
+            },
+            ProcessorChain::new(self.channels_count),
+            playback_buffer,
+            self.channels_count,
+            self.average_buffer_frames_count,
+        )
+        .await;
+
+        self.remove_speaker(ECHO_TEST_SPEAKER_ID);
+
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_mix_with_controls() {
+        let tap = AudioTap::new();
+        let mut tap_receiver = tap.subscribe();
+        let mixer = Mixer::new(
+            2,
+            1000,
+            &AudioBufferingConfig {
+                average_buffering_ms: 50,
+                batch_ms: 10,
+            },
+            PlaybackProcessingDesc {
+                tap,
+                ..Default::default()
+            },
+        );
+        let mut renderer = mixer.take_renderer().unwrap();
+        assert!(mixer.take_renderer().is_err());
+
+        let mut first = mixer.add_speaker(1, None).unwrap();
+        let mut second = mixer.add_speaker(2, None).unwrap();
+        let mut muted = mixer.add_speaker(3, None).unwrap();
+        assert!(mixer.add_speaker(1, None).is_err());
+
+        mixer.set_controls(
+            2,
+            SpeakerControls {
+                volume: 0.5,
+                muted: false,
+                pan: 1.,
+            },
+        );
+        mixer.set_controls(
+            3,
+            SpeakerControls {
+                muted: true,
+                ..Default::default()
+            },
+        );
+
+        // Enough to end the prebuffering and play two batches without fade-out
+        for buffer in [&mut first, &mut second, &mut muted] {
+            buffer.push(&[0.2; 140]);
+        }
+
+        // The first batch is faded in. The tap frames are published by another thread.
+        let mut batch = vec![0.; renderer.batch_samples_count()];
+        renderer.mix_batch(&mut batch);
+        for _ in 0..4 {
+            tap_receiver.blocking_recv().unwrap();
+        }
+
+        renderer.mix_batch(&mut batch);
+        assert_eq!(batch.len(), 20);
+
+        // Left: only the first speaker. Right: first speaker plus the second one panned right.
+        let expected_right = 0.2 + 0.2 * 0.5 * SQRT_2;
+        for frame in batch.chunks_exact(2) {
+            assert!((frame[0] - 0.2).abs() < 1e-5);
+            assert!((frame[1] - expected_right).abs() < 1e-5);
+        }
+
+        // One frame per speaker plus the mix. The speaker frames are not affected by the controls.
+        let mut tracks = vec![];
+        while tracks.last() != Some(&TapTrack::Mix) {
+            let frame = tap_receiver.blocking_recv().unwrap();
+            if frame.track == TapTrack::Speaker(3) {
+                assert_eq!(frame.samples[0], 0.2);
+            }
+            tracks.push(frame.track);
+        }
+        assert_eq!(tracks.len(), 4);
+
+        // The removed speaker is dropped outside of the callback
+        mixer.remove_speaker(2);
+        renderer.mix_batch(&mut batch);
+        assert_eq!(mixer.speakers().len(), 2);
+        assert!(batch.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+    }
+}
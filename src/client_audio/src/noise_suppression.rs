@@ -0,0 +1,252 @@
+// Noise suppression based on spectral subtraction. Each frame is transformed with a short time
+// Fourier transform (50% overlap, square root Hann window, perfect reconstruction). The noise
+// spectrum is estimated by tracking the minimum of the smoothed power spectrum, and each bin is
+// attenuated with a Wiener gain computed from the decision-directed a priori SNR, which greatly
+// reduces musical noise compared to plain subtraction.
+
+use crate::AudioProcessor;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::{f32::consts::PI, sync::Arc};
+use vors_share_session::{NoiseSuppressionConfig, NoiseSuppressionStrength};
+
+// Time smoothing of the power spectrum used for noise tracking
+const POWER_SMOOTHING: f32 = 0.8;
+
+// Per frame growth of the noise estimate when the signal is louder than it. Allows the estimate
+// to follow increasing noise levels (about 2 dB/s with 10ms frames).
+const NOISE_RISE_FACTOR: f32 = 1.005;
+
+// Weight of the previous frame in the decision-directed a priori SNR estimation
+const DECISION_DIRECTED_ALPHA: f32 = 0.98;
+
+// The first frames are assumed to contain only noise
+const NOISE_TRAINING_FRAMES: usize = 10;
+
+struct ChannelState {
+    input_history: Vec<f32>,
+    output_overlap: Vec<f32>,
+    smoothed_power: Vec<f32>,
+    noise_power: Vec<f32>,
+    previous_clean_power: Vec<f32>,
+}
+
+pub struct NoiseSuppressor {
+    channels_count: usize,
+    hop_size: usize,
+    fft: Arc<dyn Fft<f32>>,
+    ifft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    gain_floor: f32,
+    oversubtraction: f32,
+    channels: Vec<ChannelState>,
+    spectrum: Vec<Complex<f32>>,
+    frames_count: usize,
+}
+
+impl NoiseSuppressor {
+    // `frame_size` is the number of frames (samples per channel) passed to each `process()` call.
+    pub fn new(config: &NoiseSuppressionConfig, channels_count: usize, frame_size: usize) -> Self {
+        let fft_size = 2 * frame_size;
+        let bins_count = frame_size + 1;
+
+        let mut planner = FftPlanner::new();
+
+        // Square root of a periodic Hann window. Applied both before and after the transform, the
+        // overlapping halves sum to one.
+        let window = (0..fft_size)
+            .map(|n| (PI * n as f32 / fft_size as f32).sin())
+            .collect();
+
+        let (gain_floor_db, oversubtraction) = match config.strength {
+            NoiseSuppressionStrength::Low => (-9., 1.),
+            NoiseSuppressionStrength::Medium => (-15., 1.5),
+            NoiseSuppressionStrength::High => (-22., 2.),
+            NoiseSuppressionStrength::VeryHigh => (-30., 3.),
+        };
+
+        Self {
+            channels_count,
+            hop_size: frame_size,
+            fft: planner.plan_fft_forward(fft_size),
+            ifft: planner.plan_fft_inverse(fft_size),
+            window,
+            gain_floor: crate::dsp::db_to_linear(gain_floor_db),
+            oversubtraction,
+            channels: (0..channels_count)
+                .map(|_| ChannelState {
+                    input_history: vec![0.; frame_size],
+                    output_overlap: vec![0.; frame_size],
+                    smoothed_power: vec![0.; bins_count],
+                    noise_power: vec![0.; bins_count],
+                    previous_clean_power: vec![0.; bins_count],
+                })
+                .collect(),
+            spectrum: vec![Complex::default(); fft_size],
+            frames_count: 0,
+        }
+    }
+}
+
+impl AudioProcessor for NoiseSuppressor {
+    fn name(&self) -> &'static str {
+        "noise_suppression"
+    }
+
+    fn channels_count(&self) -> usize {
+        self.channels_count
+    }
+
+    fn latency_frames(&self) -> usize {
+        self.hop_size
+    }
+
+    fn reset(&mut self) {
+        for state in &mut self.channels {
+            for buffer in [
+                &mut state.input_history,
+                &mut state.output_overlap,
+                &mut state.smoothed_power,
+                &mut state.noise_power,
+                &mut state.previous_clean_power,
+            ] {
+                buffer.fill(0.);
+            }
+        }
+        self.frames_count = 0;
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        let hop_size = self.hop_size;
+        let fft_size = 2 * hop_size;
+        let channels_count = self.channels_count;
+        let training = self.frames_count < NOISE_TRAINING_FRAMES;
+
+        for (channel_index, state) in self.channels.iter_mut().enumerate() {
+            for n in 0..hop_size {
+                self.spectrum[n] = Complex::new(state.input_history[n] * self.window[n], 0.);
+
+                let sample = frame[n * channels_count + channel_index];
+                self.spectrum[hop_size + n] = Complex::new(sample * self.window[hop_size + n], 0.);
+                state.input_history[n] = sample;
+            }
+
+            self.fft.process(&mut self.spectrum);
+
+            for k in 0..=hop_size {
+                let power = self.spectrum[k].norm_sqr();
+
+                let smoothed = &mut state.smoothed_power[k];
+                *smoothed = if self.frames_count == 0 {
+                    power
+                } else {
+                    *smoothed * POWER_SMOOTHING + power * (1. - POWER_SMOOTHING)
+                };
+
+                let noise = &mut state.noise_power[k];
+                if training || *smoothed < *noise {
+                    *noise = *smoothed;
+                } else {
+                    *noise *= NOISE_RISE_FACTOR;
+                }
+
+                let noise = (*noise * self.oversubtraction).max(1e-12);
+                let posterior_snr = power / noise;
+                let prior_snr = DECISION_DIRECTED_ALPHA * state.previous_clean_power[k] / noise
+                    + (1. - DECISION_DIRECTED_ALPHA) * (posterior_snr - 1.).max(0.);
+
+                let gain = (prior_snr / (1. + prior_snr)).max(self.gain_floor);
+                state.previous_clean_power[k] = gain * gain * power;
+
+                self.spectrum[k] *= gain;
+                if k != 0 && k != hop_size {
+                    self.spectrum[fft_size - k] *= gain;
+                }
+            }
+
+            self.ifft.process(&mut self.spectrum);
+
+            let normalization = 1. / fft_size as f32;
+            for n in 0..hop_size {
+                let sample =
+                    state.output_overlap[n] + self.spectrum[n].re * normalization * self.window[n];
+                frame[n * channels_count + channel_index] = sample;
+
+                state.output_overlap[n] =
+                    self.spectrum[hop_size + n].re * normalization * self.window[hop_size + n];
+            }
+        }
+
+        self.frames_count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dsp;
+    use std::io::Cursor;
+
+    const FIXTURE: &[u8] = include_bytes!("../fixtures/noisy_tone_16k.wav");
+
+    fn read_fixture() -> (Vec<f32>, u32) {
+        let mut reader = hound::WavReader::new(Cursor::new(FIXTURE)).unwrap();
+        let sample_rate = reader.spec().sample_rate;
+        let samples = reader
+            .samples::<i16>()
+            .map(|s| s.unwrap() as f32 / i16::MAX as f32)
+            .collect();
+
+        (samples, sample_rate)
+    }
+
+    fn tone_amplitude(samples: &[f32], offset: usize, sample_rate: u32) -> f32 {
+        let (mut sin_sum, mut cos_sum) = (0., 0.);
+        for (i, s) in samples.iter().enumerate() {
+            let phase = 2. * PI * 1000. * (offset + i) as f32 / sample_rate as f32;
+            sin_sum += s * phase.sin();
+            cos_sum += s * phase.cos();
+        }
+
+        2. * (sin_sum * sin_sum + cos_sum * cos_sum).sqrt() / samples.len() as f32
+    }
+
+    #[test]
+    fn test_noise_suppression_on_fixture() {
+        let (input, sample_rate) = read_fixture();
+        let frame_size = sample_rate as usize / 100;
+
+        let mut suppressor = NoiseSuppressor::new(
+            &NoiseSuppressionConfig {
+                strength: NoiseSuppressionStrength::Medium,
+            },
+            1,
+            frame_size,
+        );
+
+        let mut output = input.clone();
+        for frame in output.chunks_exact_mut(frame_size) {
+            suppressor.process(frame);
+        }
+        let latency = suppressor.latency_frames();
+        let range = |start_s: f32, end_s: f32| {
+            (start_s * sample_rate as f32) as usize..(end_s * sample_rate as f32) as usize
+        };
+
+        // Noise only segment
+        let noise_range = range(1.7, 1.95);
+        let noise_in = dsp::power_db(&input[noise_range.clone()]);
+        let noise_out =
+            dsp::power_db(&output[noise_range.start + latency..noise_range.end + latency]);
+        assert!(noise_in - noise_out > 10., "{noise_in} -> {noise_out}");
+
+        // Tone segment
+        let tone_range = range(2.1, 2.4);
+        let tone_in = tone_amplitude(&input[tone_range.clone()], tone_range.start, sample_rate);
+        let tone_out = tone_amplitude(
+            &output[tone_range.start + latency..tone_range.end + latency],
+            tone_range.start,
+            sample_rate,
+        );
+        assert!((20. * (tone_out / tone_in).log10()).abs() < 1.);
+    }
+}
@@ -0,0 +1,427 @@
+// Jitter buffer between the receive loop of a speaker and the audio callback, built on a wait-free
+// ring buffer. The receive loop only pushes samples and reports packet losses. The callback applies
+// the buffering policy: prebuffering, fade-in and fade-out around interruptions, and cross-fade
+// when samples are dropped because of an overflow. The callback never waits for the receive loop
+// and its work is bounded by the batch size. When the sender is in discontinuous transmission, the
+// silence is filled with comfort noise.
+
+use crate::{
+    item_ring, sample_ring, ComfortNoiseGenerator, ItemConsumer, ItemProducer, SampleConsumer,
+    SampleProducer,
+};
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use vors_share_packets::ComfortNoiseDescriptor;
+
+// Descriptors not handled yet by the callback. The newest are dropped while nothing plays.
+const COMFORT_NOISE_QUEUE_CAPACITY: usize = 16;
+
+#[derive(Default)]
+struct Shared {
+    // Write position at the last packet loss. The samples before it are not played anymore.
+    flush_position: AtomicUsize,
+    recoveries_count: AtomicUsize,
+    overflows_count: AtomicUsize,
+}
+
+pub fn playback_buffer(
+    sample_rate: u32,
+    channels_count: usize,
+    batch_frames_count: usize,
+    average_buffer_frames_count: usize,
+) -> (PlaybackBufferProducer, PlaybackBufferConsumer) {
+    // Leave room above the overflow threshold for bursts of packets
+    let (producer, consumer) =
+        sample_ring(4 * (average_buffer_frames_count + batch_frames_count) * channels_count);
+    let shared = Arc::new(Shared::default());
+    let (comfort_noise_producer, comfort_noise_consumer) = item_ring(COMFORT_NOISE_QUEUE_CAPACITY);
+
+    (
+        PlaybackBufferProducer {
+            ring: producer,
+            shared: Arc::clone(&shared),
+            channels_count,
+            recoveries_count: 0,
+            overflows_count: 0,
+            comfort_noise: comfort_noise_producer,
+        },
+        PlaybackBufferConsumer {
+            ring: consumer,
+            shared,
+            channels_count,
+            batch_frames_count,
+            average_buffer_frames_count,
+            handled_flush_position: 0,
+            playing: false,
+            crossfade_batch: vec![0.; batch_frames_count * channels_count],
+            sample_rate,
+            comfort_noise: ComfortNoiseGenerator::new(sample_rate, channels_count),
+            comfort_noise_descriptors: comfort_noise_consumer,
+            comfort_noise_remaining_frames: 0,
+        },
+    )
+}
+
+pub struct PlaybackBufferProducer {
+    ring: SampleProducer,
+    shared: Arc<Shared>,
+    channels_count: usize,
+    recoveries_count: usize,
+    overflows_count: usize,
+    comfort_noise: ItemProducer<ComfortNoiseDescriptor>,
+}
+
+impl PlaybackBufferProducer {
+    // Returns the count of samples that did not fit in the buffer
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        samples.len() - self.ring.push(samples)
+    }
+
+    // Called after a packet loss. The samples pushed so far are discarded, except for a batch
+    // played with a fade-out, and the buffer is filled again before resuming.
+    pub fn flush(&mut self) {
+        self.shared
+            .flush_position
+            .store(self.ring.position(), Ordering::Release);
+    }
+
+    pub fn frames_count(&self) -> usize {
+        self.ring.len() / self.channels_count
+    }
+
+    // Play comfort noise whenever the buffer runs out of samples, for the duration of the descriptor
+    pub fn set_comfort_noise(&mut self, descriptor: ComfortNoiseDescriptor) {
+        self.comfort_noise.push(descriptor).ok();
+    }
+
+    // Whether the playback resumed since the last call
+    pub fn take_recovered(&mut self) -> bool {
+        let count = self.shared.recoveries_count.load(Ordering::Relaxed);
+        let recovered = count != self.recoveries_count;
+        self.recoveries_count = count;
+
+        recovered
+    }
+
+    // Whether samples have been dropped because of an overflow since the last call
+    pub fn take_overflowed(&mut self) -> bool {
+        let count = self.shared.overflows_count.load(Ordering::Relaxed);
+        let overflowed = count != self.overflows_count;
+        self.overflows_count = count;
+
+        overflowed
+    }
+}
+
+pub struct PlaybackBufferConsumer {
+    ring: SampleConsumer,
+    shared: Arc<Shared>,
+    channels_count: usize,
+    batch_frames_count: usize,
+    average_buffer_frames_count: usize,
+    handled_flush_position: usize,
+    playing: bool,
+    // Allocated once, the callback must not allocate
+    crossfade_batch: Vec<f32>,
+    sample_rate: u32,
+    comfort_noise: ComfortNoiseGenerator,
+    comfort_noise_descriptors: ItemConsumer<ComfortNoiseDescriptor>,
+    comfort_noise_remaining_frames: usize,
+}
+
+impl PlaybackBufferConsumer {
+    fn frames_count(&self) -> usize {
+        self.ring.len() / self.channels_count
+    }
+
+    fn fade(&self, batch: &mut [f32], fade_in: bool) {
+        for (f, frame) in batch.chunks_exact_mut(self.channels_count).enumerate() {
+            let ratio = f as f32 / self.batch_frames_count as f32;
+            let volume = if fade_in { ratio } else { 1. - ratio };
+            for sample in frame {
+                *sample *= volume;
+            }
+        }
+    }
+
+    // False while prebuffering or after an interruption
+    pub fn is_playing(&self) -> bool {
+        self.playing
+    }
+
+    fn pop_batch(ring: &mut SampleConsumer, batch: &mut [f32]) {
+        let count = ring.pop(batch);
+        batch[count..].fill(0.);
+    }
+
+    fn update_comfort_noise(&mut self) {
+        // Only the newest descriptor matters
+        let mut last_descriptor = None;
+        while let Some(descriptor) = self.comfort_noise_descriptors.pop() {
+            last_descriptor = Some(descriptor);
+        }
+
+        if let Some(descriptor) = last_descriptor {
+            self.comfort_noise.set_descriptor(&descriptor);
+            self.comfort_noise_remaining_frames =
+                descriptor.duration_ms as usize * self.sample_rate as usize / 1000;
+        }
+    }
+
+    // Called by the audio callback. The batch must be sized to batch_frames_count frames.
+    pub fn fill_batch(&mut self, batch: &mut [f32]) {
+        self.update_comfort_noise();
+
+        let ends_silent = self.fill_batch_samples(batch);
+
+        // The comfort noise fades in and out with the samples
+        self.comfort_noise_remaining_frames = self
+            .comfort_noise_remaining_frames
+            .saturating_sub(self.batch_frames_count);
+        let comfort_noise_gain = if ends_silent && self.comfort_noise_remaining_frames > 0 {
+            1.
+        } else {
+            0.
+        };
+        self.comfort_noise.add_to(batch, comfort_noise_gain);
+    }
+
+    // Returns true if the batch ends with silence
+    fn fill_batch_samples(&mut self, batch: &mut [f32]) -> bool {
+        let batch_frames_count = self.batch_frames_count;
+
+        let flush_position = self.shared.flush_position.load(Ordering::Acquire);
+        if flush_position != self.handled_flush_position {
+            self.handled_flush_position = flush_position;
+
+            let faded_out = self.playing && self.frames_count() >= batch_frames_count;
+            if faded_out {
+                Self::pop_batch(&mut self.ring, batch);
+                self.fade(batch, false);
+            }
+
+            let discarded_count = flush_position.wrapping_sub(self.ring.position());
+            if discarded_count <= self.ring.len() {
+                self.ring.skip(discarded_count);
+            }
+            self.playing = false;
+
+            if faded_out {
+                return true;
+            }
+        }
+
+        let frames_count = self.frames_count();
+
+        if !self.playing {
+            if frames_count > self.average_buffer_frames_count + batch_frames_count {
+                Self::pop_batch(&mut self.ring, batch);
+                self.fade(batch, true);
+
+                self.playing = true;
+                self.shared.recoveries_count.fetch_add(1, Ordering::Relaxed);
+
+                false
+            } else {
+                batch.fill(0.);
+
+                true
+            }
+        } else if frames_count < batch_frames_count {
+            batch.fill(0.);
+            self.playing = false;
+
+            true
+        } else if frames_count > 2 * self.average_buffer_frames_count + batch_frames_count {
+            // The drift compensation should keep the buffer level stable. This is a last resort
+            // in case of sudden bursts of packets: go back to the average level, cross-fading the
+            // oldest batch with the first one that is kept. Both batches are taken out of the
+            // buffer, so the average level remains after them.
+            Self::pop_batch(&mut self.ring, batch);
+            self.ring.skip(
+                frames_count
+                    .saturating_sub(self.average_buffer_frames_count + 2 * batch_frames_count)
+                    * self.channels_count,
+            );
+            Self::pop_batch(&mut self.ring, &mut self.crossfade_batch);
+
+            for (f, (output, kept)) in batch
+                .chunks_exact_mut(self.channels_count)
+                .zip(self.crossfade_batch.chunks_exact(self.channels_count))
+                .enumerate()
+            {
+                let volume = f as f32 / batch_frames_count as f32;
+                for (output, kept) in output.iter_mut().zip(kept) {
+                    *output = kept * volume + *output * (1. - volume);
+                }
+            }
+
+            self.shared.overflows_count.fetch_add(1, Ordering::Relaxed);
+
+            false
+        } else {
+            Self::pop_batch(&mut self.ring, batch);
+
+            if self.frames_count() < batch_frames_count {
+                // Render fade-out. It is completely contained in the current batch. Playback
+                // resumes with a fade-in once the buffer is filled again.
+                self.fade(batch, false);
+                self.playing = false;
+            }
+
+            !self.playing
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode_pcm_packet, receive_samples_loop, ProcessorChain};
+    use std::{cell::Cell, net::Ipv4Addr};
+    use vors_share_packets::{AudioPacketHeader, COMFORT_NOISE_BANDS_COUNT};
+    use vors_share_session::{SocketBufferSize, SocketProtocol};
+    use vors_share_sockets::StreamSocketBuilder;
+
+    #[test]
+    fn test_fades_and_flush() {
+        let (mut producer, mut consumer) = playback_buffer(1000, 1, 4, 8);
+        let mut batch = [0.; 4];
+
+        // Prebuffering
+        producer.push(&[1.; 12]);
+        consumer.fill_batch(&mut batch);
+        assert_eq!(batch, [0.; 4]);
+
+        producer.push(&[1.; 4]);
+        consumer.fill_batch(&mut batch);
+        assert_eq!(batch, [0., 0.25, 0.5, 0.75]);
+        assert!(producer.take_recovered());
+        assert!(!producer.take_recovered());
+
+        consumer.fill_batch(&mut batch);
+        assert_eq!(batch, [1.; 4]);
+
+        // Packet loss: one batch is faded out and the rest is discarded
+        producer.flush();
+        producer.push(&[0.5; 2]);
+        consumer.fill_batch(&mut batch);
+        assert_eq!(batch, [1., 0.75, 0.5, 0.25]);
+        assert_eq!(producer.frames_count(), 2);
+
+        consumer.fill_batch(&mut batch);
+        assert_eq!(batch, [0.; 4]);
+    }
+
+    #[test]
+    fn test_comfort_noise_after_drain() {
+        let (mut producer, mut consumer) = playback_buffer(16000, 1, 160, 320);
+        let mut batch = [0.; 160];
+
+        producer.set_comfort_noise(ComfortNoiseDescriptor {
+            level_db: -40.,
+            spectrum_db: [0.; COMFORT_NOISE_BANDS_COUNT],
+            duration_ms: 200,
+        });
+        producer.push(&[0.; 640]);
+        while producer.frames_count() > 0 {
+            consumer.fill_batch(&mut batch);
+        }
+
+        // 4 batches of the 20 covered by the descriptor were played from the buffer
+        consumer.fill_batch(&mut batch);
+        assert!(batch.iter().any(|sample| *sample != 0.));
+
+        for _ in 0..15 {
+            consumer.fill_batch(&mut batch);
+        }
+        consumer.fill_batch(&mut batch);
+        assert_eq!(batch, [0.; 160]);
+    }
+
+    // The payload of a descriptor packet is empty, it must not reach the decoder
+    #[tokio::test]
+    async fn test_comfort_noise_descriptor_is_not_decoded() {
+        let port = std::net::UdpSocket::bind((Ipv4Addr::LOCALHOST, 0))
+            .and_then(|socket| socket.local_addr())
+            .unwrap()
+            .port();
+        let socket = StreamSocketBuilder::connect_to_client(
+            Ipv4Addr::LOCALHOST.into(),
+            port,
+            SocketProtocol::Udp,
+            SocketBufferSize::Default,
+            SocketBufferSize::Default,
+            1400,
+        )
+        .await
+        .unwrap();
+        let mut sender = socket.request_stream::<AudioPacketHeader>(0).await.unwrap();
+        let receiver = socket.subscribe_to_stream(0).await.unwrap();
+
+        let (producer, mut consumer) = playback_buffer(16000, 1, 160, 320);
+        let decoded_packets_count = Cell::new(0);
+
+        let send_packets = async {
+            let descriptor = ComfortNoiseDescriptor {
+                level_db: -40.,
+                spectrum_db: [0.; COMFORT_NOISE_BANDS_COUNT],
+                duration_ms: 200,
+            };
+            let header = AudioPacketHeader {
+                voice_activity: false,
+                comfort_noise: Some(descriptor),
+            };
+            sender.send(&header, vec![]).await.unwrap();
+            sender
+                .send(&AudioPacketHeader::default(), vec![0; 320])
+                .await
+                .unwrap();
+
+            while decoded_packets_count.get() == 0 {
+                tokio::task::yield_now().await;
+            }
+        };
+
+        tokio::select! {
+            res = socket.receive_loop() => panic!("{res:?}"),
+            res = receive_samples_loop(
+                receiver,
+                |header: &AudioPacketHeader, packet, samples| {
+                    assert!(header.comfort_noise.is_none());
+                    decoded_packets_count.set(decoded_packets_count.get() + 1);
+                    decode_pcm_packet(packet, samples)
+                },
+                ProcessorChain::new(1),
+                producer,
+                1,
+                320,
+            ) => panic!("{res:?}"),
+            _ = send_packets => (),
+        }
+
+        // Prebuffering, the descriptor fills the silence
+        let mut batch = [0.; 160];
+        consumer.fill_batch(&mut batch);
+        assert!(batch.iter().any(|sample| *sample != 0.));
+    }
+
+    #[test]
+    fn test_overflow() {
+        let (mut producer, mut consumer) = playback_buffer(1000, 1, 4, 8);
+        let mut batch = [0.; 4];
+
+        producer.push(&[1.; 16]);
+        consumer.fill_batch(&mut batch);
+
+        // Above 2 * 8 + 4 frames
+        producer.push(&[0.; 12]);
+        assert_eq!(producer.frames_count(), 24);
+        consumer.fill_batch(&mut batch);
+        assert_eq!(batch, [1., 0.75, 0.5, 0.25]);
+        assert!(producer.take_overflowed());
+        assert_eq!(producer.frames_count(), 8);
+    }
+}
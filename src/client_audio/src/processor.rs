@@ -0,0 +1,374 @@
+// Frame based audio processing. Each DSP feature implements `AudioProcessor`, and the capture and
+// playback paths run a `ProcessorChain` built from the settings. Processors can then be added,
+// reordered, bypassed and tested independently from the audio loops.
+
+use crate::{
+    AudioTap, AutomaticGainControl, EchoCanceller, EchoReference, Limiter, NoiseSuppressor,
+    Sidetone, SoundboardControl, SpatialRenderer, SpatialScene, SpeakerId, VoiceActivitySignal,
+    VoiceEffects, VoiceEffectsControl, CAPTURE_FRAME_MS,
+};
+use vors_share_common::prelude::*;
+use vors_share_session::{
+    AudioConfig, AutomaticGainControlConfig, DuckingConfig, EchoCancellationConfig,
+    NoiseSuppressionConfig, SpatialAudioConfig, VoiceActivityDetectionConfig,
+};
+
+pub trait AudioProcessor: Send {
+    // Used to identify the processor inside a chain
+    fn name(&self) -> &'static str;
+
+    fn channels_count(&self) -> usize;
+
+    // Processing delay, in frames
+    fn latency_frames(&self) -> usize {
+        0
+    }
+
+    // Forget the signal history, as if the processor was just created
+    fn reset(&mut self);
+
+    // Process one frame of interleaved samples in place. All frames passed to a processor must
+    // have the size it was created with.
+    fn process(&mut self, frame: &mut [f32]);
+}
+
+struct ChainEntry {
+    processor: Box<dyn AudioProcessor>,
+    bypassed: bool,
+}
+
+pub struct ProcessorChain {
+    channels_count: usize,
+    entries: Vec<ChainEntry>,
+}
+
+impl ProcessorChain {
+    pub fn new(channels_count: usize) -> Self {
+        Self {
+            channels_count,
+            entries: vec![],
+        }
+    }
+
+    // Append a processor at the end of the chain
+    pub fn push(&mut self, processor: impl AudioProcessor + 'static) {
+        assert_eq!(processor.channels_count(), self.channels_count);
+
+        self.entries.push(ChainEntry {
+            processor: Box::new(processor),
+            bypassed: false,
+        });
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.entries.iter().map(|e| e.processor.name()).collect()
+    }
+
+    // A bypassed processor is skipped but keeps its state. Returns false if there is no processor
+    // with this name.
+    pub fn set_bypassed(&mut self, name: &str, bypassed: bool) -> bool {
+        let mut found = false;
+        for entry in &mut self.entries {
+            if entry.processor.name() == name {
+                entry.bypassed = bypassed;
+                found = true;
+            }
+        }
+
+        found
+    }
+
+    // Chain for the microphone signal, before the voice activity detection and the encoding
+    pub fn capture(
+        desc: &CaptureProcessingDesc,
+        sample_rate: u32,
+        channels_count: usize,
+        frame_size: usize,
+    ) -> Self {
+        let mut chain = Self::new(channels_count);
+
+        if let (Some(config), Some(reference)) = (&desc.echo_cancellation, &desc.echo_reference) {
+            chain.push(EchoCanceller::new(
+                config,
+                reference.clone(),
+                sample_rate,
+                channels_count,
+                frame_size,
+            ));
+        }
+        if let Some(config) = &desc.noise_suppression {
+            chain.push(NoiseSuppressor::new(config, channels_count, frame_size));
+        }
+        // Always present, so that an effect can be selected at any time
+        chain.push(VoiceEffects::new(
+            desc.voice_effects.clone(),
+            sample_rate,
+            channels_count,
+            frame_size,
+        ));
+        if let Some(config) = &desc.automatic_gain_control {
+            chain.push(AutomaticGainControl::new(
+                config,
+                channels_count,
+                CAPTURE_FRAME_MS,
+            ));
+        }
+
+        // Always last, it prevents clipping on the conversion to i16
+        chain.push(Limiter::new(sample_rate, channels_count));
+
+        chain
+    }
+
+    // Chain for the voice of one remote speaker. Each frame corresponds to one received packet.
+    pub fn playback(
+        desc: &PlaybackProcessingDesc,
+        speaker_id: SpeakerId,
+        scene: &SpatialScene,
+        sample_rate: u32,
+        channels_count: usize,
+    ) -> Self {
+        let mut chain = Self::new(channels_count);
+
+        if let Some(config) = &desc.speaker_normalization {
+            chain.push(AutomaticGainControl::new(
+                config,
+                channels_count,
+                CAPTURE_FRAME_MS,
+            ));
+        }
+        if let Some(config) = &desc.spatial_audio {
+            if channels_count == 2 {
+                chain.push(SpatialRenderer::new(
+                    config,
+                    scene.clone(),
+                    speaker_id,
+                    sample_rate,
+                ));
+            } else {
+                warn!("Spatial audio requires a stereo output device");
+            }
+        }
+
+        chain
+    }
+}
+
+impl AudioProcessor for ProcessorChain {
+    fn name(&self) -> &'static str {
+        "chain"
+    }
+
+    fn channels_count(&self) -> usize {
+        self.channels_count
+    }
+
+    fn latency_frames(&self) -> usize {
+        self.entries
+            .iter()
+            .filter(|e| !e.bypassed)
+            .map(|e| e.processor.latency_frames())
+            .sum()
+    }
+
+    fn reset(&mut self) {
+        for entry in &mut self.entries {
+            entry.processor.reset();
+        }
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        for entry in &mut self.entries {
+            if !entry.bypassed {
+                entry.processor.process(frame);
+            }
+        }
+    }
+}
+
+// Settings of the capture path
+#[derive(Clone, Default)]
+pub struct CaptureProcessingDesc {
+    pub echo_cancellation: Option<EchoCancellationConfig>,
+    // Far-end signal for the echo cancellation, published by the playback loop
+    pub echo_reference: Option<EchoReference>,
+    pub noise_suppression: Option<NoiseSuppressionConfig>,
+    // Shared with the local API
+    pub voice_effects: VoiceEffectsControl,
+    // Not part of the chain, the clips are mixed into its output. Shared with the local API.
+    pub soundboard: SoundboardControl,
+    pub automatic_gain_control: Option<AutomaticGainControlConfig>,
+    // Not part of the chain, the detection runs on its output
+    pub voice_activity_detection: Option<VoiceActivityDetectionConfig>,
+    // Side-chain of the ducking, set while the microphone transmits speech
+    pub local_voice_activity: Option<VoiceActivitySignal>,
+    // Played by the playback path
+    pub sidetone: Option<Sidetone>,
+}
+
+impl CaptureProcessingDesc {
+    pub fn new(
+        config: &AudioConfig,
+        echo_reference: Option<EchoReference>,
+        // Initialized from the settings by the caller
+        voice_effects: VoiceEffectsControl,
+        soundboard: SoundboardControl,
+        local_voice_activity: Option<VoiceActivitySignal>,
+        sidetone: Option<Sidetone>,
+    ) -> Self {
+        Self {
+            echo_cancellation: config.echo_cancellation.as_option().cloned(),
+            echo_reference,
+            noise_suppression: config.noise_suppression.as_option().cloned(),
+            voice_effects,
+            soundboard,
+            automatic_gain_control: config.automatic_gain_control.as_option().cloned(),
+            voice_activity_detection: config.voice_activity_detection.as_option().cloned(),
+            local_voice_activity,
+            sidetone,
+        }
+    }
+}
+
+// Settings of the per-speaker playback chain
+#[derive(Clone, Default)]
+pub struct PlaybackProcessingDesc {
+    pub speaker_normalization: Option<AutomaticGainControlConfig>,
+    pub spatial_audio: Option<SpatialAudioConfig>,
+    // Receives the decoded voice of each speaker and the mix
+    pub tap: AudioTap,
+    // Applied by the mixer to the background streams
+    pub ducking: Option<DuckingConfig>,
+    // Shared with the capture path, used only if enabled in the ducking settings
+    pub local_voice_activity: Option<VoiceActivitySignal>,
+    // Published by the capture path, added to the output after the mix
+    pub sidetone: Option<Sidetone>,
+}
+
+impl PlaybackProcessingDesc {
+    pub fn new(
+        config: &AudioConfig,
+        tap: AudioTap,
+        local_voice_activity: Option<VoiceActivitySignal>,
+        sidetone: Option<Sidetone>,
+    ) -> Self {
+        let ducking = config.ducking.as_option().cloned();
+        let local_voice_activity = local_voice_activity.filter(|_| {
+            ducking
+                .as_ref()
+                .map(|config| config.local_microphone)
+                .unwrap_or(false)
+        });
+
+        Self {
+            speaker_normalization: config.speaker_normalization.as_option().cloned(),
+            spatial_audio: config.spatial_audio.as_option().cloned(),
+            tap,
+            ducking,
+            local_voice_activity,
+            sidetone,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vors_share_session::VoiceEffectPreset;
+
+    struct Scale {
+        factor: f32,
+    }
+
+    impl AudioProcessor for Scale {
+        fn name(&self) -> &'static str {
+            "scale"
+        }
+
+        fn channels_count(&self) -> usize {
+            2
+        }
+
+        fn latency_frames(&self) -> usize {
+            3
+        }
+
+        fn reset(&mut self) {}
+
+        fn process(&mut self, frame: &mut [f32]) {
+            for sample in frame {
+                *sample *= self.factor;
+            }
+        }
+    }
+
+    #[test]
+    fn test_chain_order_and_bypass() {
+        let mut chain = ProcessorChain::new(2);
+        chain.push(Scale { factor: 2. });
+        chain.push(Limiter::new(48000, 2));
+
+        let mut frame = vec![0.25, -0.25];
+        chain.process(&mut frame);
+        assert_eq!(frame, [0.5, -0.5]);
+        assert_eq!(chain.latency_frames(), 3);
+
+        // The limiter runs after the gain
+        let mut frame = vec![0.75, -0.75];
+        chain.process(&mut frame);
+        assert!(frame[0] < 1.);
+
+        // The limiter would still be reducing the gain
+        chain.reset();
+
+        assert!(chain.set_bypassed("scale", true));
+        assert!(!chain.set_bypassed("missing", true));
+        let mut frame = vec![0.25, -0.25];
+        chain.process(&mut frame);
+        assert_eq!(frame, [0.25, -0.25]);
+        assert_eq!(chain.latency_frames(), 0);
+        assert_eq!(chain.names(), ["scale", "limiter"]);
+    }
+
+    #[test]
+    fn test_capture_chain_follows_voice_effects_control() {
+        const SAMPLE_RATE: u32 = 16000;
+        const FRAME_SIZE: usize = 160;
+
+        // Shared with the local API
+        let control = VoiceEffectsControl::default();
+        let desc = CaptureProcessingDesc {
+            voice_effects: control.clone(),
+            ..Default::default()
+        };
+        let mut chain = ProcessorChain::capture(&desc, SAMPLE_RATE, 1, FRAME_SIZE);
+
+        let mut impulse_response = || {
+            let mut output = vec![];
+            for index in 0..4 {
+                let mut frame = vec![0.; FRAME_SIZE];
+                if index == 0 {
+                    frame[0] = 0.5;
+                }
+                chain.process(&mut frame);
+                output.extend(frame);
+            }
+
+            output
+        };
+
+        assert_eq!(impulse_response()[320], 0.);
+
+        control.set_preset(Some(VoiceEffectPreset::Echo {
+            delay_ms: 20,
+            feedback: 0.,
+            wet: 1.,
+        }));
+        // 20ms at 16kHz
+        assert!(impulse_response()[320] > 0.1);
+    }
+}
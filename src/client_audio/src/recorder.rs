@@ -0,0 +1,622 @@
+// Multitrack recording of the playback. The mix and the voice of each speaker are written to
+// separate files that share the same timeline: a speaker track starts with silence if the speaker
+// joined after the start of the recording, and gaps are filled with silence too. A JSON sidecar
+// lists the files and the segments where each speaker was talking.
+
+use crate::{dsp, AudioTap, FractionalResampler, SpeakerId, TapFrame, TapTrack};
+use audiopus::{coder::Encoder as OpusEncoder, Application, Bitrate, Channels, SampleRate};
+use hound::{SampleFormat, WavSpec, WavWriter};
+use ogg::{PacketWriteEndInfo, PacketWriter};
+use serde::Serialize;
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::mpsc as smpsc,
+    thread::{self, JoinHandle},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::broadcast::{self, error::TryRecvError};
+use vors_share_common::prelude::*;
+use vors_share_session::{AudioRecordingConfig, OggOpusRecordingConfig};
+
+const OPUS_SAMPLE_RATE: u32 = 48000;
+const OPUS_FRAME_SIZE: usize = OPUS_SAMPLE_RATE as usize / 50; // 20ms
+const OPUS_MAX_PACKET_SIZE: usize = 4000;
+
+// Pauses shorter than this do not split a speech segment
+const SPEECH_MERGE_GAP_MS: u64 = 500;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+const SIDECAR_FILE_NAME: &str = "recording.json";
+
+#[derive(Serialize, Clone, Debug)]
+pub struct RecordedTrack {
+    pub track: TapTrack,
+    // Relative to the recording directory
+    pub files: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct SpeechSegment {
+    pub speaker: SpeakerId,
+    pub start_s: f64,
+    pub end_s: f64,
+}
+
+// Content of the sidecar
+#[derive(Serialize, Clone, Debug)]
+pub struct RecordingInfo {
+    pub directory: PathBuf,
+    pub started_unix_ms: u64,
+    // Zero if nothing has been played during the recording
+    pub sample_rate: u32,
+    pub channels_count: u16,
+    pub duration_s: f64,
+    pub tracks: Vec<RecordedTrack>,
+    // Sorted by start time
+    pub speech: Vec<SpeechSegment>,
+}
+
+fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1., 1.) * i16::MAX as f32) as i16
+}
+
+// Opus stream in an Ogg container, as described by RFC 7845. The audio is resampled to 48kHz.
+struct OggOpusWriter {
+    packet_writer: PacketWriter<BufWriter<File>>,
+    serial: u32,
+    encoder: OpusEncoder,
+    resampler: Option<FractionalResampler>,
+    input_sample_rate: u32,
+    channels_count: usize,
+    pre_skip: u64,
+    // Samples at 48kHz waiting for a complete Opus frame
+    pending: Vec<f32>,
+    resampled: Vec<f32>,
+    // Frames at 48kHz passed to the encoder, padding excluded
+    input_frames: u64,
+    encoded_frames: u64,
+    // The last packet is held back, so that it can be marked as the end of the stream
+    last_packet: Option<(Vec<u8>, u64)>,
+}
+
+impl OggOpusWriter {
+    fn new(
+        path: &Path,
+        serial: u32,
+        config: &OggOpusRecordingConfig,
+        sample_rate: u32,
+        channels_count: usize,
+    ) -> StrResult<Self> {
+        let channels = match channels_count {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            _ => return fmt_e!("Ogg Opus recording supports only mono and stereo"),
+        };
+
+        let mut encoder =
+            OpusEncoder::new(SampleRate::Hz48000, channels, Application::Audio).map_err(err!())?;
+        encoder
+            .set_bitrate(Bitrate::BitsPerSecond(config.bitrate_kbps as i32 * 1000))
+            .map_err(err!())?;
+        let pre_skip = encoder.lookahead().map_err(err!())?;
+
+        let mut packet_writer =
+            PacketWriter::new(BufWriter::new(File::create(path).map_err(err!())?));
+
+        let mut head = b"OpusHead".to_vec();
+        head.push(1); // version
+        head.push(channels_count as u8);
+        head.extend((pre_skip as u16).to_le_bytes());
+        head.extend(sample_rate.to_le_bytes());
+        head.extend(0_i16.to_le_bytes()); // output gain
+        head.push(0); // channel mapping family
+        packet_writer
+            .write_packet(head.into(), serial, PacketWriteEndInfo::EndPage, 0)
+            .map_err(err!())?;
+
+        let vendor = b"vors";
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend((vendor.len() as u32).to_le_bytes());
+        tags.extend(vendor);
+        tags.extend(0_u32.to_le_bytes()); // user comments count
+        packet_writer
+            .write_packet(tags.into(), serial, PacketWriteEndInfo::EndPage, 0)
+            .map_err(err!())?;
+
+        Ok(Self {
+            packet_writer,
+            serial,
+            encoder,
+            resampler: (sample_rate != OPUS_SAMPLE_RATE)
+                .then(|| FractionalResampler::new(channels_count)),
+            input_sample_rate: sample_rate,
+            channels_count,
+            pre_skip: pre_skip as u64,
+            pending: vec![],
+            resampled: vec![],
+            input_frames: 0,
+            encoded_frames: 0,
+            last_packet: None,
+        })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> StrResult {
+        if let Some(resampler) = &mut self.resampler {
+            self.resampled.clear();
+            resampler.process(
+                samples,
+                self.input_sample_rate as f64 / OPUS_SAMPLE_RATE as f64,
+                &mut self.resampled,
+            );
+            self.pending.extend(&self.resampled);
+        } else {
+            self.pending.extend(samples);
+        }
+
+        let frame_samples_count = OPUS_FRAME_SIZE * self.channels_count;
+        while self.pending.len() >= frame_samples_count {
+            let frame = self
+                .pending
+                .drain(0..frame_samples_count)
+                .collect::<Vec<_>>();
+            self.input_frames += OPUS_FRAME_SIZE as u64;
+            self.encode(&frame)?;
+        }
+
+        Ok(())
+    }
+
+    fn encode(&mut self, frame: &[f32]) -> StrResult {
+        let mut packet = vec![0; OPUS_MAX_PACKET_SIZE];
+        let size = self
+            .encoder
+            .encode_float(frame, &mut packet)
+            .map_err(err!())?;
+        packet.truncate(size);
+
+        self.encoded_frames += OPUS_FRAME_SIZE as u64;
+
+        if let Some((packet, granule_position)) = self.last_packet.take() {
+            self.packet_writer
+                .write_packet(
+                    packet.into(),
+                    self.serial,
+                    PacketWriteEndInfo::NormalPacket,
+                    granule_position,
+                )
+                .map_err(err!())?;
+        }
+        self.last_packet = Some((packet, self.pre_skip + self.encoded_frames));
+
+        Ok(())
+    }
+
+    fn finish(mut self) -> StrResult {
+        if !self.pending.is_empty() || self.last_packet.is_none() {
+            self.input_frames += (self.pending.len() / self.channels_count) as u64;
+
+            let mut frame = std::mem::take(&mut self.pending);
+            frame.resize(OPUS_FRAME_SIZE * self.channels_count, 0.);
+            self.encode(&frame)?;
+        }
+
+        if let Some((packet, _)) = self.last_packet.take() {
+            // The granule position of the last packet trims the padding of the last frame
+            self.packet_writer
+                .write_packet(
+                    packet.into(),
+                    self.serial,
+                    PacketWriteEndInfo::EndStream,
+                    self.pre_skip + self.input_frames,
+                )
+                .map_err(err!())?;
+        }
+
+        Ok(())
+    }
+}
+
+struct TrackWriter {
+    wav: Option<WavWriter<BufWriter<File>>>,
+    ogg_opus: Option<OggOpusWriter>,
+    channels_count: usize,
+    written_frames: u64,
+    files: Vec<String>,
+}
+
+impl TrackWriter {
+    fn new(
+        directory: &Path,
+        track: TapTrack,
+        serial: u32,
+        config: &AudioRecordingConfig,
+        sample_rate: u32,
+        channels_count: u16,
+    ) -> StrResult<Self> {
+        let mut files = vec![];
+
+        let wav = if config.wav {
+            let file_name = format!("{}.wav", track.file_stem());
+            let writer = WavWriter::create(
+                directory.join(&file_name),
+                WavSpec {
+                    channels: channels_count,
+                    sample_rate,
+                    bits_per_sample: 16,
+                    sample_format: SampleFormat::Int,
+                },
+            )
+            .map_err(err!())?;
+            files.push(file_name);
+
+            Some(writer)
+        } else {
+            None
+        };
+
+        let ogg_opus = if let Some(opus_config) = config.ogg_opus.as_option() {
+            let file_name = format!("{}.opus", track.file_stem());
+            let writer = OggOpusWriter::new(
+                &directory.join(&file_name),
+                serial,
+                opus_config,
+                sample_rate,
+                channels_count as _,
+            )?;
+            files.push(file_name);
+
+            Some(writer)
+        } else {
+            None
+        };
+
+        Ok(Self {
+            wav,
+            ogg_opus,
+            channels_count: channels_count as _,
+            written_frames: 0,
+            files,
+        })
+    }
+
+    fn write(&mut self, samples: &[f32]) -> StrResult {
+        if let Some(writer) = &mut self.wav {
+            for sample in samples {
+                writer.write_sample(to_i16(*sample)).map_err(err!())?;
+            }
+        }
+        if let Some(writer) = &mut self.ogg_opus {
+            writer.write(samples)?;
+        }
+
+        self.written_frames += (samples.len() / self.channels_count) as u64;
+
+        Ok(())
+    }
+
+    // Write silence up to the specified position
+    fn pad_to(&mut self, position_frames: u64) -> StrResult {
+        const CHUNK_FRAMES: u64 = 4800;
+
+        let silence = vec![0.; CHUNK_FRAMES as usize * self.channels_count];
+        while self.written_frames < position_frames {
+            let frames_count = u64::min(position_frames - self.written_frames, CHUNK_FRAMES);
+            self.write(&silence[0..frames_count as usize * self.channels_count])?;
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> StrResult<Vec<String>> {
+        if let Some(writer) = self.wav {
+            writer.finalize().map_err(err!())?;
+        }
+        if let Some(writer) = self.ogg_opus {
+            writer.finish()?;
+        }
+
+        Ok(self.files)
+    }
+}
+
+// Segments where a speaker level is above the threshold, in frames
+#[derive(Default)]
+struct SpeechTracker {
+    current: Option<(u64, u64)>,
+    segments: Vec<(u64, u64)>,
+}
+
+struct RecordingState {
+    config: AudioRecordingConfig,
+    directory: PathBuf,
+    started_unix_ms: u64,
+    // Sample rate and channels count of the first frame. Frames with a different format are
+    // discarded.
+    format: Option<(u32, u16)>,
+    // Tap position of the start of the recording
+    origin_frames: u64,
+    end_frames: u64,
+    tracks: BTreeMap<TapTrack, TrackWriter>,
+    speech: BTreeMap<SpeakerId, SpeechTracker>,
+    format_warning_shown: bool,
+}
+
+impl RecordingState {
+    fn push(&mut self, frame: TapFrame) -> StrResult {
+        let (sample_rate, channels_count) = *self
+            .format
+            .get_or_insert((frame.sample_rate, frame.channels_count));
+        if self.tracks.is_empty() {
+            self.origin_frames = frame.position_frames;
+        }
+
+        if frame.sample_rate != sample_rate
+            || frame.channels_count != channels_count
+            || frame.position_frames < self.origin_frames
+        {
+            if !self.format_warning_shown {
+                warn!("The playback has been restarted, its audio is not recorded anymore");
+                self.format_warning_shown = true;
+            }
+
+            return Ok(());
+        }
+
+        let start_frames = frame.position_frames - self.origin_frames;
+        let frames_count = (frame.samples.len() / channels_count as usize) as u64;
+
+        if !self.tracks.contains_key(&frame.track) {
+            let writer = TrackWriter::new(
+                &self.directory,
+                frame.track,
+                self.tracks.len() as u32 + 1,
+                &self.config,
+                sample_rate,
+                channels_count,
+            )?;
+            self.tracks.insert(frame.track, writer);
+        }
+        let writer = self.tracks.get_mut(&frame.track).unwrap();
+
+        // Frames published twice for the same position are ignored
+        if start_frames < writer.written_frames {
+            return Ok(());
+        }
+        writer.pad_to(start_frames)?;
+        writer.write(&frame.samples)?;
+
+        self.end_frames = u64::max(self.end_frames, start_frames + frames_count);
+
+        if let TapTrack::Speaker(id) = frame.track {
+            let tracker = self.speech.entry(id).or_default();
+
+            if dsp::power_db(&frame.samples) > self.config.speech_threshold_db {
+                let end_frames = start_frames + frames_count;
+                let merge_gap_frames = SPEECH_MERGE_GAP_MS * sample_rate as u64 / 1000;
+
+                match &mut tracker.current {
+                    Some((_, end)) if start_frames <= *end + merge_gap_frames => *end = end_frames,
+                    current => {
+                        if let Some(segment) = current.take() {
+                            tracker.segments.push(segment);
+                        }
+                        *current = Some((start_frames, end_frames));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    fn finish(self) -> StrResult<RecordingInfo> {
+        let (sample_rate, channels_count) = self.format.unwrap_or((0, 0));
+        let to_seconds = |frames: u64| {
+            if sample_rate > 0 {
+                frames as f64 / sample_rate as f64
+            } else {
+                0.
+            }
+        };
+
+        let mut tracks = vec![];
+        for (track, mut writer) in self.tracks {
+            writer.pad_to(self.end_frames)?;
+
+            tracks.push(RecordedTrack {
+                track,
+                files: writer.finish()?,
+            });
+        }
+
+        let mut speech = vec![];
+        for (speaker, tracker) in self.speech {
+            for (start, end) in tracker.segments.into_iter().chain(tracker.current) {
+                speech.push(SpeechSegment {
+                    speaker,
+                    start_s: to_seconds(start),
+                    end_s: to_seconds(end),
+                });
+            }
+        }
+        speech.sort_by(|a, b| a.start_s.total_cmp(&b.start_s));
+
+        let info = RecordingInfo {
+            directory: self.directory,
+            started_unix_ms: self.started_unix_ms,
+            sample_rate,
+            channels_count,
+            duration_s: to_seconds(self.end_frames),
+            tracks,
+            speech,
+        };
+
+        fs::write(
+            info.directory.join(SIDECAR_FILE_NAME),
+            serde_json::to_string_pretty(&info).map_err(err!())?,
+        )
+        .map_err(err!())?;
+
+        Ok(info)
+    }
+}
+
+fn recording_loop(
+    mut state: RecordingState,
+    mut receiver: broadcast::Receiver<TapFrame>,
+    shutdown_receiver: smpsc::Receiver<()>,
+) -> StrResult<RecordingInfo> {
+    // Frames already published are still recorded after the stop request
+    let mut stopping = false;
+    loop {
+        stopping |= !matches!(
+            shutdown_receiver.try_recv(),
+            Err(smpsc::TryRecvError::Empty)
+        );
+
+        match receiver.try_recv() {
+            Ok(frame) => state.push(frame)?,
+            Err(TryRecvError::Lagged(count)) => {
+                // The missing audio is replaced by silence
+                warn!("Audio recording is too slow, {count} frames have been dropped");
+            }
+            Err(TryRecvError::Empty) => {
+                if stopping {
+                    break;
+                }
+
+                thread::sleep(POLL_INTERVAL);
+            }
+            Err(TryRecvError::Closed) => break,
+        }
+    }
+
+    state.finish()
+}
+
+// Records the tap until stopped
+pub struct AudioRecorder {
+    directory: PathBuf,
+    shutdown_notifier: smpsc::Sender<()>,
+    thread: JoinHandle<StrResult<RecordingInfo>>,
+}
+
+impl AudioRecorder {
+    pub fn start(tap: &AudioTap, config: AudioRecordingConfig) -> StrResult<Self> {
+        if !config.wav && config.ogg_opus.as_option().is_none() {
+            return fmt_e!("No recording format is enabled");
+        }
+
+        let started_unix_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(err!())?
+            .as_millis() as u64;
+        let directory =
+            PathBuf::from(&config.directory).join(format!("recording_{started_unix_ms}"));
+        fs::create_dir_all(&directory).map_err(err!())?;
+
+        // Subscribe now, so that no frame is lost while the thread starts
+        let receiver = tap.subscribe();
+        let (shutdown_notifier, shutdown_receiver) = smpsc::channel();
+
+        let state = RecordingState {
+            config,
+            directory: directory.clone(),
+            started_unix_ms,
+            format: None,
+            origin_frames: 0,
+            end_frames: 0,
+            tracks: BTreeMap::new(),
+            speech: BTreeMap::new(),
+            format_warning_shown: false,
+        };
+        let thread = thread::spawn(move || recording_loop(state, receiver, shutdown_receiver));
+
+        Ok(Self {
+            directory,
+            shutdown_notifier,
+            thread,
+        })
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    // Finalize the files and write the sidecar
+    pub fn stop(self) -> StrResult<RecordingInfo> {
+        self.shutdown_notifier.send(()).ok();
+
+        self.thread
+            .join()
+            .map_err(|_| "The recording thread panicked".to_owned())?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vors_share_session::settings_schema::Switch;
+
+    #[test]
+    fn test_multitrack_recording() {
+        let directory =
+            std::env::temp_dir().join(format!("vors_recorder_test_{}", std::process::id()));
+        fs::remove_dir_all(&directory).ok();
+
+        let tap = AudioTap::new();
+        let recorder = AudioRecorder::start(
+            &tap,
+            AudioRecordingConfig {
+                directory: directory.to_string_lossy().into(),
+                wav: true,
+                ogg_opus: Switch::Enabled(OggOpusRecordingConfig { bitrate_kbps: 32 }),
+                speech_threshold_db: -40.,
+            },
+        )
+        .unwrap();
+
+        // 10ms batches at 1kHz. The second speaker joins after 100ms and talks for 50ms.
+        let silence = [0.; 10];
+        let voice = [0.5; 10];
+        for batch in 0..30_u64 {
+            let position = 1000 + batch * 10;
+            tap.publish(TapTrack::Speaker(1), position, 1000, 1, &voice);
+            if batch >= 10 {
+                let samples = if batch < 15 { &voice } else { &silence };
+                tap.publish(TapTrack::Speaker(2), position, 1000, 1, samples);
+            }
+            tap.publish(TapTrack::Mix, position, 1000, 1, &voice);
+        }
+
+        let info = recorder.stop().unwrap();
+        assert_eq!(info.duration_s, 0.3);
+        assert_eq!(info.tracks.len(), 3);
+        assert_eq!(info.tracks[0].track, TapTrack::Mix);
+
+        let mut reader = hound::WavReader::open(info.directory.join("speaker_2.wav")).unwrap();
+        assert_eq!(reader.len(), 300);
+        let samples = reader
+            .samples::<i16>()
+            .map(|s| s.unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(samples[99], 0);
+        assert!(samples[100] > 0);
+
+        let ogg = fs::read(info.directory.join("mix.opus")).unwrap();
+        assert_eq!(&ogg[0..4], b"OggS");
+
+        assert_eq!(info.speech.len(), 2);
+        assert_eq!(info.speech[1].speaker, 2);
+        assert_eq!(info.speech[1].start_s, 0.1);
+        assert_eq!(info.speech[1].end_s, 0.15);
+
+        assert!(info.directory.join(SIDECAR_FILE_NAME).exists());
+
+        fs::remove_dir_all(&directory).ok();
+    }
+}
@@ -0,0 +1,309 @@
+// Wait-free single producer single consumer ring buffers. The two sides never wait for each other,
+// so they can be used by the real-time audio callback. Samples are stored as the bits of atomic
+// integers to avoid unsafe code, the synchronization is done on the positions. Other values (for
+// example the objects handed over to the callback) are moved through `item_ring`.
+
+use std::{
+    cell::UnsafeCell,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+struct Shared {
+    samples: Box<[AtomicU32]>,
+    // Total count of samples written and read. The index in the buffer is obtained with the mask.
+    write_position: AtomicUsize,
+    read_position: AtomicUsize,
+}
+
+// The capacity is rounded up to a power of two
+pub fn sample_ring(capacity: usize) -> (SampleProducer, SampleConsumer) {
+    let capacity = capacity.next_power_of_two();
+    let shared = Arc::new(Shared {
+        samples: (0..capacity).map(|_| AtomicU32::new(0)).collect(),
+        write_position: AtomicUsize::new(0),
+        read_position: AtomicUsize::new(0),
+    });
+
+    (
+        SampleProducer {
+            shared: Arc::clone(&shared),
+            mask: capacity - 1,
+            write_position: 0,
+        },
+        SampleConsumer {
+            shared,
+            mask: capacity - 1,
+            read_position: 0,
+        },
+    )
+}
+
+pub struct SampleProducer {
+    shared: Arc<Shared>,
+    mask: usize,
+    write_position: usize,
+}
+
+impl SampleProducer {
+    pub fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    pub fn len(&self) -> usize {
+        self.write_position
+            .wrapping_sub(self.shared.read_position.load(Ordering::Acquire))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Total count of samples written
+    pub fn position(&self) -> usize {
+        self.write_position
+    }
+
+    // Returns the count of samples written, less than the input if the buffer is full
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let count = samples.len().min(self.capacity() - self.len());
+
+        for (offset, sample) in samples[..count].iter().enumerate() {
+            self.shared.samples[self.write_position.wrapping_add(offset) & self.mask]
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+
+        self.write_position = self.write_position.wrapping_add(count);
+        self.shared
+            .write_position
+            .store(self.write_position, Ordering::Release);
+
+        count
+    }
+}
+
+pub struct SampleConsumer {
+    shared: Arc<Shared>,
+    mask: usize,
+    read_position: usize,
+}
+
+impl SampleConsumer {
+    pub fn len(&self) -> usize {
+        self.shared
+            .write_position
+            .load(Ordering::Acquire)
+            .wrapping_sub(self.read_position)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // Total count of samples read or skipped
+    pub fn position(&self) -> usize {
+        self.read_position
+    }
+
+    // Returns the count of samples read, less than the output size if the buffer runs out
+    pub fn pop(&mut self, output: &mut [f32]) -> usize {
+        let count = output.len().min(self.len());
+
+        for (offset, sample) in output[..count].iter_mut().enumerate() {
+            *sample = f32::from_bits(
+                self.shared.samples[self.read_position.wrapping_add(offset) & self.mask]
+                    .load(Ordering::Relaxed),
+            );
+        }
+
+        self.skip(count)
+    }
+
+    // Discard samples without reading them. Returns the count of samples discarded.
+    pub fn skip(&mut self, count: usize) -> usize {
+        let count = count.min(self.len());
+
+        self.read_position = self.read_position.wrapping_add(count);
+        self.shared
+            .read_position
+            .store(self.read_position, Ordering::Release);
+
+        count
+    }
+}
+
+struct ItemShared<T> {
+    slots: Box<[UnsafeCell<Option<T>>]>,
+    write_position: AtomicUsize,
+    read_position: AtomicUsize,
+}
+
+// The slots between the read and the write positions belong to the consumer, the others to the
+// producer. Each side publishes a position only after it is done with the slots it hands over.
+unsafe impl<T: Send> Sync for ItemShared<T> {}
+
+// Moving a value never allocates. The values popped are dropped by the consumer, so the audio
+// callback must send back the values that own memory instead of dropping them.
+pub fn item_ring<T>(capacity: usize) -> (ItemProducer<T>, ItemConsumer<T>) {
+    let shared = Arc::new(ItemShared {
+        slots: (0..capacity).map(|_| UnsafeCell::new(None)).collect(),
+        write_position: AtomicUsize::new(0),
+        read_position: AtomicUsize::new(0),
+    });
+
+    (
+        ItemProducer {
+            shared: Arc::clone(&shared),
+            write_position: 0,
+        },
+        ItemConsumer {
+            shared,
+            read_position: 0,
+        },
+    )
+}
+
+pub struct ItemProducer<T> {
+    shared: Arc<ItemShared<T>>,
+    write_position: usize,
+}
+
+impl<T> ItemProducer<T> {
+    pub fn is_full(&self) -> bool {
+        self.write_position
+            .wrapping_sub(self.shared.read_position.load(Ordering::Acquire))
+            == self.shared.slots.len()
+    }
+
+    // Gives the value back if the ring is full
+    pub fn push(&mut self, item: T) -> Result<(), T> {
+        if self.is_full() {
+            return Err(item);
+        }
+
+        let slot = &self.shared.slots[self.write_position % self.shared.slots.len()];
+        // SAFETY: the slot is not between the read and write positions, the consumer does not
+        // access it until the new write position is published
+        unsafe { *slot.get() = Some(item) };
+
+        self.write_position = self.write_position.wrapping_add(1);
+        self.shared
+            .write_position
+            .store(self.write_position, Ordering::Release);
+
+        Ok(())
+    }
+}
+
+pub struct ItemConsumer<T> {
+    shared: Arc<ItemShared<T>>,
+    read_position: usize,
+}
+
+impl<T> ItemConsumer<T> {
+    pub fn pop(&mut self) -> Option<T> {
+        if self.shared.write_position.load(Ordering::Acquire) == self.read_position {
+            return None;
+        }
+
+        let slot = &self.shared.slots[self.read_position % self.shared.slots.len()];
+        // SAFETY: the slot is between the read and write positions, the producer does not access
+        // it until the new read position is published
+        let item = unsafe { (*slot.get()).take() };
+
+        self.read_position = self.read_position.wrapping_add(1);
+        self.shared
+            .read_position
+            .store(self.read_position, Ordering::Release);
+
+        item
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_wrap_around() {
+        let (mut producer, mut consumer) = sample_ring(6);
+        assert_eq!(producer.capacity(), 8);
+
+        let mut output = [0.; 4];
+        for round in 0..5 {
+            let samples = [0., 1., 2., 3., 4., 5.].map(|s| s + round as f32);
+            assert_eq!(producer.push(&samples), 6);
+            // Full
+            assert_eq!(producer.push(&samples), 2);
+
+            assert_eq!(consumer.pop(&mut output), 4);
+            assert_eq!(output, samples[..4]);
+            assert_eq!(consumer.skip(10), 4);
+            assert!(consumer.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_concurrent_order() {
+        let (mut producer, mut consumer) = sample_ring(64);
+
+        let producer_thread = thread::spawn(move || {
+            let mut next = 0;
+            while next < 100_000 {
+                let samples = (next..next + 7).map(|s| s as f32).collect::<Vec<_>>();
+                let count = producer.push(&samples);
+                if count == 0 {
+                    thread::yield_now();
+                }
+                next += count;
+            }
+        });
+
+        let mut expected = 0;
+        let mut output = [0.; 5];
+        while expected < 100_000 {
+            let count = consumer.pop(&mut output);
+            if count == 0 {
+                thread::yield_now();
+            }
+            for &sample in &output[..count] {
+                assert_eq!(sample, expected as f32);
+                expected += 1;
+            }
+        }
+
+        producer_thread.join().unwrap();
+    }
+
+    #[test]
+    fn test_items_are_moved() {
+        let (mut producer, mut consumer) = item_ring(2);
+
+        let producer_thread = thread::spawn(move || {
+            for index in 0..10_000 {
+                let mut item = vec![index];
+                while let Err(returned) = producer.push(item) {
+                    item = returned;
+                    thread::yield_now();
+                }
+            }
+            assert!(!producer.is_full() || producer.push(vec![]).is_err());
+        });
+
+        let mut expected = 0;
+        while expected < 10_000 {
+            if let Some(item) = consumer.pop() {
+                assert_eq!(item, [expected]);
+                expected += 1;
+            } else {
+                thread::yield_now();
+            }
+        }
+
+        producer_thread.join().unwrap();
+        assert!(consumer.pop().is_none());
+    }
+}
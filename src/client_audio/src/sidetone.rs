@@ -0,0 +1,221 @@
+// Sidetone: the user's own voice fed back into the local playback, so that users wearing closed
+// headsets hear themselves. The capture path publishes into a `Sidetone`, either the processed
+// microphone or the outgoing stream as sent. The playback callback adds it to the output without
+// going through the jitter buffer, after the echo reference is taken, so that the echo canceller
+// does not try to remove the user's own voice. The capture path converts the voice to the playback
+// sample rate and writes it into a ring, so that the callback never waits.
+
+use crate::{dsp, sample_ring, FractionalResampler, SampleConsumer, SampleProducer};
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+use vors_share_common::parking_lot::Mutex;
+use vors_share_session::{SidetoneConfig, SidetoneSource};
+
+// The oldest samples are dropped above this delay, so that the latency stays low when the capture
+// delivers frames in bursts
+const MAX_SIDETONE_DELAY_MS: usize = 40;
+// The ring holds the maximum delay up to this playback sample rate
+const MAX_SAMPLE_RATE: usize = 192_000;
+
+// Used only by the capture path
+struct SidetoneInput {
+    producer: SampleProducer,
+    sample_rate: u32,
+    playback_sample_rate: u32,
+    resampler: FractionalResampler,
+    samples: Vec<f32>,
+    resampled: Vec<f32>,
+}
+
+struct SidetoneShared {
+    // Bits of the linear gain
+    gain: AtomicU32,
+    // Set while a playback reads the sidetone, 0 otherwise
+    playback_sample_rate: AtomicU32,
+    input: Mutex<SidetoneInput>,
+    // Taken by the playback
+    consumer: Mutex<Option<SampleConsumer>>,
+}
+
+// Cheap to clone
+#[derive(Clone)]
+pub struct Sidetone {
+    shared: Arc<SidetoneShared>,
+    source: SidetoneSource,
+}
+
+impl Sidetone {
+    pub fn new(config: &SidetoneConfig) -> Self {
+        let (producer, consumer) = sample_ring(MAX_SAMPLE_RATE * MAX_SIDETONE_DELAY_MS / 1000);
+
+        Self {
+            shared: Arc::new(SidetoneShared {
+                gain: AtomicU32::new(dsp::db_to_linear(config.level_db).to_bits()),
+                playback_sample_rate: AtomicU32::new(0),
+                input: Mutex::new(SidetoneInput {
+                    producer,
+                    sample_rate: 0,
+                    playback_sample_rate: 0,
+                    resampler: FractionalResampler::new(1),
+                    samples: vec![],
+                    resampled: vec![],
+                }),
+                consumer: Mutex::new(Some(consumer)),
+            }),
+            source: config.source,
+        }
+    }
+
+    pub fn source(&self) -> SidetoneSource {
+        self.source
+    }
+
+    pub fn set_level_db(&self, level_db: f32) {
+        self.shared
+            .gain
+            .store(dsp::db_to_linear(level_db).to_bits(), Ordering::Relaxed);
+    }
+
+    // Called by the capture path with interleaved samples. Dropped while nothing is playing.
+    pub fn push(&self, samples: &[f32], channels_count: usize, sample_rate: u32) {
+        let playback_sample_rate = self.shared.playback_sample_rate.load(Ordering::Relaxed);
+        if playback_sample_rate == 0 {
+            return;
+        }
+
+        let mut input = self.shared.input.lock();
+        let input = &mut *input;
+
+        if input.sample_rate != sample_rate || input.playback_sample_rate != playback_sample_rate {
+            input.resampler.reset();
+            input.sample_rate = sample_rate;
+            input.playback_sample_rate = playback_sample_rate;
+        }
+
+        input.samples.clear();
+        input.samples.extend(
+            samples
+                .chunks_exact(channels_count)
+                .map(|frame| frame.iter().sum::<f32>() / channels_count as f32),
+        );
+
+        if sample_rate == playback_sample_rate {
+            input.producer.push(&input.samples);
+        } else {
+            input.resampled.clear();
+            input.resampler.process(
+                &input.samples,
+                sample_rate as f64 / playback_sample_rate as f64,
+                &mut input.resampled,
+            );
+            input.producer.push(&input.resampled);
+        }
+    }
+
+    // Taken by the playback path, None if another playback already plays the sidetone
+    pub fn reader(&self, sample_rate: u32) -> Option<SidetoneReader> {
+        let consumer = self.shared.consumer.lock().take()?;
+        self.shared
+            .playback_sample_rate
+            .store(sample_rate, Ordering::Relaxed);
+
+        Some(SidetoneReader {
+            consumer: Some(consumer),
+            shared: Arc::clone(&self.shared),
+            max_len: sample_rate as usize * MAX_SIDETONE_DELAY_MS / 1000,
+        })
+    }
+}
+
+// Owned by the playback callback. The sidetone can be played again once it is dropped.
+pub struct SidetoneReader {
+    consumer: Option<SampleConsumer>,
+    shared: Arc<SidetoneShared>,
+    max_len: usize,
+}
+
+impl SidetoneReader {
+    // Adds the sidetone to an interleaved batch
+    pub fn mix_into(&mut self, batch: &mut [f32], channels_count: usize) {
+        let Some(consumer) = &mut self.consumer else {
+            return;
+        };
+        let gain = f32::from_bits(self.shared.gain.load(Ordering::Relaxed));
+
+        consumer.skip(consumer.len().saturating_sub(self.max_len));
+
+        let mut samples = [0.; 64];
+        for frames in batch.chunks_mut(samples.len() * channels_count) {
+            let count = consumer.pop(&mut samples[..frames.len() / channels_count]);
+            if count == 0 {
+                break;
+            }
+
+            for (frame, sample) in frames
+                .chunks_exact_mut(channels_count)
+                .zip(&samples[..count])
+            {
+                for output in frame {
+                    *output = dsp::soft_clip(*output + sample * gain);
+                }
+            }
+        }
+    }
+}
+
+impl Drop for SidetoneReader {
+    fn drop(&mut self) {
+        self.shared.playback_sample_rate.store(0, Ordering::Relaxed);
+        *self.shared.consumer.lock() = self.consumer.take();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sidetone_latency_is_bounded() {
+        let sidetone = Sidetone::new(&SidetoneConfig {
+            source: SidetoneSource::ProcessedMicrophone,
+            level_db: 0.,
+        });
+
+        // Nothing is kept while nothing plays
+        sidetone.push(&[1.; 10], 1, 1000);
+
+        let mut reader = sidetone.reader(1000).unwrap();
+        assert!(sidetone.reader(1000).is_none());
+
+        let mut batch = [0.; 20];
+        reader.mix_into(&mut batch, 2);
+        assert_eq!(batch, [0.; 20]);
+
+        // Stereo input is downmixed, then duplicated on both output channels
+        for _ in 0..10 {
+            sidetone.push(&[0.5, 0.25], 2, 1000);
+        }
+        reader.mix_into(&mut batch, 2);
+        assert_eq!(batch, [0.375; 20]);
+
+        // A burst longer than the maximum delay is truncated
+        sidetone.push(&[1.; 200], 1, 1000);
+        let mut batch = [0.; 10];
+        for _ in 0..4 {
+            reader.mix_into(&mut batch, 1);
+        }
+        let mut batch = [0.; 10];
+        reader.mix_into(&mut batch, 1);
+        assert_eq!(batch, [0.; 10]);
+
+        // The capture side converts to the playback sample rate
+        drop(reader);
+        let mut reader = sidetone.reader(2000).unwrap();
+        sidetone.push(&[0.5; 20], 1, 1000);
+        let mut batch = [0.; 40];
+        reader.mix_into(&mut batch, 1);
+        assert!(batch[..30].iter().all(|sample| (sample - 0.5).abs() < 1e-5));
+    }
+}
@@ -0,0 +1,306 @@
+// Soundboard: short clips mixed into the outgoing stream. Clips are added after the microphone
+// processing, so that they are not altered by the noise suppression or the voice effects, and do
+// not trigger the voice activity detection. Like the transmit triggers, the triggers are abstract:
+// the local API, a hotkey handler or an OSC message all drive the same `SoundboardControl`.
+
+use crate::{adapt_channels, FractionalResampler};
+use audiopus::{coder::Decoder as OpusDecoder, Channels, SampleRate};
+use hound::{SampleFormat, WavReader};
+use ogg::PacketReader;
+use std::{collections::HashMap, fs::File, io::BufReader, path::Path, sync::Arc};
+use vors_share_common::{parking_lot::Mutex, prelude::*};
+use vors_share_session::SoundboardConfig;
+
+// Longest frame allowed by Opus
+const OPUS_MAX_FRAME_SIZE: usize = 5760;
+
+enum SoundboardRequest {
+    Play(String),
+    StopAll,
+}
+
+struct SoundboardState {
+    config: Option<SoundboardConfig>,
+    // Consumed by the capture loop
+    requests: Vec<SoundboardRequest>,
+}
+
+// Triggers the clips at runtime. Cheap to clone.
+#[derive(Clone)]
+pub struct SoundboardControl(Arc<Mutex<SoundboardState>>);
+
+impl SoundboardControl {
+    pub fn new(config: Option<SoundboardConfig>) -> Self {
+        Self(Arc::new(Mutex::new(SoundboardState {
+            config,
+            requests: vec![],
+        })))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.0.lock().config.is_some()
+    }
+
+    pub fn clip_names(&self) -> Vec<String> {
+        self.0
+            .lock()
+            .config
+            .as_ref()
+            .map(|config| config.clips.iter().map(|(name, _)| name.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    pub fn play(&self, name: &str) -> StrResult {
+        let mut state = self.0.lock();
+
+        let Some(config) = &state.config else {
+            return fmt_e!("The soundboard is disabled");
+        };
+        if !config.clips.iter().any(|(clip_name, _)| clip_name == name) {
+            return fmt_e!("Unknown soundboard clip \"{name}\"");
+        }
+
+        state
+            .requests
+            .push(SoundboardRequest::Play(name.to_owned()));
+
+        Ok(())
+    }
+
+    pub fn stop_all(&self) {
+        self.0.lock().requests.push(SoundboardRequest::StopAll);
+    }
+}
+
+impl Default for SoundboardControl {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+fn read_wav(path: &Path) -> StrResult<(Vec<f32>, u16, u32)> {
+    let mut reader = WavReader::open(path).map_err(err!())?;
+    let spec = reader.spec();
+
+    let samples = if spec.sample_format == SampleFormat::Float {
+        reader
+            .samples::<f32>()
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(err!())?
+    } else {
+        let int_scale = 1. / (1_u64 << (spec.bits_per_sample - 1)) as f32;
+        reader
+            .samples::<i32>()
+            .map(|s| s.map(|s| s as f32 * int_scale))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(err!())?
+    };
+
+    Ok((samples, spec.channels, spec.sample_rate))
+}
+
+fn read_ogg_opus(path: &Path) -> StrResult<(Vec<f32>, u16, u32)> {
+    let mut reader = PacketReader::new(BufReader::new(File::open(path).map_err(err!())?));
+
+    let head = reader
+        .read_packet()
+        .map_err(err!())?
+        .ok_or_else(|| "Empty Ogg file".to_owned())?
+        .data;
+    if head.len() < 19 || &head[0..8] != b"OpusHead" {
+        return fmt_e!("Not an Ogg Opus file");
+    }
+    let channels_count = head[9] as usize;
+    let pre_skip = u16::from_le_bytes([head[10], head[11]]) as usize;
+    let channels = match channels_count {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        _ => return fmt_e!("Ogg Opus files with more than 2 channels are not supported"),
+    };
+
+    // Comment header
+    reader.read_packet().map_err(err!())?;
+
+    let mut decoder = OpusDecoder::new(SampleRate::Hz48000, channels).map_err(err!())?;
+    let mut decoded = vec![0.; OPUS_MAX_FRAME_SIZE * channels_count];
+    let mut samples = vec![];
+    while let Some(packet) = reader.read_packet().map_err(err!())? {
+        let frames_count = decoder
+            .decode_float(
+                Some(packet.data.as_slice().try_into().map_err(err!())?),
+                decoded.as_mut_slice().try_into().map_err(err!())?,
+                false,
+            )
+            .map_err(err!())?;
+        samples.extend_from_slice(&decoded[..frames_count * channels_count]);
+    }
+    samples.drain(..(pre_skip * channels_count).min(samples.len()));
+
+    Ok((samples, channels_count as u16, 48000))
+}
+
+// Decode a WAV or Ogg Opus file to the given format
+pub fn load_clip(path: &Path, sample_rate: u32, channels_count: usize) -> StrResult<Vec<f32>> {
+    let extension = path
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase());
+    let (samples, file_channels_count, file_sample_rate) = match extension.as_deref() {
+        Some("wav") => read_wav(path)?,
+        Some("ogg" | "opus") => read_ogg_opus(path)?,
+        _ => return fmt_e!("Unsupported clip format: {}", path.display()),
+    };
+    if file_channels_count > 2 {
+        return fmt_e!("Clips with more than 2 channels are not supported");
+    }
+
+    let mut samples = adapt_channels(samples, file_channels_count, channels_count as _);
+    if file_sample_rate != sample_rate {
+        // The resampler needs two frames after the last output frame
+        samples.resize(samples.len() + 2 * channels_count, 0.);
+
+        let mut resampled = vec![];
+        FractionalResampler::new(channels_count).process(
+            &samples,
+            file_sample_rate as f64 / sample_rate as f64,
+            &mut resampled,
+        );
+        samples = resampled;
+    }
+
+    Ok(samples)
+}
+
+struct PlayingClip {
+    samples: Arc<[f32]>,
+    volume: f32,
+    position: usize,
+}
+
+// Mixes the triggered clips into the capture frames. Owned by the capture loop.
+pub struct SoundboardPlayer {
+    control: SoundboardControl,
+    max_concurrent_clips: usize,
+    only_while_transmitting: bool,
+    // Decoded at the capture sample rate and channels count, with their volume
+    clips: HashMap<String, (Arc<[f32]>, f32)>,
+    playing: Vec<PlayingClip>,
+}
+
+impl SoundboardPlayer {
+    // Returns None if the soundboard is disabled. Clips that cannot be loaded are skipped.
+    pub fn new(
+        control: SoundboardControl,
+        sample_rate: u32,
+        channels_count: usize,
+    ) -> Option<Self> {
+        let config = control.0.lock().config.clone()?;
+
+        let mut clips = HashMap::new();
+        for (name, clip) in config.clips {
+            match load_clip(Path::new(&clip.path), sample_rate, channels_count) {
+                Ok(samples) => {
+                    clips.insert(name, (samples.into(), clip.volume));
+                }
+                Err(e) => warn!("Cannot load soundboard clip \"{name}\": {e}"),
+            }
+        }
+
+        Some(Self {
+            control,
+            max_concurrent_clips: config.max_concurrent_clips.max(1),
+            only_while_transmitting: config.only_while_transmitting,
+            clips,
+            playing: vec![],
+        })
+    }
+
+    // Mix the playing clips into a capture frame. When not transmitting, the microphone audio is
+    // replaced with the clips. Returns true if the frame contains clips and must be sent.
+    pub fn process(&mut self, frame: &mut [f32], transmitting: bool) -> bool {
+        let requests = std::mem::take(&mut self.control.0.lock().requests);
+        for request in requests {
+            match request {
+                SoundboardRequest::Play(name) => {
+                    if let Some((samples, volume)) = self.clips.get(&name) {
+                        if self.playing.len() >= self.max_concurrent_clips {
+                            self.playing.remove(0);
+                        }
+                        self.playing.push(PlayingClip {
+                            samples: Arc::clone(samples),
+                            volume: *volume,
+                            position: 0,
+                        });
+                    }
+                }
+                SoundboardRequest::StopAll => self.playing.clear(),
+            }
+        }
+
+        if self.playing.is_empty() || (self.only_while_transmitting && !transmitting) {
+            self.playing.clear();
+            return false;
+        }
+
+        if !transmitting {
+            frame.fill(0.);
+        }
+
+        for clip in &mut self.playing {
+            let remaining = &clip.samples[clip.position..];
+            for (output, sample) in frame.iter_mut().zip(remaining) {
+                *output += sample * clip.volume;
+            }
+            clip.position += frame.len().min(remaining.len());
+        }
+        self.playing
+            .retain(|clip| clip.position < clip.samples.len());
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use vors_share_session::SoundboardClipConfig;
+
+    #[test]
+    fn test_mix_clips() {
+        let control = SoundboardControl::new(Some(SoundboardConfig {
+            clips: vec![(
+                "beep".into(),
+                SoundboardClipConfig {
+                    path: "".into(),
+                    volume: 0.5,
+                },
+            )],
+            max_concurrent_clips: 1,
+            only_while_transmitting: false,
+        }));
+        let mut player = SoundboardPlayer {
+            control: control.clone(),
+            max_concurrent_clips: 1,
+            only_while_transmitting: false,
+            clips: [("beep".to_owned(), (vec![1.; 6].into(), 0.5))].into(),
+            playing: vec![],
+        };
+        assert!(control.is_enabled());
+        assert!(control.play("unknown").is_err());
+        assert!(!SoundboardControl::new(None).is_enabled());
+
+        let mut frame = [0.1; 4];
+        assert!(!player.process(&mut frame, true));
+        assert_eq!(frame, [0.1; 4]);
+
+        // The microphone is replaced while not transmitting. The second trigger restarts the clip.
+        control.play("beep").unwrap();
+        control.play("beep").unwrap();
+        assert!(player.process(&mut frame, false));
+        assert_eq!(frame, [0.5; 4]);
+
+        let mut frame = [0.1; 4];
+        assert!(player.process(&mut frame, true));
+        assert_eq!(frame, [0.6, 0.6, 0.1, 0.1]);
+        assert!(!player.process(&mut frame, true));
+    }
+}
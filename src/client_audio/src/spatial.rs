@@ -0,0 +1,391 @@
+// Positional voice. Each remote speaker is rendered to stereo according to its pose relative to
+// the head of the listener: the distance sets the attenuation, the direction sets either a simple
+// constant power pan or, with the head model enabled, the interaural time and level differences of a
+// spherical head model (Woodworth delay and Brown-Duda head shadow filter).
+// The propagation delay is intentionally not modeled: a moving speaker would be heard with a pitch
+// shift (Doppler effect), which is unpleasant on voice. All parameters are smoothed so that pose
+// updates at a low rate do not produce zipper noise.
+
+use crate::{dsp, AudioProcessor, SpeakerId};
+use std::{
+    collections::HashMap,
+    f32::consts::{FRAC_PI_4, PI, SQRT_2},
+    sync::Arc,
+};
+use vors_share_common::{glam::Vec3, parking_lot::Mutex, Pose};
+use vors_share_session::{DistanceRolloffModel, SpatialAudioConfig};
+
+const SPEED_OF_SOUND: f32 = 343.;
+const HEAD_RADIUS: f32 = 0.0875;
+
+// Parameters of the Brown-Duda head shadow model
+const SHADOW_MIN_ALPHA: f32 = 0.1;
+const SHADOW_MIN_ANGLE: f32 = 150. * PI / 180.;
+
+// Time constant of the parameter smoothing
+const SMOOTHING_MS: f32 = 50.;
+
+// Must hold the largest interaural delay (~0.7ms) at the highest sample rate
+const HISTORY_SIZE: usize = 128;
+
+#[derive(Default)]
+struct SceneState {
+    listener: Pose,
+    speakers: HashMap<SpeakerId, Pose>,
+}
+
+// Poses of the listener head and of the remote speakers, updated by the application
+#[derive(Clone, Default)]
+pub struct SpatialScene(Arc<Mutex<SceneState>>);
+
+impl SpatialScene {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_listener_pose(&self, pose: Pose) {
+        self.0.lock().listener = pose;
+    }
+
+    pub fn set_speaker_pose(&self, id: SpeakerId, pose: Pose) {
+        self.0.lock().speakers.insert(id, pose);
+    }
+
+    pub fn remove_speaker(&self, id: SpeakerId) {
+        self.0.lock().speakers.remove(&id);
+    }
+
+    // Position of the speaker in the head space of the listener (-Z forward, +X right, +Y up).
+    // None if the pose of the speaker is unknown.
+    pub fn relative_position(&self, id: SpeakerId) -> Option<Vec3> {
+        let state = self.0.lock();
+        let speaker = state.speakers.get(&id)?;
+
+        Some(state.listener.orientation.conjugate() * (speaker.position - state.listener.position))
+    }
+}
+
+pub fn distance_gain(config: &SpatialAudioConfig, distance: f32) -> f32 {
+    let reference = config.reference_distance_m.max(0.01);
+    let max = config.max_distance_m.max(reference);
+    let distance = distance.clamp(reference, max);
+
+    match config.rolloff_model {
+        DistanceRolloffModel::Linear => {
+            if max > reference {
+                (1. - config.rolloff_factor * (distance - reference) / (max - reference))
+                    .clamp(0., 1.)
+            } else {
+                1.
+            }
+        }
+        DistanceRolloffModel::Inverse => {
+            reference / (reference + config.rolloff_factor * (distance - reference))
+        }
+        DistanceRolloffModel::Exponential => (distance / reference).powf(-config.rolloff_factor),
+    }
+}
+
+#[derive(Clone, Copy, Default)]
+struct EarParameters {
+    gain: f32,
+    // In samples
+    delay: f32,
+    // High frequency gain of the head shadow filter. 1 is a flat response.
+    shadow_alpha: f32,
+}
+
+impl EarParameters {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        Self {
+            gain: self.gain + (other.gain - self.gain) * t,
+            delay: self.delay + (other.delay - self.delay) * t,
+            shadow_alpha: self.shadow_alpha + (other.shadow_alpha - self.shadow_alpha) * t,
+        }
+    }
+}
+
+#[derive(Default)]
+struct Ear {
+    parameters: EarParameters,
+    filter_input: f32,
+    filter_output: f32,
+}
+
+pub struct SpatialRenderer {
+    config: SpatialAudioConfig,
+    scene: SpatialScene,
+    speaker_id: SpeakerId,
+    sample_rate: u32,
+    history: Vec<f32>,
+    history_cursor: usize,
+    ears: [Ear; 2],
+    initialized: bool,
+    mono_buffer: Vec<f32>,
+}
+
+impl SpatialRenderer {
+    pub fn new(
+        config: &SpatialAudioConfig,
+        scene: SpatialScene,
+        speaker_id: SpeakerId,
+        sample_rate: u32,
+    ) -> Self {
+        Self {
+            config: config.clone(),
+            scene,
+            speaker_id,
+            sample_rate,
+            history: vec![0.; HISTORY_SIZE],
+            history_cursor: 0,
+            ears: Default::default(),
+            initialized: false,
+            mono_buffer: vec![],
+        }
+    }
+
+    fn shadow_alpha(ear_angle: f32) -> f32 {
+        (1. + SHADOW_MIN_ALPHA / 2.)
+            + (1. - SHADOW_MIN_ALPHA / 2.) * (ear_angle / SHADOW_MIN_ANGLE * PI).cos()
+    }
+
+    fn target_parameters(&self) -> [EarParameters; 2] {
+        let Some(position) = self.scene.relative_position(self.speaker_id) else {
+            // Unknown pose, render at the center
+            return [EarParameters {
+                gain: 1.,
+                delay: 0.,
+                shadow_alpha: 1.,
+            }; 2];
+        };
+
+        let distance = position.length();
+        let direction = if distance > 1e-3 {
+            position / distance
+        } else {
+            -Vec3::Z
+        };
+        let gain = distance_gain(&self.config, distance);
+
+        // Sine of the angle between the direction and the median plane
+        let lateral = direction.x.clamp(-1., 1.);
+
+        if self.config.head_model {
+            let lateral_angle = lateral.asin().abs();
+            let delay = HEAD_RADIUS / SPEED_OF_SOUND
+                * (lateral_angle + lateral_angle.sin())
+                * self.sample_rate as f32;
+
+            let (left_delay, right_delay) = if lateral > 0. {
+                (delay, 0.)
+            } else {
+                (0., delay)
+            };
+
+            [
+                EarParameters {
+                    gain,
+                    delay: left_delay,
+                    shadow_alpha: Self::shadow_alpha((-lateral).acos()),
+                },
+                EarParameters {
+                    gain,
+                    delay: right_delay,
+                    shadow_alpha: Self::shadow_alpha(lateral.acos()),
+                },
+            ]
+        } else {
+            // Constant power pan law, normalized to unity gain at the center
+            let angle = (lateral + 1.) * FRAC_PI_4;
+
+            [angle.cos(), angle.sin()].map(|pan_gain| EarParameters {
+                gain: gain * pan_gain * SQRT_2,
+                delay: 0.,
+                shadow_alpha: 1.,
+            })
+        }
+    }
+}
+
+impl AudioProcessor for SpatialRenderer {
+    fn name(&self) -> &'static str {
+        "spatial_audio"
+    }
+
+    fn channels_count(&self) -> usize {
+        2
+    }
+
+    fn reset(&mut self) {
+        self.history.fill(0.);
+        self.ears = Default::default();
+        self.initialized = false;
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        dsp::downmix_to_mono(frame, 2, &mut self.mono_buffer);
+        let frames_count = self.mono_buffer.len();
+
+        let targets = self.target_parameters();
+        let frame_ms = frames_count as f32 * 1000. / self.sample_rate as f32;
+        let smoothing = 1. - (-frame_ms / SMOOTHING_MS).exp();
+
+        let mut start_parameters = [EarParameters::default(); 2];
+        let mut end_parameters = [EarParameters::default(); 2];
+        for (index, (ear, target)) in self.ears.iter_mut().zip(targets).enumerate() {
+            if !self.initialized {
+                ear.parameters = target;
+            }
+            start_parameters[index] = ear.parameters;
+            ear.parameters = ear.parameters.lerp(&target, smoothing);
+            end_parameters[index] = ear.parameters;
+        }
+        self.initialized = true;
+
+        // Bilinear transform of the head shadow filter H(s) = (alpha * s + beta) / (s + beta)
+        let beta = 2. * SPEED_OF_SOUND / HEAD_RADIUS;
+        let k = 2. * self.sample_rate as f32;
+
+        for (n, (&input, output)) in self
+            .mono_buffer
+            .iter()
+            .zip(frame.chunks_exact_mut(2))
+            .enumerate()
+        {
+            self.history_cursor = (self.history_cursor + 1) % HISTORY_SIZE;
+            self.history[self.history_cursor] = input;
+
+            let t = (n + 1) as f32 / frames_count as f32;
+            for ((ear, output), (start, end)) in self
+                .ears
+                .iter_mut()
+                .zip(output)
+                .zip(start_parameters.iter().zip(&end_parameters))
+            {
+                let parameters = start.lerp(end, t);
+
+                // Fractional delay with linear interpolation
+                let delay = parameters.delay.clamp(0., (HISTORY_SIZE - 2) as f32);
+                let whole = delay as usize;
+                let fraction = delay - whole as f32;
+                let newer =
+                    self.history[(self.history_cursor + HISTORY_SIZE - whole) % HISTORY_SIZE];
+                let older =
+                    self.history[(self.history_cursor + HISTORY_SIZE - whole - 1) % HISTORY_SIZE];
+                let delayed = newer + (older - newer) * fraction;
+
+                let b0 = (beta + parameters.shadow_alpha * k) / (beta + k);
+                let b1 = (beta - parameters.shadow_alpha * k) / (beta + k);
+                let a1 = (beta - k) / (beta + k);
+                let filtered = b0 * delayed + b1 * ear.filter_input - a1 * ear.filter_output;
+                ear.filter_input = delayed;
+                ear.filter_output = filtered;
+
+                *output = filtered * parameters.gain;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 48000;
+    const FRAME_SIZE: usize = 480;
+
+    fn render(renderer: &mut SpatialRenderer, frames_count: usize) -> Vec<f32> {
+        // Deterministic white noise
+        let mut seed = 1_u32;
+        let mut output = vec![];
+        for _ in 0..frames_count {
+            let mut frame = (0..FRAME_SIZE)
+                .flat_map(|_| {
+                    seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+                    let sample = (seed >> 8) as f32 / (1 << 24) as f32 - 0.5;
+                    [sample, sample]
+                })
+                .collect::<Vec<_>>();
+            renderer.process(&mut frame);
+            output.extend(frame);
+        }
+
+        output
+    }
+
+    fn channel(samples: &[f32], index: usize) -> Vec<f32> {
+        samples.iter().skip(index).step_by(2).copied().collect()
+    }
+
+    fn config(head_model: bool) -> SpatialAudioConfig {
+        SpatialAudioConfig {
+            rolloff_model: DistanceRolloffModel::Inverse,
+            reference_distance_m: 1.,
+            max_distance_m: 50.,
+            rolloff_factor: 1.,
+            head_model,
+        }
+    }
+
+    fn pose_at(position: Vec3) -> Pose {
+        Pose {
+            position,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_distance_and_pan() {
+        let scene = SpatialScene::new();
+        let mut renderer = SpatialRenderer::new(&config(false), scene.clone(), 1, SAMPLE_RATE);
+
+        scene.set_speaker_pose(1, pose_at(Vec3::new(0., 0., -1.)));
+        let near = render(&mut renderer, 20);
+        scene.set_speaker_pose(1, pose_at(Vec3::new(0., 0., -4.)));
+        let far = render(&mut renderer, 50);
+        let steady = far.len() / 2..;
+        let attenuation =
+            dsp::power_db(&channel(&near, 0)) - dsp::power_db(&channel(&far[steady], 0));
+        assert!((attenuation - 12.).abs() < 0.5, "{attenuation}");
+
+        // Turning the head to the left puts the speaker on the right
+        scene.set_listener_pose(Pose {
+            orientation: vors_share_common::glam::Quat::from_rotation_y(PI / 2.),
+            ..Default::default()
+        });
+        let turned = render(&mut renderer, 50);
+        let steady = &turned[turned.len() / 2..];
+        assert!(dsp::power_db(&channel(steady, 1)) - dsp::power_db(&channel(steady, 0)) > 20.);
+    }
+
+    #[test]
+    fn test_head_model_interaural_delay() {
+        let scene = SpatialScene::new();
+        scene.set_speaker_pose(1, pose_at(Vec3::new(2., 0., 0.)));
+        let mut renderer = SpatialRenderer::new(&config(true), scene, 1, SAMPLE_RATE);
+
+        let output = render(&mut renderer, 20);
+        let left = channel(&output, 0);
+        let right = channel(&output, 1);
+
+        // The left ear receives the sound later
+        let correlation = |lag: usize| -> f32 {
+            left[lag..]
+                .iter()
+                .zip(&right)
+                .map(|(l, r)| l * r)
+                .sum::<f32>()
+        };
+        let best_lag = (0..60)
+            .max_by(|a, b| correlation(*a).total_cmp(&correlation(*b)))
+            .unwrap();
+        let expected = HEAD_RADIUS / SPEED_OF_SOUND * (PI / 2. + 1.) * SAMPLE_RATE as f32;
+        assert!(
+            (best_lag as f32 - expected).abs() < 2.,
+            "{best_lag} {expected}"
+        );
+
+        // Head shadow: the far ear is quieter
+        assert!(dsp::power_db(&right) - dsp::power_db(&left) > 3.);
+    }
+}
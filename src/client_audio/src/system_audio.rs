@@ -0,0 +1,172 @@
+// Sharing of the audio played by this computer (music, a video, a game), as a stream separate from
+// the voice. It skips the voice processing and is encoded with Opus settings tuned for music:
+// stereo, full band and a higher bitrate. Receivers mix it as its own speaker, so that its volume
+// is controlled independently from the voice of the participant sharing it.
+
+use crate::{start_capture, AudioDevice, FractionalResampler, SpeakerId};
+use audiopus::{
+    coder::{Decoder as OpusDecoder, Encoder as OpusEncoder},
+    Application, Bitrate, Channels, SampleRate,
+};
+use std::sync::mpsc as smpsc;
+use tokio::sync::mpsc as tmpsc;
+use vors_share_common::prelude::*;
+use vors_share_packets::SystemAudioPacketHeader;
+use vors_share_session::SystemAudioShareConfig;
+use vors_share_sockets::StreamSender;
+
+const SYSTEM_AUDIO_SAMPLE_RATE: u32 = 48000;
+const SYSTEM_AUDIO_CHANNELS_COUNT: usize = 2;
+const SYSTEM_AUDIO_FRAME_MS: u64 = 20;
+const OPUS_MAX_PACKET_SIZE: usize = 4000;
+// Longest frame allowed by Opus
+const OPUS_MAX_FRAME_SIZE: usize = 5760;
+
+// Set on the mixer id of the system audio of a speaker, so that it does not collide with the id of
+// its voice
+const SYSTEM_AUDIO_SPEAKER_FLAG: SpeakerId = 1 << 63;
+
+// Mixer id of the system audio shared by a speaker, used to set its controls
+pub fn system_audio_speaker_id(speaker: SpeakerId) -> SpeakerId {
+    speaker | SYSTEM_AUDIO_SPEAKER_FLAG
+}
+
+// Capture, encode and send the system audio until the stream is closed. The device is usually
+// created with AudioDevice::new_loopback().
+pub async fn share_system_audio_loop(
+    device: AudioDevice,
+    config: SystemAudioShareConfig,
+    mut sender: StreamSender<SystemAudioPacketHeader>,
+) -> StrResult {
+    let (data_sender, mut data_receiver) = tmpsc::unbounded_channel::<StrResult<Vec<f32>>>();
+    let (_shutdown_notifier, shutdown_receiver) = smpsc::channel::<()>();
+
+    let sample_rate = start_capture(
+        device,
+        SYSTEM_AUDIO_CHANNELS_COUNT as _,
+        false,
+        data_sender,
+        None,
+        shutdown_receiver,
+    )?;
+
+    let mut encoder = OpusEncoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio)
+        .map_err(err!())?;
+    encoder
+        .set_bitrate(Bitrate::BitsPerSecond(config.bitrate_kbps as i32 * 1000))
+        .map_err(err!())?;
+
+    let mut resampler = (sample_rate != SYSTEM_AUDIO_SAMPLE_RATE).then(|| {
+        (
+            FractionalResampler::new(SYSTEM_AUDIO_CHANNELS_COUNT),
+            sample_rate as f64 / SYSTEM_AUDIO_SAMPLE_RATE as f64,
+        )
+    });
+
+    let frame_samples_count = (SYSTEM_AUDIO_SAMPLE_RATE as u64 * SYSTEM_AUDIO_FRAME_MS / 1000)
+        as usize
+        * SYSTEM_AUDIO_CHANNELS_COUNT;
+    let mut pending_samples = vec![];
+
+    while let Some(maybe_data) = data_receiver.recv().await {
+        let data = maybe_data?;
+        if let Some((resampler, ratio)) = &mut resampler {
+            resampler.process(&data, *ratio, &mut pending_samples);
+        } else {
+            pending_samples.extend(data);
+        }
+
+        while pending_samples.len() >= frame_samples_count {
+            let mut packet = vec![0; OPUS_MAX_PACKET_SIZE];
+            let size = encoder
+                .encode_float(&pending_samples[..frame_samples_count], &mut packet)
+                .map_err(err!())?;
+            packet.truncate(size);
+            pending_samples.drain(..frame_samples_count);
+
+            sender.send(&SystemAudioPacketHeader, packet).await.ok();
+        }
+    }
+
+    Ok(())
+}
+
+// Decodes the system audio packets to the sample rate and channels count of the mixer
+pub struct SystemAudioDecoder {
+    decoder: OpusDecoder,
+    channels_count: usize,
+    resampler: Option<(FractionalResampler, f64)>,
+    decoded: Vec<f32>,
+}
+
+impl SystemAudioDecoder {
+    pub fn new(sample_rate: u32, channels_count: usize) -> StrResult<Self> {
+        let channels = match channels_count {
+            1 => Channels::Mono,
+            2 => Channels::Stereo,
+            _ => return fmt_e!("System audio requires a mono or stereo output"),
+        };
+
+        Ok(Self {
+            decoder: OpusDecoder::new(SampleRate::Hz48000, channels).map_err(err!())?,
+            channels_count,
+            resampler: (sample_rate != SYSTEM_AUDIO_SAMPLE_RATE).then(|| {
+                (
+                    FractionalResampler::new(channels_count),
+                    SYSTEM_AUDIO_SAMPLE_RATE as f64 / sample_rate as f64,
+                )
+            }),
+            decoded: vec![0.; OPUS_MAX_FRAME_SIZE * channels_count],
+        })
+    }
+
+    // Appends the decoded samples
+    pub fn decode(&mut self, packet: &[u8], samples: &mut Vec<f32>) -> StrResult {
+        let frames_count = self
+            .decoder
+            .decode_float(
+                Some(packet.try_into().map_err(err!())?),
+                self.decoded.as_mut_slice().try_into().map_err(err!())?,
+                false,
+            )
+            .map_err(err!())?;
+        let decoded = &self.decoded[..frames_count * self.channels_count];
+
+        if let Some((resampler, ratio)) = &mut self.resampler {
+            resampler.process(decoded, *ratio, samples);
+        } else {
+            samples.extend_from_slice(decoded);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_to_mixer_sample_rate() {
+        let encoder =
+            OpusEncoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio).unwrap();
+        let frame = (0..960)
+            .flat_map(|i| {
+                let sample = (i as f32 * 0.05).sin() * 0.5;
+                [sample, sample]
+            })
+            .collect::<Vec<_>>();
+        let mut packet = vec![0; OPUS_MAX_PACKET_SIZE];
+        let size = encoder.encode_float(&frame, &mut packet).unwrap();
+        packet.truncate(size);
+
+        let mut decoder = SystemAudioDecoder::new(44100, 2).unwrap();
+        let mut samples = vec![];
+        for _ in 0..10 {
+            decoder.decode(&packet, &mut samples).unwrap();
+        }
+
+        // 200 ms at 44.1 kHz, within the interpolation delay of the resampler
+        assert!((samples.len() as i64 / 2 - 8820).abs() <= 4);
+    }
+}
@@ -0,0 +1,357 @@
+// Output taps. The mixer publishes the decoded voice of each speaker and the mixed output, and
+// external consumers (WebSockets, named pipes, UDP) subscribe to the tracks they need. The audio
+// callback only copies the frames into rings, a publisher thread sends them to the subscribers.
+// Nothing is allocated while there are no subscribers.
+
+use crate::{item_ring, sample_ring, ItemProducer, SampleProducer, SpeakerId};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    sync::{Arc, Weak},
+    thread,
+    time::Duration,
+};
+use tokio::{
+    net::{self, UdpSocket},
+    sync::broadcast,
+};
+use vors_share_common::prelude::*;
+use vors_share_session::UdpAudioTapConfig;
+
+const TAP_BROADCAST_CAPACITY: usize = 256;
+
+// Room of the rings between the audio callback and the publisher thread, in batches of samples of
+// all tracks together. The frames that do not fit are dropped.
+const TAP_RING_BATCHES_COUNT: usize = 32;
+const TAP_RING_FRAMES_COUNT: usize = 256;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub enum TapTrack {
+    Mix,
+    Speaker(SpeakerId),
+}
+
+impl TapTrack {
+    // Used to name files and pipes
+    pub fn file_stem(&self) -> String {
+        match self {
+            TapTrack::Mix => "mix".into(),
+            TapTrack::Speaker(id) => format!("speaker_{id}"),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TapFrame {
+    pub track: TapTrack,
+    // Playback position of the first frame, counted in frames since the mixer creation. Frames of
+    // different tracks with the same position are played at the same time.
+    pub position_frames: u64,
+    pub sample_rate: u32,
+    pub channels_count: u16,
+    // Interleaved
+    pub samples: Arc<[f32]>,
+}
+
+impl TapFrame {
+    // Interleaved 16 bit little endian PCM
+    pub fn to_pcm_bytes(&self) -> Vec<u8> {
+        self.samples
+            .iter()
+            .flat_map(|s| {
+                let sample = (s.clamp(-1., 1.) * i16::MAX as f32) as i16;
+                sample.to_le_bytes()
+            })
+            .collect()
+    }
+}
+
+#[derive(Clone)]
+pub struct AudioTap(broadcast::Sender<TapFrame>);
+
+impl AudioTap {
+    pub fn new() -> Self {
+        Self(broadcast::channel(TAP_BROADCAST_CAPACITY).0)
+    }
+
+    // Sender side of the broadcast channel, to plug in helpers that subscribe by themselves
+    pub fn sender(&self) -> broadcast::Sender<TapFrame> {
+        self.0.clone()
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<TapFrame> {
+        self.0.subscribe()
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.0.receiver_count() > 0
+    }
+
+    pub fn publish(
+        &self,
+        track: TapTrack,
+        position_frames: u64,
+        sample_rate: u32,
+        channels_count: u16,
+        samples: &[f32],
+    ) {
+        if self.is_active() {
+            self.0
+                .send(TapFrame {
+                    track,
+                    position_frames,
+                    sample_rate,
+                    channels_count,
+                    samples: samples.into(),
+                })
+                .ok();
+        }
+    }
+}
+
+impl AudioTap {
+    // Starts the thread that publishes the frames written by the audio callback, every period,
+    // until the guard is dropped
+    pub fn spawn_writer(
+        &self,
+        batch_samples_count: usize,
+        period: Duration,
+    ) -> (TapWriter, TapPublisherGuard) {
+        let (samples_producer, mut samples_consumer) =
+            sample_ring(batch_samples_count * TAP_RING_BATCHES_COUNT);
+        let (headers_producer, mut headers_consumer) =
+            item_ring::<TapFrameHeader>(TAP_RING_FRAMES_COUNT);
+        let guard = Arc::new(());
+
+        let tap = self.clone();
+        let alive = Arc::downgrade(&guard);
+        thread::spawn(move || {
+            while Weak::strong_count(&alive) > 0 {
+                thread::sleep(period);
+
+                while let Some(header) = headers_consumer.pop() {
+                    if tap.is_active() {
+                        let mut samples = vec![0.; header.samples_count];
+                        samples_consumer.pop(&mut samples);
+
+                        tap.0
+                            .send(TapFrame {
+                                track: header.track,
+                                position_frames: header.position_frames,
+                                sample_rate: header.sample_rate,
+                                channels_count: header.channels_count,
+                                samples: samples.into(),
+                            })
+                            .ok();
+                    } else {
+                        samples_consumer.skip(header.samples_count);
+                    }
+                }
+            }
+        });
+
+        (
+            TapWriter {
+                samples: samples_producer,
+                headers: headers_producer,
+            },
+            TapPublisherGuard { _alive: guard },
+        )
+    }
+}
+
+struct TapFrameHeader {
+    track: TapTrack,
+    position_frames: u64,
+    sample_rate: u32,
+    channels_count: u16,
+    samples_count: usize,
+}
+
+// Owned by the audio callback
+pub struct TapWriter {
+    samples: SampleProducer,
+    headers: ItemProducer<TapFrameHeader>,
+}
+
+impl TapWriter {
+    // The frame is dropped if the publisher thread lags behind
+    pub fn write(
+        &mut self,
+        track: TapTrack,
+        position_frames: u64,
+        sample_rate: u32,
+        channels_count: u16,
+        samples: &[f32],
+    ) {
+        if self.headers.is_full() || self.samples.capacity() - self.samples.len() < samples.len() {
+            return;
+        }
+
+        // The samples are available before the header is seen by the publisher
+        self.samples.push(samples);
+        self.headers
+            .push(TapFrameHeader {
+                track,
+                position_frames,
+                sample_rate,
+                channels_count,
+                samples_count: samples.len(),
+            })
+            .ok();
+    }
+}
+
+// Keeps the publisher thread alive
+pub struct TapPublisherGuard {
+    _alive: Arc<()>,
+}
+
+impl Default for AudioTap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Returns None when the channel is closed. Lost frames are skipped.
+async fn next_frame(receiver: &mut broadcast::Receiver<TapFrame>) -> Option<TapFrame> {
+    loop {
+        match receiver.recv().await {
+            Ok(frame) => return Some(frame),
+            Err(broadcast::error::RecvError::Lagged(count)) => {
+                warn!("Audio tap is too slow, {count} frames have been dropped")
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+// Send each track to its own port as raw PCM
+pub async fn udp_tap_loop(tap: AudioTap, config: UdpAudioTapConfig) -> StrResult {
+    // Resolved once, only the port changes between the tracks
+    let mut address = net::lookup_host((config.host.as_str(), 0))
+        .await
+        .map_err(err!())?
+        .next()
+        .ok_or_else(|| format!("Cannot resolve the audio tap host \"{}\"", config.host))?;
+    let local_ip: IpAddr = if address.is_ipv6() {
+        Ipv6Addr::UNSPECIFIED.into()
+    } else {
+        Ipv4Addr::UNSPECIFIED.into()
+    };
+
+    let socket = UdpSocket::bind((local_ip, 0)).await.map_err(err!())?;
+    let mut receiver = tap.subscribe();
+
+    while let Some(frame) = next_frame(&mut receiver).await {
+        let port = match frame.track {
+            TapTrack::Mix => Some(config.mix_port),
+            TapTrack::Speaker(id) => u16::try_from(id)
+                .ok()
+                .and_then(|id| config.speaker_base_port.checked_add(id)),
+        };
+
+        if let Some(port) = port {
+            address.set_port(port);
+            socket.send_to(&frame.to_pcm_bytes(), address).await.ok();
+        }
+    }
+
+    Ok(())
+}
+
+// Write each track to its own named pipe. A pipe is only written while a reader has it open,
+// otherwise the frames are discarded.
+#[cfg(unix)]
+pub async fn fifo_tap_loop(tap: AudioTap, directory: std::path::PathBuf) -> StrResult {
+    use std::{
+        collections::{hash_map::Entry, HashMap},
+        ffi::CString,
+        os::unix::ffi::OsStrExt,
+    };
+    use tokio::{io::AsyncWriteExt, net::unix::pipe};
+
+    let mut senders = HashMap::<TapTrack, pipe::Sender>::new();
+    let mut receiver = tap.subscribe();
+
+    while let Some(frame) = next_frame(&mut receiver).await {
+        if let Entry::Vacant(entry) = senders.entry(frame.track) {
+            let path = directory.join(format!("{}.pcm", frame.track.file_stem()));
+            if !path.exists() {
+                let c_path = CString::new(path.as_os_str().as_bytes()).map_err(err!())?;
+                if unsafe { libc::mkfifo(c_path.as_ptr(), 0o644) } != 0 {
+                    return fmt_e!(
+                        "Cannot create the named pipe {}: {}",
+                        path.display(),
+                        std::io::Error::last_os_error()
+                    );
+                }
+            }
+
+            // Fails if there is no reader yet
+            if let Ok(sender) = pipe::OpenOptions::new().open_sender(&path) {
+                entry.insert(sender);
+            }
+        }
+
+        if let Some(sender) = senders.get_mut(&frame.track) {
+            if sender.write_all(&frame.to_pcm_bytes()).await.is_err() {
+                // The reader went away. The pipe is opened again on the next frames.
+                senders.remove(&frame.track);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn fifo_tap_loop(_: AudioTap, _: std::path::PathBuf) -> StrResult {
+    fmt_e!("Named pipe audio taps are not supported on this platform")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_udp_tap() {
+        let listener = UdpSocket::bind(("127.0.0.1", 0)).await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        // Both routes lead to the listener, whatever its port
+        let tap = AudioTap::new();
+        tokio::spawn(udp_tap_loop(
+            tap.clone(),
+            UdpAudioTapConfig {
+                host: "127.0.0.1".into(),
+                mix_port: port,
+                speaker_base_port: 0,
+            },
+        ));
+
+        // Wait for the loop to subscribe
+        while !tap.is_active() {
+            tokio::task::yield_now().await;
+        }
+
+        // Out of the port range, dropped
+        tap.publish(TapTrack::Speaker(u64::MAX), 0, 48000, 1, &[1.; 4]);
+        tap.publish(TapTrack::Mix, 0, 48000, 1, &[0.; 4]);
+        tap.publish(
+            TapTrack::Speaker(port as _),
+            0,
+            48000,
+            1,
+            &[0.5, -1., 0., 1.],
+        );
+
+        let mut buffer = [0; 64];
+        let size = listener.recv(&mut buffer).await.unwrap();
+        assert_eq!(buffer[..size], [0; 8]);
+
+        let size = listener.recv(&mut buffer).await.unwrap();
+        assert_eq!(size, 8);
+        assert_eq!(i16::from_le_bytes([buffer[2], buffer[3]]), -i16::MAX);
+    }
+}
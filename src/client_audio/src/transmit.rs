@@ -0,0 +1,234 @@
+// Transmit mode controller. It decides whether captured frames are sent, combining the selected
+// mode with the voice activity detection and with external triggers. Triggers are abstract: a
+// hotkey handler, the local REST API or an OSC message all drive the same `TransmitTrigger`.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use vors_share_common::parking_lot::Mutex;
+use vors_share_session::{TransmitConfig, TransmitMode};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug)]
+pub enum TransmitTriggerAction {
+    Press,
+    Release,
+    Toggle,
+}
+
+struct TriggerState {
+    mode: TransmitMode,
+    pressed: bool,
+    toggled_on: bool,
+    // Cancels the release tail of the controller
+    reset: bool,
+}
+
+#[derive(Clone)]
+pub struct TransmitTrigger(Arc<Mutex<TriggerState>>);
+
+impl TransmitTrigger {
+    pub fn new(mode: TransmitMode) -> Self {
+        Self(Arc::new(Mutex::new(TriggerState {
+            mode,
+            pressed: false,
+            toggled_on: false,
+            reset: false,
+        })))
+    }
+
+    pub fn set_mode(&self, mode: TransmitMode) {
+        let mut state = self.0.lock();
+        state.mode = mode;
+        state.pressed = false;
+        state.toggled_on = false;
+        state.reset = true;
+    }
+
+    pub fn mode(&self) -> TransmitMode {
+        self.0.lock().mode
+    }
+
+    pub fn press(&self) {
+        let mut state = self.0.lock();
+
+        // Ignore key repeats
+        if !state.pressed {
+            state.pressed = true;
+            if matches!(state.mode, TransmitMode::Toggle) {
+                state.toggled_on = !state.toggled_on;
+            }
+        }
+    }
+
+    pub fn release(&self) {
+        self.0.lock().pressed = false;
+    }
+
+    // Press and release in one go. Useful for sources that have no notion of key up, like a button
+    // in the dashboard.
+    pub fn toggle(&self) {
+        self.press();
+        self.release();
+    }
+
+    pub fn apply(&self, action: TransmitTriggerAction) {
+        match action {
+            TransmitTriggerAction::Press => self.press(),
+            TransmitTriggerAction::Release => self.release(),
+            TransmitTriggerAction::Toggle => self.toggle(),
+        }
+    }
+}
+
+pub struct TransmitController {
+    trigger: TransmitTrigger,
+    release_tail_frames: usize,
+    release_tail_count: usize,
+}
+
+impl TransmitController {
+    pub fn new(config: &TransmitConfig, trigger: TransmitTrigger, frame_ms: u64) -> Self {
+        Self {
+            trigger,
+            release_tail_frames: (config.release_tail_ms / frame_ms.max(1)) as usize,
+            release_tail_count: 0,
+        }
+    }
+
+    pub fn trigger(&self) -> &TransmitTrigger {
+        &self.trigger
+    }
+
+    // Called once per captured frame. `voice_activity` is None if the voice activity detection
+    // is disabled, in which case the voice is considered always active.
+    pub fn process(&mut self, voice_activity: Option<bool>) -> bool {
+        let voice_activity = voice_activity.unwrap_or(true);
+
+        let keyed = {
+            let mut state = self.trigger.0.lock();
+            if state.reset {
+                state.reset = false;
+                self.release_tail_count = 0;
+            }
+
+            match state.mode {
+                TransmitMode::Continuous => return true,
+                // The voice activity detection has its own hangover, no need for a release tail
+                TransmitMode::VoiceActivation => return voice_activity,
+                // Muting must be immediate
+                TransmitMode::PushToMute => return !state.pressed && voice_activity,
+                TransmitMode::PushToTalk => state.pressed,
+                TransmitMode::Toggle => state.toggled_on,
+            }
+        };
+
+        // Keep transmitting for a while after the key is released, to avoid cutting the last
+        // syllable
+        if keyed {
+            self.release_tail_count = self.release_tail_frames;
+            true
+        } else if self.release_tail_count > 0 {
+            self.release_tail_count -= 1;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FRAME_MS: u64 = 10;
+
+    fn controller(mode: TransmitMode) -> TransmitController {
+        let config = TransmitConfig {
+            mode,
+            release_tail_ms: 30,
+        };
+
+        TransmitController::new(&config, TransmitTrigger::new(mode), FRAME_MS)
+    }
+
+    #[test]
+    fn test_push_to_talk_release_tail() {
+        let mut controller = controller(TransmitMode::PushToTalk);
+        assert!(!controller.process(Some(true)));
+
+        controller.trigger().press();
+        // The voice activity does not matter
+        assert!(controller.process(Some(false)));
+
+        controller.trigger().release();
+        for _ in 0..3 {
+            assert!(controller.process(Some(false)));
+        }
+        assert!(!controller.process(Some(true)));
+    }
+
+    #[test]
+    fn test_toggle_ignores_key_repeats() {
+        let mut controller = controller(TransmitMode::Toggle);
+
+        // Key repeats send several presses before the release
+        controller.trigger().press();
+        controller.trigger().press();
+        controller.trigger().release();
+        assert!(controller.process(None));
+        assert!(controller.process(None));
+
+        controller.trigger().toggle();
+        for _ in 0..3 {
+            assert!(controller.process(None));
+        }
+        assert!(!controller.process(None));
+
+        controller.trigger().apply(TransmitTriggerAction::Toggle);
+        assert!(controller.process(None));
+    }
+
+    #[test]
+    fn test_push_to_mute() {
+        let mut controller = controller(TransmitMode::PushToMute);
+        assert!(controller.process(Some(true)));
+        assert!(!controller.process(Some(false)));
+
+        // Muting is immediate and has no release tail
+        controller.trigger().press();
+        assert!(!controller.process(Some(true)));
+        controller.trigger().release();
+        assert!(controller.process(Some(true)));
+    }
+
+    #[test]
+    fn test_voice_activation() {
+        let mut controller = controller(TransmitMode::VoiceActivation);
+        assert!(controller.process(Some(true)));
+        assert!(!controller.process(Some(false)));
+        // Without voice activity detection the voice is always active
+        assert!(controller.process(None));
+
+        // The trigger is ignored
+        controller.trigger().press();
+        assert!(!controller.process(Some(false)));
+    }
+
+    #[test]
+    fn test_set_mode_resets_state() {
+        let mut controller = controller(TransmitMode::Toggle);
+        controller.trigger().toggle();
+        assert!(controller.process(None));
+
+        // Neither the toggle nor the release tail survive a mode change
+        controller.trigger().set_mode(TransmitMode::Toggle);
+        assert!(!controller.process(None));
+
+        controller.trigger().press();
+        controller.trigger().set_mode(TransmitMode::PushToTalk);
+        assert!(matches!(
+            controller.trigger().mode(),
+            TransmitMode::PushToTalk
+        ));
+        assert!(!controller.process(None));
+    }
+}
@@ -0,0 +1,165 @@
+// Voice activity detection. The decision is based on the frame energy relative to an adaptive noise
+// floor, combined with the fraction of energy that falls inside the speech band. This rejects both
+// steady background noise (fans, hum) and broadband noise (keyboard clicks, hiss) that is loud but
+// not speech-like.
+
+use crate::dsp::{self, Biquad};
+use vors_share_session::VoiceActivityDetectionConfig;
+
+// Frames quieter than this are never considered speech
+const MIN_SPEECH_LEVEL_DB: f32 = -55.;
+
+// Minimum ratio between speech band energy and total energy
+const MIN_SPEECH_BAND_RATIO: f32 = 0.25;
+
+// Consecutive speech frames needed to open the gate. Avoids triggering on single clicks.
+const ONSET_FRAMES: usize = 2;
+
+// Noise floor tracking speed, in dB per frame
+const NOISE_FLOOR_RISE_DB: f32 = 0.02;
+const NOISE_FLOOR_FALL_FACTOR: f32 = 0.2;
+
+pub struct VoiceActivityDetector {
+    channels_count: usize,
+    threshold_db: f32,
+    hangover_frames: usize,
+    speech_band_filter: Biquad,
+    noise_floor_db: f32,
+    onset_count: usize,
+    hangover_count: usize,
+    speaking: bool,
+    mono_buffer: Vec<f32>,
+}
+
+impl VoiceActivityDetector {
+    pub fn new(
+        config: &VoiceActivityDetectionConfig,
+        sample_rate: u32,
+        channels_count: usize,
+        frame_ms: u64,
+    ) -> Self {
+        let sensitivity = config.sensitivity.clamp(0., 1.);
+
+        Self {
+            channels_count,
+            // Maximum sensitivity opens the gate 3 dB above the noise floor, minimum at 20 dB.
+            threshold_db: 20. - 17. * sensitivity,
+            hangover_frames: (config.hangover_ms / frame_ms.max(1)) as usize,
+            // Covers roughly 300Hz-3400Hz
+            speech_band_filter: Biquad::band_pass(sample_rate, 1000., 0.5),
+            noise_floor_db: -70.,
+            onset_count: 0,
+            hangover_count: 0,
+            speaking: false,
+            mono_buffer: vec![],
+        }
+    }
+
+    // Analyze one frame of interleaved samples. Returns true if the frame should be considered
+    // speech, hangover included.
+    pub fn process(&mut self, frame: &[f32]) -> bool {
+        dsp::downmix_to_mono(frame, self.channels_count, &mut self.mono_buffer);
+
+        let level_db = dsp::power_db(&self.mono_buffer);
+
+        for sample in &mut self.mono_buffer {
+            *sample = self.speech_band_filter.process(*sample);
+        }
+        let speech_band_ratio = dsp::db_to_linear(dsp::power_db(&self.mono_buffer) - level_db);
+
+        let is_speech_frame = level_db > MIN_SPEECH_LEVEL_DB
+            && level_db > self.noise_floor_db + self.threshold_db
+            && speech_band_ratio > MIN_SPEECH_BAND_RATIO;
+
+        // The noise floor follows quiet frames quickly and loud frames slowly, so that it
+        // approximates the minimum level. It is not updated during speech.
+        if level_db < self.noise_floor_db {
+            self.noise_floor_db += (level_db - self.noise_floor_db) * NOISE_FLOOR_FALL_FACTOR;
+        } else if !is_speech_frame {
+            self.noise_floor_db += NOISE_FLOOR_RISE_DB;
+        }
+
+        if is_speech_frame {
+            self.onset_count += 1;
+            if self.onset_count >= ONSET_FRAMES {
+                self.speaking = true;
+                self.hangover_count = self.hangover_frames;
+            }
+        } else {
+            self.onset_count = 0;
+            if self.hangover_count > 0 {
+                self.hangover_count -= 1;
+            } else {
+                self.speaking = false;
+            }
+        }
+
+        self.speaking
+    }
+
+    pub fn is_speaking(&self) -> bool {
+        self.speaking
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+    use vors_share_session::settings_schema::Switch;
+
+    const SAMPLE_RATE: u32 = 48_000;
+    const FRAME_SIZE: usize = 480;
+
+    fn config() -> VoiceActivityDetectionConfig {
+        VoiceActivityDetectionConfig {
+            sensitivity: 0.5,
+            hangover_ms: 100,
+            suppress_silent_frames: true,
+            comfort_noise: Switch::Disabled,
+        }
+    }
+
+    fn tone_frame(index: usize, amplitude: f32) -> Vec<f32> {
+        (0..FRAME_SIZE)
+            .map(|i| {
+                let t = (index * FRAME_SIZE + i) as f32 / SAMPLE_RATE as f32;
+                amplitude * (2. * PI * 800. * t).sin()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_vad_detects_speech_band_tone_with_hangover() {
+        let mut vad = VoiceActivityDetector::new(&config(), SAMPLE_RATE, 1, 10);
+
+        for i in 0..100 {
+            assert!(!vad.process(&tone_frame(i, 0.001)));
+        }
+        for i in 100..150 {
+            vad.process(&tone_frame(i, 0.3));
+        }
+        assert!(vad.is_speaking());
+
+        // 100ms of hangover
+        for i in 150..160 {
+            assert!(vad.process(&tone_frame(i, 0.001)));
+        }
+        assert!(!vad.process(&tone_frame(160, 0.001)));
+    }
+
+    #[test]
+    fn test_vad_ignores_low_frequency_hum() {
+        let mut vad = VoiceActivityDetector::new(&config(), SAMPLE_RATE, 1, 10);
+
+        for i in 0..100 {
+            let frame = (0..FRAME_SIZE)
+                .map(|s| {
+                    let t = (i * FRAME_SIZE + s) as f32 / SAMPLE_RATE as f32;
+                    0.5 * (2. * PI * 50. * t).sin()
+                })
+                .collect::<Vec<_>>();
+            assert!(!vad.process(&frame));
+        }
+    }
+}
@@ -0,0 +1,545 @@
+// Real-time voice effects for the capture path. All effects work on the mono downmix of the
+// microphone and add at most one capture frame of latency on average, so that they fit the latency
+// budget of the batch framing.
+// The pitch shifter is a dual tap delay line whose read positions drift at a speed proportional to
+// the pitch ratio, crossfaded with sin² windows. To preserve the formants, the signal is first
+// whitened with a linear prediction filter, the residual (which carries only the pitch) is shifted,
+// and the spectral envelope is applied again with the synthesis filter.
+
+use crate::{dsp, AudioProcessor};
+use std::{f32::consts::PI, sync::Arc};
+use vors_share_common::parking_lot::Mutex;
+use vors_share_session::VoiceEffectPreset;
+
+// Order of the linear prediction used for the formant preservation
+const LPC_ORDER: usize = 16;
+
+// Freeverb tunings, for a sample rate of 44.1kHz
+const REVERB_COMB_TUNINGS: [usize; 8] = [1116, 1188, 1277, 1356, 1422, 1491, 1557, 1617];
+const REVERB_ALLPASS_TUNINGS: [usize; 4] = [556, 441, 341, 225];
+const REVERB_INPUT_GAIN: f32 = 0.015;
+const REVERB_WET_SCALE: f32 = 3.;
+
+struct EffectsState {
+    // Incremented on each change, so that the processor knows when to rebuild the effect
+    generation: u64,
+    preset: Option<VoiceEffectPreset>,
+}
+
+// Selects the effect at runtime, for example from the local API. Cheap to clone.
+#[derive(Clone)]
+pub struct VoiceEffectsControl(Arc<Mutex<EffectsState>>);
+
+impl VoiceEffectsControl {
+    pub fn new(preset: Option<VoiceEffectPreset>) -> Self {
+        Self(Arc::new(Mutex::new(EffectsState {
+            generation: 0,
+            preset,
+        })))
+    }
+
+    pub fn preset(&self) -> Option<VoiceEffectPreset> {
+        self.0.lock().preset.clone()
+    }
+
+    pub fn set_preset(&self, preset: Option<VoiceEffectPreset>) {
+        let mut state = self.0.lock();
+        state.generation += 1;
+        state.preset = preset;
+    }
+}
+
+impl Default for VoiceEffectsControl {
+    fn default() -> Self {
+        Self::new(None)
+    }
+}
+
+trait MonoEffect: Send {
+    fn latency_frames(&self) -> usize {
+        0
+    }
+
+    fn process(&mut self, samples: &mut [f32]);
+}
+
+struct PitchShifter {
+    ratio: f32,
+    window: f32,
+    buffer: Vec<f32>,
+    cursor: usize,
+    delay: f32,
+}
+
+impl PitchShifter {
+    fn new(semitones: f32, window_size: usize) -> Self {
+        Self {
+            ratio: 2_f32.powf(semitones / 12.),
+            window: window_size as f32,
+            buffer: vec![0.; window_size + 2],
+            cursor: 0,
+            delay: 0.,
+        }
+    }
+
+    fn read(&self, delay: f32) -> f32 {
+        let len = self.buffer.len();
+        let whole = delay as usize;
+        let fraction = delay - whole as f32;
+        let newer = self.buffer[(self.cursor + len - whole) % len];
+        let older = self.buffer[(self.cursor + 2 * len - whole - 1) % len];
+
+        newer + (older - newer) * fraction
+    }
+}
+
+impl MonoEffect for PitchShifter {
+    fn latency_frames(&self) -> usize {
+        self.window as usize / 2
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            self.cursor = (self.cursor + 1) % self.buffer.len();
+            self.buffer[self.cursor] = *sample;
+
+            self.delay = (self.delay + 1. - self.ratio).rem_euclid(self.window);
+            let other_delay = (self.delay + self.window / 2.) % self.window;
+
+            // The taps are silent when they wrap around
+            let gain = (PI * self.delay / self.window).sin().powi(2);
+            *sample = self.read(self.delay) * gain + self.read(other_delay) * (1. - gain);
+        }
+    }
+}
+
+// Autocorrelation method with the Levinson-Durbin recursion. Returns the coefficients of
+// A(z) = 1 + a1 z^-1 + ... + ap z^-p. The resulting synthesis filter is always stable.
+fn linear_prediction(samples: &[f32], coefficients: &mut [f32]) {
+    let order = coefficients.len();
+
+    let mut autocorrelation = (0..=order)
+        .map(|lag| {
+            samples[lag..]
+                .iter()
+                .zip(samples)
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+        })
+        .collect::<Vec<_>>();
+    // White noise correction, keeps the recursion well conditioned
+    autocorrelation[0] = autocorrelation[0] * (1. + 1e-4) + 1e-9;
+
+    coefficients.fill(0.);
+    let mut error = autocorrelation[0];
+    let mut previous = vec![0.; order];
+    for i in 0..order {
+        let mut acc = autocorrelation[i + 1];
+        for j in 0..i {
+            acc += coefficients[j] * autocorrelation[i - j];
+        }
+        let reflection = -acc / error;
+
+        previous[..i].copy_from_slice(&coefficients[..i]);
+        for j in 0..i {
+            coefficients[j] += reflection * previous[i - 1 - j];
+        }
+        coefficients[i] = reflection;
+
+        error *= 1. - reflection * reflection;
+    }
+}
+
+struct FormantPreservingPitchShifter {
+    shifter: PitchShifter,
+    coefficients: Vec<f32>,
+    analysis_window: Vec<f32>,
+    analysis_buffer: Vec<f32>,
+    // Last input samples, for the analysis filter
+    input_history: Vec<f32>,
+    // Last output samples, for the synthesis filter
+    output_history: Vec<f32>,
+}
+
+impl FormantPreservingPitchShifter {
+    fn new(semitones: f32, frame_size: usize) -> Self {
+        // The envelope is estimated on the last two frames
+        let analysis_size = 2 * frame_size;
+
+        Self {
+            shifter: PitchShifter::new(semitones, 2 * frame_size),
+            coefficients: vec![0.; LPC_ORDER],
+            analysis_window: (0..analysis_size)
+                .map(|n| (PI * (n as f32 + 0.5) / analysis_size as f32).sin().powi(2))
+                .collect(),
+            analysis_buffer: vec![0.; analysis_size],
+            input_history: vec![0.; LPC_ORDER],
+            output_history: vec![0.; LPC_ORDER],
+        }
+    }
+}
+
+impl MonoEffect for FormantPreservingPitchShifter {
+    fn latency_frames(&self) -> usize {
+        self.shifter.latency_frames()
+    }
+
+    fn process(&mut self, samples: &mut [f32]) {
+        let analysis_size = self.analysis_buffer.len();
+        let new_samples_count = samples.len().min(analysis_size);
+        self.analysis_buffer.rotate_left(new_samples_count);
+        self.analysis_buffer[analysis_size - new_samples_count..]
+            .copy_from_slice(&samples[samples.len() - new_samples_count..]);
+
+        let windowed = self
+            .analysis_buffer
+            .iter()
+            .zip(&self.analysis_window)
+            .map(|(s, w)| s * w)
+            .collect::<Vec<_>>();
+        linear_prediction(&windowed, &mut self.coefficients);
+
+        // Whitening
+        for sample in samples.iter_mut() {
+            let input = *sample;
+            *sample += self
+                .coefficients
+                .iter()
+                .zip(&self.input_history)
+                .map(|(a, x)| a * x)
+                .sum::<f32>();
+
+            self.input_history.rotate_right(1);
+            self.input_history[0] = input;
+        }
+
+        self.shifter.process(samples);
+
+        // Apply the envelope again
+        for sample in samples.iter_mut() {
+            *sample -= self
+                .coefficients
+                .iter()
+                .zip(&self.output_history)
+                .map(|(a, y)| a * y)
+                .sum::<f32>();
+
+            self.output_history.rotate_right(1);
+            self.output_history[0] = *sample;
+        }
+    }
+}
+
+struct RingModulator {
+    phase: f32,
+    phase_increment: f32,
+}
+
+impl MonoEffect for RingModulator {
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            *sample *= self.phase.sin();
+            self.phase = (self.phase + self.phase_increment) % (2. * PI);
+        }
+    }
+}
+
+struct CombFilter {
+    buffer: Vec<f32>,
+    cursor: usize,
+    filter_store: f32,
+}
+
+struct AllpassFilter {
+    buffer: Vec<f32>,
+    cursor: usize,
+}
+
+struct Reverb {
+    combs: Vec<CombFilter>,
+    allpasses: Vec<AllpassFilter>,
+    feedback: f32,
+    damping: f32,
+    wet: f32,
+}
+
+impl Reverb {
+    fn new(room_size: f32, damping: f32, wet: f32, sample_rate: u32) -> Self {
+        let scale = |tuning: usize| (tuning * sample_rate as usize / 44100).max(1);
+
+        Self {
+            combs: REVERB_COMB_TUNINGS
+                .iter()
+                .map(|&tuning| CombFilter {
+                    buffer: vec![0.; scale(tuning)],
+                    cursor: 0,
+                    filter_store: 0.,
+                })
+                .collect(),
+            allpasses: REVERB_ALLPASS_TUNINGS
+                .iter()
+                .map(|&tuning| AllpassFilter {
+                    buffer: vec![0.; scale(tuning)],
+                    cursor: 0,
+                })
+                .collect(),
+            feedback: room_size.clamp(0., 1.) * 0.28 + 0.7,
+            damping: damping.clamp(0., 1.) * 0.4,
+            wet: wet.clamp(0., 1.),
+        }
+    }
+}
+
+impl MonoEffect for Reverb {
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            let input = *sample * REVERB_INPUT_GAIN;
+
+            let mut output = 0.;
+            for comb in &mut self.combs {
+                let delayed = comb.buffer[comb.cursor];
+                comb.filter_store =
+                    delayed * (1. - self.damping) + comb.filter_store * self.damping;
+                comb.buffer[comb.cursor] = input + comb.filter_store * self.feedback;
+                comb.cursor = (comb.cursor + 1) % comb.buffer.len();
+
+                output += delayed;
+            }
+
+            for allpass in &mut self.allpasses {
+                let delayed = allpass.buffer[allpass.cursor];
+                allpass.buffer[allpass.cursor] = output + delayed * 0.5;
+                allpass.cursor = (allpass.cursor + 1) % allpass.buffer.len();
+
+                output = delayed - output;
+            }
+
+            *sample = *sample * (1. - self.wet) + output * self.wet * REVERB_WET_SCALE;
+        }
+    }
+}
+
+struct Echo {
+    buffer: Vec<f32>,
+    cursor: usize,
+    feedback: f32,
+    wet: f32,
+}
+
+impl MonoEffect for Echo {
+    fn process(&mut self, samples: &mut [f32]) {
+        for sample in samples {
+            let delayed = self.buffer[self.cursor];
+            self.buffer[self.cursor] = *sample + delayed * self.feedback;
+            self.cursor = (self.cursor + 1) % self.buffer.len();
+
+            *sample += delayed * self.wet;
+        }
+    }
+}
+
+fn build_effect(
+    preset: &VoiceEffectPreset,
+    sample_rate: u32,
+    frame_size: usize,
+) -> Box<dyn MonoEffect> {
+    match preset {
+        VoiceEffectPreset::PitchShift {
+            semitones,
+            preserve_formants,
+        } => {
+            if *preserve_formants {
+                Box::new(FormantPreservingPitchShifter::new(*semitones, frame_size))
+            } else {
+                Box::new(PitchShifter::new(*semitones, 2 * frame_size))
+            }
+        }
+        VoiceEffectPreset::Robot { modulation_hz } => Box::new(RingModulator {
+            phase: 0.,
+            phase_increment: 2. * PI * modulation_hz / sample_rate as f32,
+        }),
+        VoiceEffectPreset::Reverb {
+            room_size,
+            damping,
+            wet,
+        } => Box::new(Reverb::new(*room_size, *damping, *wet, sample_rate)),
+        VoiceEffectPreset::Echo {
+            delay_ms,
+            feedback,
+            wet,
+        } => Box::new(Echo {
+            buffer: vec![0.; (*delay_ms as usize * sample_rate as usize / 1000).max(1)],
+            cursor: 0,
+            feedback: feedback.clamp(0., 0.95),
+            wet: wet.clamp(0., 1.),
+        }),
+    }
+}
+
+pub struct VoiceEffects {
+    control: VoiceEffectsControl,
+    sample_rate: u32,
+    channels_count: usize,
+    frame_size: usize,
+    generation: Option<u64>,
+    effect: Option<Box<dyn MonoEffect>>,
+    mono_buffer: Vec<f32>,
+}
+
+impl VoiceEffects {
+    pub fn new(
+        control: VoiceEffectsControl,
+        sample_rate: u32,
+        channels_count: usize,
+        frame_size: usize,
+    ) -> Self {
+        Self {
+            control,
+            sample_rate,
+            channels_count,
+            frame_size,
+            generation: None,
+            effect: None,
+            mono_buffer: vec![],
+        }
+    }
+
+    fn update_effect(&mut self) {
+        let state = self.control.0.lock();
+        if self.generation != Some(state.generation) {
+            self.generation = Some(state.generation);
+            self.effect = state
+                .preset
+                .as_ref()
+                .map(|preset| build_effect(preset, self.sample_rate, self.frame_size));
+        }
+    }
+}
+
+impl AudioProcessor for VoiceEffects {
+    fn name(&self) -> &'static str {
+        "voice_effects"
+    }
+
+    fn channels_count(&self) -> usize {
+        self.channels_count
+    }
+
+    fn latency_frames(&self) -> usize {
+        self.effect
+            .as_ref()
+            .map(|effect| effect.latency_frames())
+            .unwrap_or(0)
+    }
+
+    fn reset(&mut self) {
+        // Rebuilt on the next frame
+        self.generation = None;
+    }
+
+    fn process(&mut self, frame: &mut [f32]) {
+        self.update_effect();
+
+        if let Some(effect) = &mut self.effect {
+            dsp::downmix_to_mono(frame, self.channels_count, &mut self.mono_buffer);
+            effect.process(&mut self.mono_buffer);
+
+            for (samples, sample) in frame
+                .chunks_exact_mut(self.channels_count)
+                .zip(&self.mono_buffer)
+            {
+                samples.fill(*sample);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 16000;
+    const FRAME_SIZE: usize = 160;
+
+    // Period of the strongest periodicity, in samples
+    fn estimate_period(samples: &[f32]) -> usize {
+        let correlation = |lag: usize| -> f32 {
+            samples[lag..]
+                .iter()
+                .zip(samples)
+                .map(|(a, b)| a * b)
+                .sum::<f32>()
+        };
+        let correlations = (0..200).map(correlation).collect::<Vec<_>>();
+        let max = correlations[10..].iter().copied().fold(0., f32::max);
+
+        // First peak close to the maximum, to avoid picking a multiple of the period
+        (11..199)
+            .find(|&lag| {
+                correlations[lag] > 0.8 * max
+                    && correlations[lag] >= correlations[lag - 1]
+                    && correlations[lag] >= correlations[lag + 1]
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_pitch_shift() {
+        for preserve_formants in [false, true] {
+            let control = VoiceEffectsControl::new(Some(VoiceEffectPreset::PitchShift {
+                semitones: 12.,
+                preserve_formants,
+            }));
+            let mut effects = VoiceEffects::new(control, SAMPLE_RATE, 1, FRAME_SIZE);
+
+            // Sawtooth at 200Hz, the period is 80 samples
+            let mut output = vec![];
+            for index in 0..100 {
+                let mut frame = (0..FRAME_SIZE)
+                    .map(|n| ((index * FRAME_SIZE + n) % 80) as f32 / 80. - 0.5)
+                    .collect::<Vec<_>>();
+                effects.process(&mut frame);
+                output.extend(frame);
+            }
+
+            let steady = &output[output.len() / 2..];
+            let period = estimate_period(steady);
+            assert!((38..=42).contains(&period), "{preserve_formants}: {period}");
+            assert!(steady.iter().all(|s| s.is_finite()));
+        }
+    }
+
+    #[test]
+    fn test_echo_and_preset_switch() {
+        let control = VoiceEffectsControl::default();
+        let mut effects = VoiceEffects::new(control.clone(), SAMPLE_RATE, 2, FRAME_SIZE);
+
+        // No effect, pass-through
+        let mut frame = vec![0.5; 2 * FRAME_SIZE];
+        effects.process(&mut frame);
+        assert!(frame.iter().all(|s| *s == 0.5));
+
+        control.set_preset(Some(VoiceEffectPreset::Echo {
+            delay_ms: 20,
+            feedback: 0.5,
+            wet: 1.,
+        }));
+
+        let mut output = vec![];
+        for index in 0..10 {
+            let mut frame = vec![0.; 2 * FRAME_SIZE];
+            if index == 0 {
+                frame[0] = 1.;
+                frame[1] = 1.;
+            }
+            effects.process(&mut frame);
+            output.extend(frame.into_iter().step_by(2));
+        }
+
+        // 20ms at 16kHz
+        assert_eq!(output[0], 1.);
+        assert_eq!(output[320], 1.);
+        assert_eq!(output[640], 0.5);
+        assert_eq!(output[1..320].iter().copied().fold(0., f32::max), 0.);
+    }
+}
@@ -1,7 +1,78 @@
 mod web_server;
 
 use web_server::*;
-use tokio::sync::broadcast::{self, error::RecvError};
+use vors_client_audio::{
+    AudioEvent, AudioRecorder, AudioTap, SoundboardControl, TransmitTrigger, VoiceEffectsControl,
+};
+use vors_share_common::{once_cell::sync::Lazy, parking_lot::Mutex};
+use vors_share_session::settings_schema::Switch;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc as tmpsc,
+    },
+    task::JoinHandle,
+};
+
+// Handed to CaptureProcessingDesc::new by the capture loop, controlled through the web server.
+// The edge does not start a capture loop yet: until it does, "/api/voice-effect" only stores the
+// preset.
+pub static VOICE_EFFECTS: Lazy<VoiceEffectsControl> = Lazy::new(|| {
+    VoiceEffectsControl::new(
+        SERVER_DATA_MANAGER
+            .read()
+            .settings()
+            .audio
+            .voice_effects
+            .as_option()
+            .cloned(),
+    )
+});
+
+// Handed to CaptureProcessingDesc::new by the capture loop, triggered through the web server. The
+// edge does not start a capture loop yet: until it does, the clips played through "/api/soundboard"
+// are not heard.
+pub static SOUNDBOARD: Lazy<SoundboardControl> = Lazy::new(|| {
+    SoundboardControl::new(
+        SERVER_DATA_MANAGER
+            .read()
+            .settings()
+            .audio
+            .soundboard
+            .as_option()
+            .cloned(),
+    )
+});
+
+// Handed to TransmitController::new by the capture loop, pressed through the web server. The edge
+// does not start a capture loop yet: until it does, "/api/transmit" only changes the trigger state.
+pub static TRANSMIT_TRIGGER: Lazy<TransmitTrigger> =
+    Lazy::new(|| TransmitTrigger::new(SERVER_DATA_MANAGER.read().settings().audio.transmit.mode));
+
+// Published by the playback mixer, consumed by the web server and the external taps
+pub static AUDIO_TAP: Lazy<AudioTap> = Lazy::new(AudioTap::new);
+
+// Running audio recording, started and stopped through the web server
+pub static AUDIO_RECORDER: Lazy<Mutex<Option<AudioRecorder>>> = Lazy::new(|| Mutex::new(None));
+
+// Events of the audio loops, sent to the dashboard through "/api/events"
+pub static AUDIO_EVENTS: Lazy<broadcast::Sender<AudioEvent>> =
+    Lazy::new(|| broadcast::channel(web_server::WS_BROADCAST_CAPACITY).0);
+
+// Running microphone test, started and stopped through the web server
+pub static MIC_TEST: Lazy<Mutex<Option<JoinHandle<()>>>> = Lazy::new(|| Mutex::new(None));
+
+// Sender to pass to an audio loop. Must be called inside the runtime.
+pub fn audio_events_sender() -> tmpsc::UnboundedSender<AudioEvent> {
+    let (sender, mut receiver) = tmpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Some(event) = receiver.recv().await {
+            AUDIO_EVENTS.send(event).ok();
+        }
+    });
+
+    sender
+}
 
 fn init() {
     let (events_sender, _) = broadcast::channel(web_server::WS_BROADCAST_CAPACITY);
@@ -11,6 +82,27 @@ fn init() {
         runtime.spawn(vors_share_common::show_err_async(web_server::web_server(
             events_sender,
         )));
+
+        runtime.spawn(async {
+            vors_client_audio::speaker_levels_loop(AUDIO_TAP.clone(), audio_events_sender()).await
+        });
+
+        let tap_config = SERVER_DATA_MANAGER
+            .read()
+            .settings()
+            .audio
+            .output_tap
+            .clone();
+        if let Switch::Enabled(config) = tap_config.udp {
+            runtime.spawn(vors_share_common::show_err_async(
+                vors_client_audio::udp_tap_loop(AUDIO_TAP.clone(), config),
+            ));
+        }
+        if let Switch::Enabled(directory) = tap_config.fifo_directory {
+            runtime.spawn(vors_share_common::show_err_async(
+                vors_client_audio::fifo_tap_loop(AUDIO_TAP.clone(), directory.into()),
+            ));
+        }
     }
 
 
@@ -1,18 +1,20 @@
 use crate::{
-    DECODER_CONFIG, FILESYSTEM_LAYOUT, SERVER_DATA_MANAGER, VIDEO_MIRROR_SENDER,
-    VIDEO_RECORDING_FILE,
+    AUDIO_EVENTS, AUDIO_RECORDER, AUDIO_TAP, DECODER_CONFIG, FILESYSTEM_LAYOUT, MIC_TEST,
+    SERVER_DATA_MANAGER, SOUNDBOARD, TRANSMIT_TRIGGER, VIDEO_MIRROR_SENDER, VOICE_EFFECTS,
 };
+use vors_client_audio::{AudioDevice, AudioRecorder, TapTrack, TransmitTriggerAction};
 use vors_share_common::{log, prelude::*};
 use vors_share_events::{Event, EventType};
 use vors_share_packets::ServerRequest;
+use vors_share_session::{AudioBufferingConfig, CustomAudioDeviceConfig, VoiceEffectPreset};
 use bytes::Buf;
 use futures::SinkExt;
 use headers::HeaderMapExt;
 use hyper::{
     header::{HeaderValue, ACCESS_CONTROL_ALLOW_ORIGIN, CACHE_CONTROL, CONTENT_TYPE},
-    service, Body, Request, Response, StatusCode,
+    service, Body, Method, Request, Response, StatusCode,
 };
-use serde::de::DeserializeOwned;
+use serde::{de::DeserializeOwned, Serialize};
 use serde_json as json;
 use std::net::SocketAddr;
 use tokio::sync::broadcast::{self, error::RecvError};
@@ -21,6 +23,8 @@ use tokio_util::codec::{BytesCodec, FramedRead};
 
 pub const WS_BROADCAST_CAPACITY: usize = 256;
 
+const LATENCY_MEASUREMENT_MARKERS_COUNT: usize = 10;
+
 fn reply(code: StatusCode) -> StrResult<Response<Body>> {
     Response::builder()
         .status(code)
@@ -28,6 +32,13 @@ fn reply(code: StatusCode) -> StrResult<Response<Body>> {
         .map_err(err!())
 }
 
+fn reply_json<T: Serialize>(obj: &T) -> StrResult<Response<Body>> {
+    Response::builder()
+        .header(CONTENT_TYPE, "application/json")
+        .body(json::to_string(obj).map_err(err!())?.into())
+        .map_err(err!())
+}
+
 async fn from_request_body<T: DeserializeOwned>(request: Request<Body>) -> StrResult<T> {
     json::from_reader(
         hyper::body::aggregate(request)
@@ -41,7 +52,8 @@ async fn from_request_body<T: DeserializeOwned>(request: Request<Body>) -> StrRe
 async fn websocket<T: Clone + Send + 'static>(
     request: Request<Body>,
     sender: broadcast::Sender<T>,
-    message_builder: impl Fn(T) -> protocol::Message + Send + Sync + 'static,
+    // Returning None skips the data
+    message_builder: impl Fn(T) -> Option<protocol::Message> + Send + Sync + 'static,
 ) -> StrResult<Response<Body>> {
     if let Some(key) = request.headers().typed_get::<headers::SecWebsocketKey>() {
         tokio::spawn(async move {
@@ -56,7 +68,11 @@ async fn websocket<T: Clone + Send + 'static>(
                     loop {
                         match data_receiver.recv().await {
                             Ok(data) => {
-                                if let Err(e) = ws.send(message_builder(data)).await {
+                                let Some(message) = message_builder(data) else {
+                                    continue;
+                                };
+
+                                if let Err(e) = ws.send(message).await {
                                     info!("Failed to send log with websocket: {e}");
                                     break;
                                 }
@@ -92,9 +108,52 @@ async fn websocket<T: Clone + Send + 'static>(
     }
 }
 
+// Forward the events of a channel to the dashboard, serialized as JSON
+async fn forward_events<T: Serialize + Clone>(
+    mut receiver: broadcast::Receiver<T>,
+    dashboard_sender: broadcast::Sender<String>,
+) {
+    loop {
+        match receiver.recv().await {
+            Ok(event) => {
+                if let Ok(json) = json::to_string(&event) {
+                    dashboard_sender.send(json).ok();
+                }
+            }
+            Err(RecvError::Lagged(_)) => (),
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+fn start_audio_recording() -> StrResult {
+    let mut recorder_lock = AUDIO_RECORDER.lock();
+    if recorder_lock.is_none() {
+        let config = SERVER_DATA_MANAGER
+            .read()
+            .settings()
+            .audio
+            .recording
+            .clone();
+        let recorder = AudioRecorder::start(&AUDIO_TAP, config)?;
+        info!("Recording audio to {}", recorder.directory().display());
+
+        *recorder_lock = Some(recorder);
+    }
+
+    Ok(())
+}
+
+fn stop_audio_recording() {
+    if let Some(recorder) = AUDIO_RECORDER.lock().take() {
+        // Finalizing the files can take a while
+        tokio::task::spawn_blocking(move || vors_share_common::show_err(recorder.stop()));
+    }
+}
+
 async fn http_api(
     request: Request<Body>,
-    events_sender: broadcast::Sender<Event>,
+    events_sender: broadcast::Sender<String>,
 ) -> StrResult<Response<Body>> {
     let mut response = match request.uri().path() {
         // New unified requests
@@ -120,14 +179,16 @@ async fn http_api(
                         .write()
                         .update_client_list(hostname, action),
                     ServerRequest::GetAudioDevices => {
-                        if let Ok(list) = SERVER_DATA_MANAGER.read().get_audio_devices_list() {
-                            vors_events::send_event(EventType::AudioDevices(list));
-                        }
+                        vors_events::send_event(EventType::AudioDevices(
+                            vors_client_audio::audio_devices_list(),
+                        ));
                     }
                     ServerRequest::CaptureFrame => unsafe { crate::CaptureFrame() },
                     ServerRequest::InsertIdr => unsafe { crate::RequestIDR() },
-                    ServerRequest::StartRecording => crate::create_recording_file(),
-                    ServerRequest::StopRecording => *VIDEO_RECORDING_FILE.lock() = None,
+                    ServerRequest::StartRecording => {
+                        vors_share_common::show_err(start_audio_recording());
+                    }
+                    ServerRequest::StopRecording => stop_audio_recording(),
                     ServerRequest::FirewallRules(action) => {
                         if alvr_server_io::firewall_rules(action).is_ok() {
                             info!("Setting firewall rules succeeded!");
@@ -168,10 +229,7 @@ async fn http_api(
             }
         }
         "/api/events" => {
-            websocket(request, events_sender, |e| {
-                protocol::Message::Text(json::to_string(&e).unwrap())
-            })
-            .await?
+            websocket(request, events_sender, |e| Some(protocol::Message::Text(e))).await?
         }
         "/api/video-mirror" => {
             let sender = {
@@ -190,12 +248,136 @@ async fn http_api(
                 sender.send(config.config_buffer.clone()).ok();
             }
 
-            let res = websocket(request, sender, protocol::Message::Binary).await?;
+            let res = websocket(request, sender, |data| {
+                Some(protocol::Message::Binary(data))
+            })
+            .await?;
 
             unsafe { crate::RequestIDR() };
 
             res
         }
+        "/api/audio-devices" => {
+            // Probing the devices can take a while
+            let list = tokio::task::spawn_blocking(vors_client_audio::audio_devices_list)
+                .await
+                .map_err(err!())?;
+
+            reply_json(&list)?
+        }
+        // GET returns the current voice effect, POST selects a new one (null disables the effects)
+        "/api/voice-effect" => {
+            if request.method() == Method::GET {
+                reply_json(&VOICE_EFFECTS.preset())?
+            } else if let Ok(preset) = from_request_body::<Option<VoiceEffectPreset>>(request).await
+            {
+                VOICE_EFFECTS.set_preset(preset);
+
+                reply(StatusCode::OK)?
+            } else {
+                reply(StatusCode::BAD_REQUEST)?
+            }
+        }
+        // GET lists the clips, POST plays the clip named in the body
+        "/api/soundboard" => {
+            if request.method() == Method::GET {
+                reply_json(&SOUNDBOARD.clip_names())?
+            } else if !SOUNDBOARD.is_enabled() {
+                reply(StatusCode::CONFLICT)?
+            } else if let Ok(name) = from_request_body::<String>(request).await {
+                if SOUNDBOARD.play(&name).is_ok() {
+                    reply(StatusCode::OK)?
+                } else {
+                    // The soundboard is enabled, so the clip is unknown
+                    reply(StatusCode::NOT_FOUND)?
+                }
+            } else {
+                reply(StatusCode::BAD_REQUEST)?
+            }
+        }
+        "/api/soundboard/stop" => {
+            SOUNDBOARD.stop_all();
+
+            reply(StatusCode::OK)?
+        }
+        // Drive the transmit trigger like a hotkey would, the body is the action
+        "/api/transmit" => {
+            if let Ok(action) = from_request_body::<TransmitTriggerAction>(request).await {
+                TRANSMIT_TRIGGER.apply(action);
+
+                reply(StatusCode::OK)?
+            } else {
+                reply(StatusCode::BAD_REQUEST)?
+            }
+        }
+        // Raw 16 bit PCM of the mix ("/api/audio-tap/mix") or of one speaker
+        // ("/api/audio-tap/speaker/<id>"), one binary message per playback batch
+        path if path.starts_with("/api/audio-tap/") => {
+            let track = match path.trim_start_matches("/api/audio-tap/") {
+                "mix" => Some(TapTrack::Mix),
+                other => other
+                    .strip_prefix("speaker/")
+                    .and_then(|id| id.parse().ok())
+                    .map(TapTrack::Speaker),
+            };
+
+            if let Some(track) = track {
+                websocket(request, AUDIO_TAP.sender(), move |frame| {
+                    (frame.track == track).then(|| protocol::Message::Binary(frame.to_pcm_bytes()))
+                })
+                .await?
+            } else {
+                reply(StatusCode::NOT_FOUND)?
+            }
+        }
+        // Returns the directory of the running recording, or null
+        "/api/audio-recording" => reply_json(
+            &AUDIO_RECORDER
+                .lock()
+                .as_ref()
+                .map(|recorder| recorder.directory().to_owned()),
+        )?,
+        // Report the level of a microphone on "/api/events" while not connected. The body selects
+        // the device, null for the default one.
+        "/api/mic-test/start" => {
+            if let Ok(config) = from_request_body::<Option<CustomAudioDeviceConfig>>(request).await
+            {
+                let device = AudioDevice::new_input(config)?;
+
+                let task = tokio::spawn(vors_share_common::show_err_async(
+                    vors_client_audio::mic_test_loop(device, 1, crate::audio_events_sender()),
+                ));
+                if let Some(previous_task) = MIC_TEST.lock().replace(task) {
+                    previous_task.abort();
+                }
+
+                reply(StatusCode::OK)?
+            } else {
+                reply(StatusCode::BAD_REQUEST)?
+            }
+        }
+        "/api/mic-test/stop" => {
+            if let Some(task) = MIC_TEST.lock().take() {
+                task.abort();
+            }
+
+            reply(StatusCode::OK)?
+        }
+        // Measure the latency of the audio pipeline over a loopback socket, with the playback
+        // buffering given in the body. Takes a few seconds.
+        "/api/latency-measurement" => {
+            if let Ok(buffering) = from_request_body::<AudioBufferingConfig>(request).await {
+                let report = vors_client_audio::measure_latency(
+                    buffering,
+                    LATENCY_MEASUREMENT_MARKERS_COUNT,
+                )
+                .await?;
+
+                reply_json(&report)?
+            } else {
+                reply(StatusCode::BAD_REQUEST)?
+            }
+        }
         "/api/ping" => reply(StatusCode::OK)?,
         other_uri => {
             if other_uri.contains("..") {
@@ -244,6 +426,18 @@ async fn http_api(
 }
 
 pub async fn web_server(events_sender: broadcast::Sender<Event>) -> StrResult {
+    // The dashboard events and the audio events share the "/api/events" websocket
+    let (dashboard_events_sender, _) = broadcast::channel(WS_BROADCAST_CAPACITY);
+    tokio::spawn(forward_events(
+        events_sender.subscribe(),
+        dashboard_events_sender.clone(),
+    ));
+    tokio::spawn(forward_events(
+        AUDIO_EVENTS.subscribe(),
+        dashboard_events_sender.clone(),
+    ));
+    let events_sender = dashboard_events_sender;
+
     let web_server_port = SERVER_DATA_MANAGER
         .read()
         .settings()
@@ -0,0 +1,67 @@
+// Echo test service. The voice received on the echo test stream is sent back to the client after a
+// delay, through the same socket as the real streams, so that the client can check the quality of
+// its microphone, the latency and the packet loss before joining a channel.
+
+use std::time::Duration;
+use tokio::{
+    sync::mpsc::{self as tmpsc, error::TrySendError},
+    time::{self, Instant},
+};
+use vors_share_common::prelude::*;
+use vors_share_packets::{EchoTestPacketHeader, ECHO_TEST_STREAM_ID};
+use vors_share_sockets::{ReceiverBuffer, StreamReceiver, StreamSender, StreamSocket};
+
+// Duration of the frames sent by the clients
+const FRAME_DURATION: Duration = Duration::from_millis(10);
+// Room for the network jitter, on top of the packets waiting for their delay
+const QUEUE_JITTER_FRAMES_COUNT: usize = 10;
+
+type QueuedPacket = (Instant, EchoTestPacketHeader, Vec<u8>);
+
+async fn receive_loop(
+    mut receiver: StreamReceiver<EchoTestPacketHeader>,
+    queue_sender: tmpsc::Sender<QueuedPacket>,
+    delay: Duration,
+) -> StrResult {
+    let mut buffer = ReceiverBuffer::new();
+    loop {
+        receiver.recv_buffer(&mut buffer).await?;
+        let (header, payload) = buffer.get()?;
+
+        // When the queue is full the packet is dropped: a client that sends faster than real time
+        // loses the extra packets
+        if let Err(TrySendError::Closed(_)) =
+            queue_sender.try_send((Instant::now() + delay, header, payload.to_vec()))
+        {
+            return fmt_e!("The echo test queue is closed");
+        }
+    }
+}
+
+async fn send_loop(
+    mut sender: StreamSender<EchoTestPacketHeader>,
+    mut queue_receiver: tmpsc::Receiver<QueuedPacket>,
+) -> StrResult {
+    while let Some((deadline, header, payload)) = queue_receiver.recv().await {
+        time::sleep_until(deadline).await;
+        sender.send(&header, payload).await?;
+    }
+
+    Ok(())
+}
+
+// Runs until the connection is closed
+pub async fn echo_test_loop(socket: &StreamSocket, delay: Duration) -> StrResult {
+    let receiver = socket.subscribe_to_stream(ECHO_TEST_STREAM_ID).await?;
+    let sender = socket.request_stream(ECHO_TEST_STREAM_ID).await?;
+
+    // The delay is the same for every packet, so the queue is ordered by deadline
+    let (queue_sender, queue_receiver) = tmpsc::channel(
+        (delay.as_millis() / FRAME_DURATION.as_millis()) as usize + QUEUE_JITTER_FRAMES_COUNT,
+    );
+
+    tokio::select! {
+        res = receive_loop(receiver, queue_sender, delay) => res,
+        res = send_loop(sender, queue_receiver) => res,
+    }
+}
@@ -1,3 +1,5 @@
-fn main() {
-    println!("Hello, world!");
-}
+mod echo_test;
+mod state;
+
+pub use echo_test::*;
+pub use state::*;
@@ -0,0 +1,428 @@
+// Authoritative state of the server: the connected users, the rooms with their channels and who is
+// in which channel. Every change goes through the `StateManager` and is broadcast as an event while
+// the lock is held, so subscribers see the changes in the same order as the state.
+
+use serde::Serialize;
+use std::{collections::BTreeMap, sync::Arc};
+use tokio::sync::broadcast;
+use vors_share_common::{parking_lot::Mutex, prelude::*};
+
+const STATE_EVENTS_CAPACITY: usize = 256;
+
+pub type UserId = u64;
+pub type RoomId = u64;
+pub type ChannelId = u64;
+
+#[derive(Serialize, Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct UserState {
+    pub muted: bool,
+    pub deafened: bool,
+    // Always false while muted
+    pub speaking: bool,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct User {
+    pub id: UserId,
+    pub name: String,
+    // A user is in at most one channel at a time
+    pub channel_id: Option<ChannelId>,
+    pub state: UserState,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Room {
+    pub id: RoomId,
+    pub name: String,
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+pub struct Channel {
+    pub id: ChannelId,
+    pub room_id: RoomId,
+    pub name: String,
+}
+
+#[derive(Serialize, Clone, Default, PartialEq, Debug)]
+pub struct ServerState {
+    pub users: BTreeMap<UserId, User>,
+    pub rooms: BTreeMap<RoomId, Room>,
+    pub channels: BTreeMap<ChannelId, Channel>,
+}
+
+impl ServerState {
+    pub fn channel_members(&self, channel_id: ChannelId) -> Vec<UserId> {
+        self.users
+            .values()
+            .filter(|user| user.channel_id == Some(channel_id))
+            .map(|user| user.id)
+            .collect()
+    }
+}
+
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "type", content = "data")]
+pub enum StateEvent {
+    UserConnected {
+        user: User,
+    },
+    // Sent after the user has left its channel
+    UserDisconnected {
+        user_id: UserId,
+    },
+    RoomCreated {
+        room: Room,
+    },
+    // Sent after its channels have been removed
+    RoomRemoved {
+        room_id: RoomId,
+    },
+    ChannelCreated {
+        channel: Channel,
+    },
+    // Sent after its members have left it
+    ChannelRemoved {
+        channel_id: ChannelId,
+    },
+    UserJoinedChannel {
+        user_id: UserId,
+        channel_id: ChannelId,
+    },
+    UserLeftChannel {
+        user_id: UserId,
+        channel_id: ChannelId,
+    },
+    UserStateChanged {
+        user_id: UserId,
+        state: UserState,
+    },
+}
+
+struct StateManagerInner {
+    state: ServerState,
+    // Ids are never reused
+    next_id: u64,
+    events_sender: broadcast::Sender<StateEvent>,
+}
+
+impl StateManagerInner {
+    fn next_id(&mut self) -> u64 {
+        self.next_id += 1;
+        self.next_id
+    }
+
+    fn notify(&self, event: StateEvent) {
+        // Fails only when there are no subscribers
+        self.events_sender.send(event).ok();
+    }
+
+    fn user_mut(&mut self, user_id: UserId) -> StrResult<&mut User> {
+        self.state
+            .users
+            .get_mut(&user_id)
+            .ok_or_else(|| format!("Unknown user {user_id}"))
+    }
+
+    fn leave_channel(&mut self, user_id: UserId) -> StrResult {
+        let Some(channel_id) = self.user_mut(user_id)?.channel_id else {
+            return Ok(());
+        };
+
+        // Nobody hears a user outside of a channel
+        self.update_user_state(user_id, |state, _| state.speaking = false)?;
+        self.user_mut(user_id)?.channel_id = None;
+
+        self.notify(StateEvent::UserLeftChannel {
+            user_id,
+            channel_id,
+        });
+
+        Ok(())
+    }
+
+    fn remove_channel(&mut self, channel_id: ChannelId) -> StrResult {
+        if !self.state.channels.contains_key(&channel_id) {
+            return fmt_e!("Unknown channel {channel_id}");
+        }
+
+        for user_id in self.state.channel_members(channel_id) {
+            self.leave_channel(user_id)?;
+        }
+        self.state.channels.remove(&channel_id);
+
+        self.notify(StateEvent::ChannelRemoved { channel_id });
+
+        Ok(())
+    }
+
+    fn update_user_state(
+        &mut self,
+        user_id: UserId,
+        update: impl FnOnce(&mut UserState, bool),
+    ) -> StrResult {
+        let user = self.user_mut(user_id)?;
+        let old_state = user.state;
+        update(&mut user.state, user.channel_id.is_some());
+
+        let state = user.state;
+        if state != old_state {
+            self.notify(StateEvent::UserStateChanged { user_id, state });
+        }
+
+        Ok(())
+    }
+}
+
+// Single source of truth for the other server features. Cheap to clone.
+#[derive(Clone)]
+pub struct StateManager(Arc<Mutex<StateManagerInner>>);
+
+impl StateManager {
+    pub fn new() -> Self {
+        Self(Arc::new(Mutex::new(StateManagerInner {
+            state: ServerState::default(),
+            next_id: 0,
+            events_sender: broadcast::channel(STATE_EVENTS_CAPACITY).0,
+        })))
+    }
+
+    pub fn snapshot(&self) -> ServerState {
+        self.0.lock().state.clone()
+    }
+
+    // The events received are exactly the changes applied after the snapshot. A lagging receiver
+    // should subscribe again.
+    pub fn subscribe(&self) -> (ServerState, broadcast::Receiver<StateEvent>) {
+        let inner = self.0.lock();
+
+        (inner.state.clone(), inner.events_sender.subscribe())
+    }
+
+    pub fn connect_user(&self, name: String) -> UserId {
+        let mut inner = self.0.lock();
+
+        let user = User {
+            id: inner.next_id(),
+            name,
+            channel_id: None,
+            state: UserState::default(),
+        };
+        inner.state.users.insert(user.id, user.clone());
+
+        let user_id = user.id;
+        inner.notify(StateEvent::UserConnected { user });
+
+        user_id
+    }
+
+    pub fn disconnect_user(&self, user_id: UserId) -> StrResult {
+        let mut inner = self.0.lock();
+
+        inner.leave_channel(user_id)?;
+        inner.state.users.remove(&user_id);
+
+        inner.notify(StateEvent::UserDisconnected { user_id });
+
+        Ok(())
+    }
+
+    pub fn create_room(&self, name: String) -> RoomId {
+        let mut inner = self.0.lock();
+
+        let room = Room {
+            id: inner.next_id(),
+            name,
+        };
+        inner.state.rooms.insert(room.id, room.clone());
+
+        let room_id = room.id;
+        inner.notify(StateEvent::RoomCreated { room });
+
+        room_id
+    }
+
+    // The channels of the room are removed too
+    pub fn remove_room(&self, room_id: RoomId) -> StrResult {
+        let mut inner = self.0.lock();
+
+        if !inner.state.rooms.contains_key(&room_id) {
+            return fmt_e!("Unknown room {room_id}");
+        }
+
+        let channel_ids = inner
+            .state
+            .channels
+            .values()
+            .filter(|channel| channel.room_id == room_id)
+            .map(|channel| channel.id)
+            .collect::<Vec<_>>();
+        for channel_id in channel_ids {
+            inner.remove_channel(channel_id)?;
+        }
+        inner.state.rooms.remove(&room_id);
+
+        inner.notify(StateEvent::RoomRemoved { room_id });
+
+        Ok(())
+    }
+
+    pub fn create_channel(&self, room_id: RoomId, name: String) -> StrResult<ChannelId> {
+        let mut inner = self.0.lock();
+
+        if !inner.state.rooms.contains_key(&room_id) {
+            return fmt_e!("Unknown room {room_id}");
+        }
+
+        let channel = Channel {
+            id: inner.next_id(),
+            room_id,
+            name,
+        };
+        inner.state.channels.insert(channel.id, channel.clone());
+
+        let channel_id = channel.id;
+        inner.notify(StateEvent::ChannelCreated { channel });
+
+        Ok(channel_id)
+    }
+
+    // The members are moved out of the channel
+    pub fn remove_channel(&self, channel_id: ChannelId) -> StrResult {
+        self.0.lock().remove_channel(channel_id)
+    }
+
+    // Leaves the current channel first, if any
+    pub fn join_channel(&self, user_id: UserId, channel_id: ChannelId) -> StrResult {
+        let mut inner = self.0.lock();
+
+        if !inner.state.channels.contains_key(&channel_id) {
+            return fmt_e!("Unknown channel {channel_id}");
+        }
+        if inner.user_mut(user_id)?.channel_id == Some(channel_id) {
+            return Ok(());
+        }
+
+        inner.leave_channel(user_id)?;
+        inner.user_mut(user_id)?.channel_id = Some(channel_id);
+
+        inner.notify(StateEvent::UserJoinedChannel {
+            user_id,
+            channel_id,
+        });
+
+        Ok(())
+    }
+
+    pub fn leave_channel(&self, user_id: UserId) -> StrResult {
+        self.0.lock().leave_channel(user_id)
+    }
+
+    // Muting stops the speaking state
+    pub fn set_muted(&self, user_id: UserId, muted: bool) -> StrResult {
+        self.0.lock().update_user_state(user_id, |state, _| {
+            state.muted = muted;
+            state.speaking &= !muted;
+        })
+    }
+
+    pub fn set_deafened(&self, user_id: UserId, deafened: bool) -> StrResult {
+        self.0
+            .lock()
+            .update_user_state(user_id, |state, _| state.deafened = deafened)
+    }
+
+    // Ignored while the user is muted or outside of a channel
+    pub fn set_speaking(&self, user_id: UserId, speaking: bool) -> StrResult {
+        self.0
+            .lock()
+            .update_user_state(user_id, |state, in_channel| {
+                state.speaking = speaking && !state.muted && in_channel;
+            })
+    }
+}
+
+impl Default for StateManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_manager() {
+        let manager = StateManager::new();
+        let room_id = manager.create_room("Room".into());
+        let lobby_id = manager.create_channel(room_id, "Lobby".into()).unwrap();
+        let game_id = manager.create_channel(room_id, "Game".into()).unwrap();
+        let user_id = manager.connect_user("User".into());
+
+        let (snapshot, mut events) = manager.subscribe();
+        assert_eq!(snapshot.users[&user_id].channel_id, None);
+
+        manager.join_channel(user_id, lobby_id).unwrap();
+        manager.set_speaking(user_id, true).unwrap();
+        manager.join_channel(user_id, game_id).unwrap();
+        manager.set_muted(user_id, true).unwrap();
+        // Ignored while muted
+        manager.set_speaking(user_id, true).unwrap();
+        manager.remove_room(room_id).unwrap();
+        assert!(manager.join_channel(user_id, lobby_id).is_err());
+
+        let speaking = |speaking| UserState {
+            muted: false,
+            deafened: false,
+            speaking,
+        };
+        let expected_events = [
+            StateEvent::UserJoinedChannel {
+                user_id,
+                channel_id: lobby_id,
+            },
+            StateEvent::UserStateChanged {
+                user_id,
+                state: speaking(true),
+            },
+            StateEvent::UserStateChanged {
+                user_id,
+                state: speaking(false),
+            },
+            StateEvent::UserLeftChannel {
+                user_id,
+                channel_id: lobby_id,
+            },
+            StateEvent::UserJoinedChannel {
+                user_id,
+                channel_id: game_id,
+            },
+            StateEvent::UserStateChanged {
+                user_id,
+                state: UserState {
+                    muted: true,
+                    ..speaking(false)
+                },
+            },
+            StateEvent::ChannelRemoved {
+                channel_id: lobby_id,
+            },
+            StateEvent::UserLeftChannel {
+                user_id,
+                channel_id: game_id,
+            },
+            StateEvent::ChannelRemoved {
+                channel_id: game_id,
+            },
+            StateEvent::RoomRemoved { room_id },
+        ];
+        for expected_event in expected_events {
+            assert_eq!(events.try_recv().unwrap(), expected_event);
+        }
+        assert!(events.try_recv().is_err());
+
+        let state = manager.snapshot();
+        assert!(state.rooms.is_empty() && state.channels.is_empty());
+        assert_eq!(state.users[&user_id].channel_id, None);
+    }
+}
@@ -1,3 +1,50 @@
+use serde::{Deserialize, Serialize};
+
+// Octave bands of the comfort noise spectrum, centered from 125 Hz to 8 kHz
+pub const COMFORT_NOISE_BANDS_COUNT: usize = 7;
+
+// Description of the background noise of a speaker, sent periodically instead of the audio frames
+// while the voice activity detection marks silence (discontinuous transmission). The listeners
+// play matching comfort noise.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct ComfortNoiseDescriptor {
+    // Power of the noise, in dBFS
+    pub level_db: f32,
+    // Power of each band relative to level_db, in dB
+    pub spectrum_db: [f32; COMFORT_NOISE_BANDS_COUNT],
+    // The comfort noise stops if no other descriptor is received within this duration
+    pub duration_ms: u16,
+}
+
+// Header of the packets of audio streams. The payload contains interleaved i16 samples.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct AudioPacketHeader {
+    // False if the voice activity detection marked this packet as silence
+    pub voice_activity: bool,
+    // Set during discontinuous transmission, the payload is then empty
+    pub comfort_noise: Option<ComfortNoiseDescriptor>,
+}
+
+// Stream of the audio played by a participant's computer, shared separately from the voice
+pub const SYSTEM_AUDIO_STREAM_ID: u16 = 8;
+
+// Header of the packets of the system audio stream. The payload is a stereo 48 kHz Opus packet.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct SystemAudioPacketHeader;
+
+// Stream of the echo test. The server sends the packets back on the same stream after a delay.
+pub const ECHO_TEST_STREAM_ID: u16 = 9;
+
+// Header of the packets of the echo test, returned unchanged by the server. The payload contains
+// interleaved i16 samples.
+#[derive(Serialize, Deserialize, Clone, Copy, Default, Debug)]
+pub struct EchoTestPacketHeader {
+    // Used to count the lost packets
+    pub sequence: u32,
+    // Client clock, when the last sample of the frame was captured
+    pub capture_timestamp_us: u64,
+}
+
 pub fn add(left: usize, right: usize) -> usize {
     left + right
 }
@@ -445,8 +445,16 @@ pub enum LinuxAudioBackend {
 pub enum CustomAudioDeviceConfig {
     #[schema(strings(display_name = "By name (substring)"))]
     NameSubstring(String),
-    #[schema(strings(display_name = "By index"))]
+    #[schema(strings(
+        display_name = "By index",
+        help = "The index changes when other devices are plugged or unplugged"
+    ))]
     Index(usize),
+    #[schema(strings(
+        display_name = "By ID",
+        help = "Stable device ID, as listed by the audio devices API"
+    ))]
+    Id(String),
 }
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
@@ -494,6 +502,330 @@ pub struct MicrophoneConfig {
     pub buffering: AudioBufferingConfig,
 }
 
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct VoiceActivityDetectionConfig {
+    #[schema(strings(
+        help = "Higher values detect quieter speech, but let through more background noise"
+    ))]
+    #[schema(gui(slider(min = 0.0, max = 1.0, step = 0.01)))]
+    pub sensitivity: f32,
+
+    #[schema(strings(help = "How long the voice is still considered active after it stopped"))]
+    #[schema(gui(slider(min = 0, max = 1000, step = 10)), suffix = "ms")]
+    pub hangover_ms: u64,
+
+    #[schema(strings(
+        help = "Do not send silent frames (discontinuous transmission). If disabled, silent frames are sent but marked as silent"
+    ))]
+    pub suppress_silent_frames: bool,
+
+    #[schema(strings(
+        help = "While silent frames are suppressed, periodically send a description of the background noise, so that the listeners hear comfort noise instead of silence"
+    ))]
+    pub comfort_noise: Switch<ComfortNoiseConfig>,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct ComfortNoiseConfig {
+    #[schema(strings(help = "How often the description of the background noise is updated"))]
+    #[schema(gui(slider(min = 50, max = 1000, step = 10)), suffix = "ms")]
+    pub descriptor_interval_ms: u64,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+#[schema(gui = "button_group")]
+pub enum NoiseSuppressionStrength {
+    Low,
+    Medium,
+    High,
+    VeryHigh,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct NoiseSuppressionConfig {
+    #[schema(strings(
+        help = "Higher strength removes more noise, but can make the voice sound muffled"
+    ))]
+    pub strength: NoiseSuppressionStrength,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct EchoCancellationConfig {
+    #[schema(strings(
+        help = "Maximum delay between the playback and the echo captured by the microphone"
+    ))]
+    #[schema(gui(slider(min = 50, max = 1000, step = 10)), suffix = "ms")]
+    pub max_delay_ms: u64,
+
+    #[schema(strings(
+        help = "Length of the modeled echo. Increase this in rooms with a lot of reverberation"
+    ))]
+    #[schema(gui(slider(min = 10, max = 300, step = 10)), suffix = "ms")]
+    pub tail_length_ms: u64,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct NoiseGateConfig {
+    #[schema(strings(help = "The gate opens when the microphone level rises above this level"))]
+    #[schema(gui(slider(min = -80.0, max = 0.0, step = 1.0)), suffix = "dBFS")]
+    pub open_threshold_db: f32,
+
+    #[schema(strings(
+        help = "The gate closes when the microphone level falls below this level. It should be lower than the open threshold"
+    ))]
+    #[schema(gui(slider(min = -80.0, max = 0.0, step = 1.0)), suffix = "dBFS")]
+    pub close_threshold_db: f32,
+
+    #[schema(strings(help = "How long the gate stays open after the level fell below the close threshold"))]
+    #[schema(gui(slider(min = 0, max = 1000, step = 10)), suffix = "ms")]
+    pub hold_ms: u64,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct SoundboardClipConfig {
+    #[schema(strings(help = "WAV or Ogg Opus file"))]
+    pub path: String,
+
+    #[schema(gui(slider(min = 0.0, max = 2.0, step = 0.05)))]
+    pub volume: f32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct SoundboardConfig {
+    #[schema(strings(help = "Clips are triggered by name"))]
+    pub clips: Vec<(String, SoundboardClipConfig)>,
+
+    #[schema(strings(
+        help = "When a clip is triggered while this many clips are playing, the oldest one is stopped"
+    ))]
+    #[schema(gui(slider(min = 1, max = 16)))]
+    pub max_concurrent_clips: usize,
+
+    #[schema(strings(
+        help = "Play the clips only while the microphone transmits. If disabled, a clip is sent even when the microphone is closed, without the microphone audio"
+    ))]
+    pub only_while_transmitting: bool,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct AutomaticGainControlConfig {
+    #[schema(strings(help = "Loudness the voice is brought to"))]
+    #[schema(gui(slider(min = -40.0, max = -3.0, step = 1.0)), suffix = "dBFS")]
+    pub target_level_db: f32,
+
+    #[schema(strings(help = "Maximum amplification applied to quiet microphones"))]
+    #[schema(gui(slider(min = 0.0, max = 40.0, step = 1.0)), suffix = "dB")]
+    pub max_gain_db: f32,
+
+    #[schema(strings(help = "How fast the gain is reduced when the voice gets louder"))]
+    #[schema(gui(slider(min = 1, max = 500)), suffix = "ms")]
+    pub attack_ms: u64,
+
+    #[schema(strings(help = "How fast the gain is increased when the voice gets quieter"))]
+    #[schema(gui(slider(min = 10, max = 5000, logarithmic)), suffix = "ms")]
+    pub release_ms: u64,
+
+    #[schema(strings(
+        help = "Attenuate the microphone when it only picks up background noise, and stop the gain from adapting to it"
+    ))]
+    pub noise_gate: Switch<NoiseGateConfig>,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+#[schema(gui = "button_group")]
+pub enum DistanceRolloffModel {
+    Linear,
+    Inverse,
+    Exponential,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct SpatialAudioConfig {
+    #[schema(strings(
+        help = "Linear: silent at the maximum distance. Inverse: natural sounding. Exponential: faster decay"
+    ))]
+    pub rolloff_model: DistanceRolloffModel,
+
+    #[schema(strings(help = "Distance under which the voice is not attenuated"))]
+    #[schema(gui(slider(min = 0.1, max = 10.0, step = 0.1)), suffix = "m")]
+    pub reference_distance_m: f32,
+
+    #[schema(strings(help = "Distance over which the voice is not attenuated any further"))]
+    #[schema(gui(slider(min = 1.0, max = 200.0, step = 1.0)), suffix = "m")]
+    pub max_distance_m: f32,
+
+    #[schema(gui(slider(min = 0.0, max = 5.0, step = 0.1)))]
+    pub rolloff_factor: f32,
+
+    #[schema(strings(
+        help = "Render the time and level differences between the ears with a spherical head model. Best with headphones"
+    ))]
+    pub head_model: bool,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct DuckingConfig {
+    #[schema(strings(help = "Attenuation applied while someone talks"))]
+    #[schema(gui(slider(min = 0.0, max = 40.0, step = 1.0)), suffix = "dB")]
+    pub depth_db: f32,
+
+    #[schema(strings(help = "Time to reach the full attenuation once someone starts talking"))]
+    #[schema(gui(slider(min = 1, max = 500)), suffix = "ms")]
+    pub attack_ms: u64,
+
+    #[schema(strings(help = "How long the attenuation is kept after the voice stopped"))]
+    #[schema(gui(slider(min = 0, max = 2000, step = 10)), suffix = "ms")]
+    pub hold_ms: u64,
+
+    #[schema(strings(help = "Time to go back to the full volume after the hold"))]
+    #[schema(gui(slider(min = 10, max = 5000, logarithmic)), suffix = "ms")]
+    pub release_ms: u64,
+
+    #[schema(strings(
+        help = "Also duck while the local microphone transmits speech. Requires the voice activity detection"
+    ))]
+    pub local_microphone: bool,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, PartialEq, Debug)]
+pub enum VoiceEffectPreset {
+    PitchShift {
+        #[schema(gui(slider(min = -12.0, max = 12.0, step = 0.5)), suffix = " semitones")]
+        semitones: f32,
+        #[schema(strings(
+            help = "Keep the timbre of the voice. Without it, the voice sounds like a cartoon character"
+        ))]
+        preserve_formants: bool,
+    },
+    Robot {
+        #[schema(gui(slider(min = 10.0, max = 300.0, step = 1.0)), suffix = "Hz")]
+        modulation_hz: f32,
+    },
+    Reverb {
+        #[schema(gui(slider(min = 0.0, max = 1.0, step = 0.01)))]
+        room_size: f32,
+        #[schema(gui(slider(min = 0.0, max = 1.0, step = 0.01)))]
+        damping: f32,
+        #[schema(gui(slider(min = 0.0, max = 1.0, step = 0.01)))]
+        wet: f32,
+    },
+    Echo {
+        #[schema(gui(slider(min = 50, max = 1000, step = 10)), suffix = "ms")]
+        delay_ms: u64,
+        #[schema(gui(slider(min = 0.0, max = 0.9, step = 0.01)))]
+        feedback: f32,
+        #[schema(gui(slider(min = 0.0, max = 1.0, step = 0.01)))]
+        wet: f32,
+    },
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+pub enum TransmitMode {
+    Continuous,
+    VoiceActivation,
+    PushToTalk,
+    PushToMute,
+    Toggle,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct TransmitConfig {
+    #[schema(strings(help = r#"Continuous: The microphone is always open.
+Voice activation: The microphone is open while the voice activity detection detects speech.
+Push to talk: The microphone is open while the trigger is held.
+Push to mute: Like voice activation, but the microphone is closed while the trigger is held.
+Toggle: Each trigger press opens or closes the microphone."#))]
+    #[schema(flag = "real-time")]
+    pub mode: TransmitMode,
+
+    #[schema(strings(
+        help = "Keep transmitting for this long after the push to talk trigger is released or toggled off"
+    ))]
+    #[schema(gui(slider(min = 0, max = 1000, step = 10)), suffix = "ms")]
+    pub release_tail_ms: u64,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
+pub enum SidetoneSource {
+    ProcessedMicrophone,
+    OutgoingStream,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct SidetoneConfig {
+    #[schema(strings(
+        help = "Processed microphone: lowest latency. Outgoing stream: exactly what the others hear, after the transmit gating and the encoding"
+    ))]
+    pub source: SidetoneSource,
+
+    #[schema(flag = "real-time")]
+    #[schema(gui(slider(min = -40.0, max = 0.0, step = 1.0)), suffix = "dB")]
+    pub level_db: f32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct UdpAudioTapConfig {
+    pub host: String,
+
+    #[schema(strings(help = "The audio of the whole channel is sent to this port"))]
+    pub mix_port: u16,
+
+    #[schema(strings(
+        help = "The voice of each participant is sent to this port plus the participant ID"
+    ))]
+    pub speaker_base_port: u16,
+}
+
+// Every track is published as interleaved 16 bit little endian PCM, at the playback sample rate and
+// channels count. Tracks are also available as WebSockets on the web server, at
+// /api/audio-tap/mix and /api/audio-tap/speaker/<id>.
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct AudioTapConfig {
+    #[schema(strings(
+        help = "Create a named pipe per track in this directory: mix.pcm and speaker_<id>.pcm. Not supported on Windows"
+    ))]
+    pub fifo_directory: Switch<String>,
+
+    #[schema(strings(help = "Send each track as raw PCM over UDP"))]
+    pub udp: Switch<UdpAudioTapConfig>,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct OggOpusRecordingConfig {
+    #[schema(gui(slider(min = 16, max = 256, step = 8)), suffix = "kbps")]
+    pub bitrate_kbps: u32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct SystemAudioShareConfig {
+    #[schema(strings(
+        help = "Device whose audio is shared. On Windows select an output device. On Linux select the monitor of the output, by default the first monitor found"
+    ))]
+    pub device: Option<CustomAudioDeviceConfig>,
+
+    #[schema(strings(help = "Encoded as stereo Opus, tuned for music"))]
+    #[schema(gui(slider(min = 32, max = 320, step = 8)), suffix = "kbps")]
+    pub bitrate_kbps: u32,
+}
+
+#[derive(SettingsSchema, Serialize, Deserialize, Clone)]
+pub struct AudioRecordingConfig {
+    #[schema(strings(help = "Each recording is saved in a new subdirectory"))]
+    pub directory: String,
+
+    #[schema(strings(display_name = "WAV"))]
+    pub wav: bool,
+
+    #[schema(strings(display_name = "Ogg Opus"))]
+    pub ogg_opus: Switch<OggOpusRecordingConfig>,
+
+    #[schema(strings(
+        help = "A participant is marked as speaking in the timeline while their level is above this threshold"
+    ))]
+    #[schema(gui(slider(min = -80.0, max = 0.0, step = 1.0)), suffix = "dB")]
+    pub speech_threshold_db: f32,
+}
+
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub struct AudioConfig {
     #[schema(strings(help = "ALSA is recommended for most PulseAudio or PipeWire-based setups"))]
@@ -502,6 +834,62 @@ pub struct AudioConfig {
     pub game_audio: Switch<GameAudioConfig>,
 
     pub microphone: Switch<MicrophoneConfig>,
+
+    pub echo_cancellation: Switch<EchoCancellationConfig>,
+
+    pub noise_suppression: Switch<NoiseSuppressionConfig>,
+
+    pub voice_activity_detection: Switch<VoiceActivityDetectionConfig>,
+
+    #[schema(strings(help = "Real-time effects applied to the outgoing voice"))]
+    #[schema(flag = "real-time")]
+    pub voice_effects: Switch<VoiceEffectPreset>,
+
+    #[schema(strings(
+        help = "Short clips mixed into the outgoing voice, triggered from the dashboard, a hotkey or OSC"
+    ))]
+    pub soundboard: Switch<SoundboardConfig>,
+
+    pub automatic_gain_control: Switch<AutomaticGainControlConfig>,
+
+    #[schema(strings(help = "Bring all the other participants to the same loudness"))]
+    pub speaker_normalization: Switch<AutomaticGainControlConfig>,
+
+    #[schema(strings(
+        help = "Position the voice of the other participants according to their pose in the virtual space"
+    ))]
+    pub spatial_audio: Switch<SpatialAudioConfig>,
+
+    #[schema(strings(
+        help = "Lower the game audio and the shared system audio of the other participants while someone talks"
+    ))]
+    pub ducking: Switch<DuckingConfig>,
+
+    pub transmit: TransmitConfig,
+
+    #[schema(strings(
+        help = "Play your own voice in your headset, so that you do not end up shouting with closed headphones"
+    ))]
+    pub sidetone: Switch<SidetoneConfig>,
+
+    #[schema(strings(
+        help = "Delay before the server sends back the voice during an echo test. The latency is measured without it"
+    ))]
+    #[schema(gui(slider(min = 0, max = 5000, step = 100)), suffix = "ms")]
+    pub echo_test_delay_ms: u64,
+
+    #[schema(strings(help = "Publish the voice of each participant to external applications"))]
+    pub output_tap: AudioTapConfig,
+
+    #[schema(strings(
+        help = "Share the audio played by this computer (music, a video, a game) as a stream separate from the microphone. Each receiver controls its volume independently from the voice"
+    ))]
+    pub system_audio_share: Switch<SystemAudioShareConfig>,
+
+    #[schema(strings(
+        help = "Record the mixed audio and the voice of each participant. Recordings are started and stopped from the dashboard"
+    ))]
+    pub recording: AudioRecordingConfig,
 }
 #[derive(SettingsSchema, Serialize, Deserialize, Clone, Copy)]
 pub enum PositionRecenteringMode {
@@ -646,8 +1034,6 @@ No action: All driver registration actions should be performed manually, VORS in
 
 #[derive(SettingsSchema, Serialize, Deserialize, Clone)]
 pub struct CaptureConfig {
-    pub save_video_stream: bool,
-
     #[schema(flag = "steamvr-restart")]
     pub capture_frame_dir: String,
 }
@@ -686,6 +1072,7 @@ pub fn session_settings_default() -> SettingsDefault {
     let default_custom_audio_device = CustomAudioDeviceConfigDefault {
         NameSubstring: "".into(),
         Index: 0,
+        Id: "".into(),
         variant: CustomAudioDeviceConfigDefaultVariant::NameSubstring,
     };
     let socket_buffer = SocketBufferSizeDefault {
@@ -866,7 +1253,7 @@ pub fn session_settings_default() -> SettingsDefault {
                     devices: MicrophoneDevicesConfigDefault {
                         Custom: MicrophoneDevicesConfigCustomDefault {
                             source: default_custom_audio_device.clone(),
-                            sink: default_custom_audio_device,
+                            sink: default_custom_audio_device.clone(),
                         },
                         variant: MicrophoneDevicesConfigDefaultVariant::Automatic,
                     },
@@ -876,6 +1263,178 @@ pub fn session_settings_default() -> SettingsDefault {
                     },
                 },
             },
+            echo_cancellation: SwitchDefault {
+                enabled: true,
+                content: EchoCancellationConfigDefault {
+                    max_delay_ms: 500,
+                    tail_length_ms: 80,
+                },
+            },
+            noise_suppression: SwitchDefault {
+                enabled: true,
+                content: NoiseSuppressionConfigDefault {
+                    strength: NoiseSuppressionStrengthDefault {
+                        variant: NoiseSuppressionStrengthDefaultVariant::Medium,
+                    },
+                },
+            },
+            voice_activity_detection: SwitchDefault {
+                enabled: true,
+                content: VoiceActivityDetectionConfigDefault {
+                    sensitivity: 0.5,
+                    hangover_ms: 300,
+                    suppress_silent_frames: true,
+                    comfort_noise: SwitchDefault {
+                        enabled: true,
+                        content: ComfortNoiseConfigDefault {
+                            descriptor_interval_ms: 200,
+                        },
+                    },
+                },
+            },
+            voice_effects: SwitchDefault {
+                enabled: false,
+                content: VoiceEffectPresetDefault {
+                    variant: VoiceEffectPresetDefaultVariant::PitchShift,
+                    PitchShift: VoiceEffectPresetPitchShiftDefault {
+                        semitones: 4.,
+                        preserve_formants: true,
+                    },
+                    Robot: VoiceEffectPresetRobotDefault {
+                        modulation_hz: 60.,
+                    },
+                    Reverb: VoiceEffectPresetReverbDefault {
+                        room_size: 0.7,
+                        damping: 0.5,
+                        wet: 0.3,
+                    },
+                    Echo: VoiceEffectPresetEchoDefault {
+                        delay_ms: 300,
+                        feedback: 0.4,
+                        wet: 0.4,
+                    },
+                },
+            },
+            soundboard: SwitchDefault {
+                enabled: false,
+                content: SoundboardConfigDefault {
+                    clips: DictionaryDefault {
+                        key: "".into(),
+                        value: SoundboardClipConfigDefault {
+                            path: "".into(),
+                            volume: 1.,
+                        },
+                        content: vec![],
+                    },
+                    max_concurrent_clips: 4,
+                    only_while_transmitting: true,
+                },
+            },
+            automatic_gain_control: SwitchDefault {
+                enabled: true,
+                content: AutomaticGainControlConfigDefault {
+                    target_level_db: -18.,
+                    max_gain_db: 24.,
+                    attack_ms: 20,
+                    release_ms: 1000,
+                    noise_gate: SwitchDefault {
+                        enabled: true,
+                        content: NoiseGateConfigDefault {
+                            open_threshold_db: -50.,
+                            close_threshold_db: -58.,
+                            hold_ms: 200,
+                        },
+                    },
+                },
+            },
+            speaker_normalization: SwitchDefault {
+                enabled: false,
+                content: AutomaticGainControlConfigDefault {
+                    target_level_db: -20.,
+                    max_gain_db: 12.,
+                    attack_ms: 50,
+                    release_ms: 2000,
+                    noise_gate: SwitchDefault {
+                        enabled: true,
+                        content: NoiseGateConfigDefault {
+                            open_threshold_db: -50.,
+                            close_threshold_db: -58.,
+                            hold_ms: 200,
+                        },
+                    },
+                },
+            },
+            spatial_audio: SwitchDefault {
+                enabled: false,
+                content: SpatialAudioConfigDefault {
+                    rolloff_model: DistanceRolloffModelDefault {
+                        variant: DistanceRolloffModelDefaultVariant::Inverse,
+                    },
+                    reference_distance_m: 1.,
+                    max_distance_m: 50.,
+                    rolloff_factor: 1.,
+                    head_model: true,
+                },
+            },
+            ducking: SwitchDefault {
+                enabled: true,
+                content: DuckingConfigDefault {
+                    depth_db: 12.,
+                    attack_ms: 50,
+                    hold_ms: 300,
+                    release_ms: 800,
+                    local_microphone: false,
+                },
+            },
+            transmit: TransmitConfigDefault {
+                mode: TransmitModeDefault {
+                    variant: TransmitModeDefaultVariant::VoiceActivation,
+                },
+                release_tail_ms: 200,
+            },
+            sidetone: SwitchDefault {
+                enabled: false,
+                content: SidetoneConfigDefault {
+                    source: SidetoneSourceDefault {
+                        variant: SidetoneSourceDefaultVariant::ProcessedMicrophone,
+                    },
+                    level_db: -12.,
+                },
+            },
+            echo_test_delay_ms: 2000,
+            output_tap: AudioTapConfigDefault {
+                fifo_directory: SwitchDefault {
+                    enabled: false,
+                    content: "".into(),
+                },
+                udp: SwitchDefault {
+                    enabled: false,
+                    content: UdpAudioTapConfigDefault {
+                        host: "127.0.0.1".into(),
+                        mix_port: 9950,
+                        speaker_base_port: 9960,
+                    },
+                },
+            },
+            recording: AudioRecordingConfigDefault {
+                directory: "recordings".into(),
+                wav: true,
+                ogg_opus: SwitchDefault {
+                    enabled: true,
+                    content: OggOpusRecordingConfigDefault { bitrate_kbps: 64 },
+                },
+                speech_threshold_db: -45.,
+            },
+            system_audio_share: SwitchDefault {
+                enabled: false,
+                content: SystemAudioShareConfigDefault {
+                    device: OptionalDefault {
+                        set: false,
+                        content: default_custom_audio_device,
+                    },
+                    bitrate_kbps: 128,
+                },
+            },
         },
         connection: ConnectionDescDefault {
             stream_protocol: SocketProtocolDefault {
@@ -923,7 +1482,6 @@ pub fn session_settings_default() -> SettingsDefault {
             open_close_steamvr_with_dashboard: false,
         },
         capture: CaptureConfigDefault {
-            save_video_stream: false,
             capture_frame_dir: if !cfg!(target_os = "linux") {
                 "/tmp".into()
             } else {